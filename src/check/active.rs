@@ -10,96 +10,326 @@
 // -----
 // Created Date: 2023/10/23 09:44:07
 
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
 use std::{
+    fmt::Display,
     net::SocketAddr,
-    time::{Duration, Instant}, io,
+    str::FromStr,
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc::{Receiver, error::TryRecvError};
-use webparse::{Request, Response};
-use wenmeng::{Client, Body};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::mpsc::{error::TryRecvError, Receiver},
+};
+use webparse::{BinaryMut, Buf, HeaderName, Request, Response, Url};
+use wenmeng::Body;
+
+use crate::{
+    reverse::{ProxyTlsConfig, UpstreamPool, UpstreamProtocol}, ConfigDuration, DisplayFromStrOrNumber, HealthCheck, Helper,
+    ProxyError, ProxyResult,
+};
+
+fn default_check_type() -> String {
+    "http".to_string()
+}
+
+fn default_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(5))
+}
+
+fn default_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(3))
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+/// 认为健康的状态码范围, 格式如 "200-399 404 5xx"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusRanges(pub Vec<(u16, u16)>);
+
+impl Default for StatusRanges {
+    fn default() -> Self {
+        Self(vec![(200, 399)])
+    }
+}
+
+impl StatusRanges {
+    pub fn contains(&self, status: u16) -> bool {
+        self.0.iter().any(|(min, max)| *min <= status && status <= *max)
+    }
+}
+
+impl FromStr for StatusRanges {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = vec![];
+        for val in Helper::split_by_whitespace(s) {
+            let range = if let Some(prefix) = val.strip_suffix("xx") {
+                prefix.parse::<u16>().ok().map(|v| (v * 100, v * 100 + 99))
+            } else if let Some((min, max)) = val.split_once('-') {
+                min.parse::<u16>().ok().zip(max.parse::<u16>().ok())
+            } else {
+                val.parse::<u16>().ok().map(|v| (v, v))
+            };
+            ranges.push(range.ok_or(ProxyError::Extension("unknow status range"))?);
+        }
+        if ranges.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl Display for StatusRanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let vals: Vec<String> = self
+            .0
+            .iter()
+            .map(|(min, max)| {
+                if min == max {
+                    format!("{}", min)
+                } else {
+                    format!("{}-{}", min, max)
+                }
+            })
+            .collect();
+        f.write_str(&vals.join(" "))
+    }
+}
+
+/// 上游的主动健康检查配置
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveCheckConfig {
+    /// 检查方式, 有http/https/tcp/udp
+    #[serde(rename = "type", default = "default_check_type")]
+    pub check_type: String,
+    /// 每次检查的间隔
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_interval")]
+    pub interval: ConfigDuration,
+    /// 单次检查的超时时间
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_timeout")]
+    pub timeout: ConfigDuration,
+    /// 间隔上随机增加的时长, 避免同时发起检查
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub jitter: Option<ConfigDuration>,
+    /// HTTP检查的请求方法
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// HTTP检查的请求路径
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// HTTP检查的Host头, HTTPS时同时作为SNI
+    pub host: Option<String>,
+    /// HTTP检查认为健康的状态码
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub status: StatusRanges,
+    /// HTTP检查返回内容需匹配的正则
+    pub body: Option<String>,
+    /// UDP检查发送的内容
+    pub send: Option<String>,
+    /// UDP检查返回内容需匹配的正则, 为空则有返回即可
+    pub expect: Option<String>,
+}
 
-use crate::{ProxyResult, HealthCheck};
+impl Default for ActiveCheckConfig {
+    fn default() -> Self {
+        Self {
+            check_type: default_check_type(),
+            interval: default_interval(),
+            timeout: default_timeout(),
+            jitter: None,
+            method: default_method(),
+            path: default_path(),
+            host: None,
+            status: StatusRanges::default(),
+            body: None,
+            send: None,
+            expect: None,
+        }
+    }
+}
+
+impl ActiveCheckConfig {
+    /// 下一次检查的间隔, 加上随机的抖动时长
+    pub fn next_interval(&self) -> Duration {
+        match &self.jitter {
+            Some(jitter) if !jitter.0.is_zero() => {
+                let millis = rand::thread_rng().gen_range(0..=jitter.0.as_millis() as u64);
+                self.interval.0 + Duration::from_millis(millis)
+            }
+            _ => self.interval.0,
+        }
+    }
+
+    fn is_match(re: &Option<String>, data: &[u8]) -> bool {
+        match re {
+            Some(re) if !re.is_empty() => match Helper::try_cache_regex(re) {
+                Some(re) => re.is_match(&String::from_utf8_lossy(data)),
+                None => false,
+            },
+            _ => true,
+        }
+    }
+}
 
 /// 单项健康检查
-/// TODO HTTP检查应该可以配置请求方法及返回编码是否正确来判定是否为健康
 #[derive(Debug, Clone)]
 pub struct OneHealth {
     /// 主动检查地址
     pub addr: SocketAddr,
-    /// 主动检查方法, 有http/https/tcp等
-    pub method: String,
-    /// 每次检查间隔
+    /// 检查的配置
+    pub check: ActiveCheckConfig,
+    /// HTTPS检查时的TLS配置
+    pub tls: Option<ProxyTlsConfig>,
+    /// 本次检查间隔
     pub interval: Duration,
     /// 最后一次记录时间
     pub last_record: Instant,
 }
 
 impl OneHealth {
-
-    pub fn new(addr: SocketAddr, method: String, interval: Duration) -> Self {
-        OneHealth { addr, method, interval, last_record: Instant::now() - interval }
+    pub fn new(addr: SocketAddr, check: ActiveCheckConfig, tls: Option<ProxyTlsConfig>) -> Self {
+        let interval = check.next_interval();
+        OneHealth {
+            addr,
+            check,
+            tls,
+            interval,
+            last_record: Instant::now() - interval,
+        }
     }
-    
+
     pub async fn connect_http(&self) -> ProxyResult<Response<Body>> {
-        let url = format!("{}://{}/", self.method, self.addr);
-        let req = Request::builder().method("GET").url(url.clone()).body("").unwrap();
-
-        let client = Client::builder().url(url)?
-            .connect().await?;
-    
-        let (mut recv, _sender) = client.send2(req.into_type()).await?;
-        match recv.recv().await {
-            Some(res) => {
-                return Ok(res?);
+        let is_https = self.check.check_type.eq_ignore_ascii_case("https");
+        let scheme = if is_https { "https" } else { "http" };
+        let url = Url::parse(format!("{}://{}{}", scheme, self.addr, self.check.path).into_bytes())?;
+        let mut builder = Request::builder()
+            .method(&*self.check.method)
+            .url(url.clone());
+        if let Some(host) = &self.check.host {
+            builder = builder.header(HeaderName::HOST, host.clone());
+        }
+        let mut req = builder.body(Body::empty())?;
+        // 主动检查不受被动检查的下线状态影响, 并读完数据使连接能被复用
+        if is_https {
+            let domain = self
+                .check
+                .host
+                .as_ref()
+                .map(|h| h.split(':').next().unwrap_or(h).to_string());
+            let client = UpstreamPool::connect(
+                &url,
+                domain.as_deref(),
+                None,
+                false,
+                self.tls.as_ref(),
+                UpstreamProtocol::Http1,
+            )
+            .await?;
+            let (mut receiver, _sender) = client.send2(req).await?;
+            match receiver.recv().await {
+                Some(res) => {
+                    let mut res = res?;
+                    res.body_mut().wait_all().await;
+                    Ok(res)
+                }
+                None => Err(ProxyError::Extension("already close by other")),
+            }
+        } else {
+            let mut res = UpstreamPool::send(
+                &url,
+                &mut req,
+                None,
+                false,
+                self.tls.as_ref(),
+                UpstreamProtocol::Http1,
+            )
+            .await?;
+            res.body_mut().wait_all().await;
+            Ok(res)
+        }
+    }
+
+    async fn check_http(&self) -> ProxyResult<()> {
+        let mut res = self.connect_http().await?;
+        if !self.check.status.contains(res.status().as_u16()) {
+            return Err(ProxyError::Extension("unexpected status"));
+        }
+        if self.check.body.is_some() {
+            let mut buffer = BinaryMut::new();
+            res.body_mut().read_all(&mut buffer).await;
+            if !ActiveCheckConfig::is_match(&self.check.body, buffer.chunk()) {
+                return Err(ProxyError::Extension("body not match"));
             }
-            _ => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, "").into());
+        }
+        Ok(())
+    }
+
+    async fn check_udp(&self) -> ProxyResult<()> {
+        let bind = if self.addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(self.addr).await?;
+        let send = self.check.send.clone().unwrap_or_default();
+        socket.send(send.as_bytes()).await?;
+        let mut buf = vec![0u8; 4096];
+        let size = socket.recv(&mut buf).await?;
+        if !ActiveCheckConfig::is_match(&self.check.expect, &buf[..size]) {
+            return Err(ProxyError::Extension("udp response not match"));
+        }
+        Ok(())
+    }
+
+    async fn check_one(&self) -> ProxyResult<()> {
+        match &*self.check.check_type.to_lowercase() {
+            "tcp" => {
+                TcpStream::connect(self.addr).await?;
+                Ok(())
             }
+            "udp" => self.check_udp().await,
+            _ => self.check_http().await,
         }
     }
+
     pub async fn do_check(&self) -> ProxyResult<()> {
-        // 防止短时间内健康检查的连接过多, 做一定的超时处理, 或者等上一条消息处理完毕
-        if !HealthCheck::check_can_request(&self.addr, self.interval) {
-            return Ok(())
-        }
-        if self.method.eq_ignore_ascii_case("http") {
-            match tokio::time::timeout(self.interval + Duration::from_secs(3), self.connect_http()).await {
-                Ok(r) => match r {
-                    Ok(r) => {
-                        if r.status().is_server_error() {
-                            log::trace!("主动健康检查:HTTP:{}, 返回失败:{}", self.addr, r.status());
-                            HealthCheck::add_fall_down(self.addr);
-                        } else {
-                            HealthCheck::add_rise_up(self.addr);
-                        }
-                    }
-                    Err(e) => {
-                        log::trace!("主动健康检查:HTTP:{}, 发生错误:{:?}", self.addr, e);
-                        HealthCheck::add_fall_down(self.addr);
-                    }
-                },
-                Err(e) => {
-                    log::trace!("主动健康检查:HTTP:{}, 发生超时:{:?}", self.addr, e);
-                    HealthCheck::add_fall_down(self.addr);
-                },
+        // 防止短时间内健康检查的连接过多, 上一次检查未超时前不重复发起
+        if !HealthCheck::check_can_request(&self.addr, self.check.timeout.0) {
+            return Ok(());
+        }
+        match tokio::time::timeout(self.check.timeout.0, self.check_one()).await {
+            Ok(Ok(())) => {
+                HealthCheck::add_rise_up(self.addr);
             }
-        } else {
-            match tokio::time::timeout(Duration::from_secs(3), self.connect_http()).await {
-                Ok(r) => {
-                    match r {
-                        Ok(_) => {
-                            HealthCheck::add_rise_up(self.addr);
-                        }
-                        Err(e) => {
-                            log::trace!("主动健康检查:TCP:{}, 发生错误:{:?}", self.addr, e);
-                            HealthCheck::add_fall_down(self.addr);
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::trace!("主动健康检查:TCP:{}, 发生超时:{:?}", self.addr, e);
-                    HealthCheck::add_fall_down(self.addr);
-                }
+            Ok(Err(e)) => {
+                log::trace!(
+                    "主动健康检查:{}:{}, 发生错误:{:?}",
+                    self.check.check_type,
+                    self.addr,
+                    e
+                );
+                HealthCheck::add_fall_down(self.addr);
+            }
+            Err(e) => {
+                log::trace!(
+                    "主动健康检查:{}:{}, 发生超时:{:?}",
+                    self.check.check_type,
+                    self.addr,
+                    e
+                );
+                HealthCheck::add_fall_down(self.addr);
             }
         }
         Ok(())
@@ -119,12 +349,23 @@ impl ActiveHealth {
         Self { healths, receiver }
     }
 
+    /// 更新检查列表, 已存在的地址保留原有的检查时间, 防止列表刷新时集中检查
+    fn update_healths(&mut self, mut healths: Vec<OneHealth>) {
+        for health in &mut healths {
+            if let Some(old) = self.healths.iter().find(|h| h.addr == health.addr) {
+                health.interval = old.interval;
+                health.last_record = old.last_record;
+            }
+        }
+        self.healths = healths;
+    }
+
     pub async fn repeat_check(&mut self) -> ProxyResult<()> {
         loop {
             let recv = self.receiver.try_recv();
             match recv {
                 Ok(value) => {
-                    self.healths = value;
+                    self.update_healths(value);
                 }
                 Err(TryRecvError::Disconnected) => {
                     break;
@@ -135,6 +376,7 @@ impl ActiveHealth {
             for health in &mut self.healths {
                 if now.duration_since(health.last_record) > health.interval {
                     health.last_record = now;
+                    health.interval = health.check.next_interval();
                     let h = health.clone();
                     tokio::spawn(async move {
                         let _ = h.do_check().await;
@@ -153,3 +395,50 @@ impl ActiveHealth {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::net::UdpSocket;
+
+    use crate::test_util::fake_upstream;
+
+    use super::{ActiveCheckConfig, OneHealth, StatusRanges};
+
+    #[test]
+    fn do_test_status_ranges() {
+        let ranges = StatusRanges::from_str("200-299 404 5xx").unwrap();
+        assert!(ranges.contains(204) && ranges.contains(404) && ranges.contains(503));
+        assert!(!ranges.contains(302) && !ranges.contains(403));
+        assert_eq!(format!("{}", ranges), "200-299 404 500-599");
+        assert!(StatusRanges::from_str("abc").is_err());
+    }
+
+    #[tokio::test]
+    async fn do_test_check() {
+        let (addr, _) =
+            fake_upstream(|_| b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nstatus".to_vec()).await;
+        let check: ActiveCheckConfig =
+            toml::from_str("path = \"/health\"\nstatus = \"200\"\nbody = \"^stat\"").unwrap();
+        assert!(OneHealth::new(addr, check.clone(), None).check_one().await.is_ok());
+        let mut wrong = check.clone();
+        wrong.body = Some("^ok$".to_string());
+        assert!(OneHealth::new(addr, wrong, None).check_one().await.is_err());
+        let mut tcp = check;
+        tcp.check_type = "tcp".to_string();
+        assert!(OneHealth::new(addr, tcp, None).check_one().await.is_ok());
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_addr = udp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            while let Ok((_, from)) = udp.recv_from(&mut buf).await {
+                let _ = udp.send_to(b"pong", from).await;
+            }
+        });
+        let check: ActiveCheckConfig =
+            toml::from_str("type = \"udp\"\nsend = \"ping\"\nexpect = \"pong\"").unwrap();
+        assert!(OneHealth::new(udp_addr, check, None).check_one().await.is_ok());
+    }
+}
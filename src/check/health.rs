@@ -25,49 +25,141 @@ lazy_static! {
     static ref HEALTH_CHECK: RwLock<HealthCheck> = RwLock::new(HealthCheck::new(60, 3, 2));
 }
 
-/// 每个SocketAddr的记录值
-struct HealthRecord {
-    /// 最后的发起时间
-    last_request: Option<Instant>,
+/// 健康检查的判定参数, 不同的上游组对同一地址可配置不同的参数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HealthPolicy {
+    /// 失败的恢复时间, 超过该时间未有新的记录则重新计算状态
+    pub fail_timeout: Duration,
+    /// 连续失败达到该次数认为不可访问
+    pub fall_times: usize,
+    /// 连续成功达到该次数认为重新存活
+    pub rise_times: usize,
+}
+
+impl HealthPolicy {
+    pub fn new(fail_timeout: Duration, fall_times: usize, rise_times: usize) -> Self {
+        Self {
+            fail_timeout,
+            fall_times,
+            rise_times,
+        }
+    }
+}
+
+/// 单个判定参数下的状态
+#[derive(Debug, Clone)]
+struct HealthState {
     /// 最后的记录时间
     last_record: Instant,
-    /// 失败的恢复时间
-    fail_timeout: Duration,
     /// 当前连续失败的次数
     fall_times: usize,
     /// 当前连续成功的次数
     rise_times: usize,
     /// 当前的状态
     failed: bool,
+    /// 最近一次从失败中恢复的时间
+    recovered: Option<Instant>,
 }
 
-impl HealthRecord {
-    pub fn new(fail_timeout: usize) -> Self {
+impl HealthState {
+    fn new(now: Instant) -> Self {
         Self {
-            last_request: None,
-            last_record: Instant::now(),
-            fail_timeout: Duration::new(fail_timeout as u64, 0),
+            last_record: now,
             fall_times: 0,
             rise_times: 0,
             failed: false,
+            recovered: None,
         }
     }
 
-    pub fn clear_status(&mut self) {
-        self.fall_times = 0;
+    /// 超出失败的恢复时间, 重新计算状态
+    fn check_expire(&mut self, policy: &HealthPolicy, now: Instant) {
+        if now.duration_since(self.last_record) > policy.fail_timeout {
+            if self.failed {
+                self.recovered = Some(self.last_record + policy.fail_timeout);
+            }
+            self.fall_times = 0;
+            self.rise_times = 0;
+            self.failed = false;
+        }
+    }
+
+    fn add_fall_down(&mut self, policy: &HealthPolicy, now: Instant) {
+        self.check_expire(policy, now);
+        self.last_record = now;
+        self.fall_times += 1;
         self.rise_times = 0;
-        self.failed = false;
+        if self.fall_times >= policy.fall_times {
+            self.failed = true;
+        }
+    }
+
+    fn add_rise_up(&mut self, policy: &HealthPolicy, now: Instant) {
+        self.check_expire(policy, now);
+        self.last_record = now;
+        self.rise_times += 1;
+        self.fall_times = 0;
+        if self.rise_times >= policy.rise_times && self.failed {
+            self.failed = false;
+            self.recovered = Some(now);
+        }
+    }
+
+    fn is_fall_down(&self, policy: &HealthPolicy, now: Instant) -> bool {
+        self.failed && now.duration_since(self.last_record) <= policy.fail_timeout
+    }
+
+    fn recover_since(&self, policy: &HealthPolicy, now: Instant) -> Option<Instant> {
+        if self.failed {
+            // 超出失败的恢复时间后重新允许访问, 视为此时恢复
+            let since = self.last_record + policy.fail_timeout;
+            if now > since {
+                return Some(since);
+            }
+            return None;
+        }
+        self.recovered
+    }
+}
+
+/// 每个SocketAddr的记录值, 每个判定参数单独维护状态, 失败及成功的记录同时作用于所有参数
+struct HealthRecord {
+    /// 最后的发起时间
+    last_request: Option<Instant>,
+    /// 默认参数下的状态, 用于未配置参数的直接连接
+    default: HealthState,
+    /// 上游配置的参数下的状态
+    policies: HashMap<HealthPolicy, HealthState>,
+}
+
+impl HealthRecord {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_request: None,
+            default: HealthState::new(now),
+            policies: HashMap::new(),
+        }
+    }
+
+    fn add_fall_down(&mut self, default: &HealthPolicy, now: Instant) {
+        self.default.add_fall_down(default, now);
+        for (policy, state) in &mut self.policies {
+            state.add_fall_down(policy, now);
+        }
+    }
+
+    fn add_rise_up(&mut self, default: &HealthPolicy, now: Instant) {
+        self.default.add_rise_up(default, now);
+        for (policy, state) in &mut self.policies {
+            state.add_rise_up(policy, now);
+        }
     }
 }
 
 /// 健康检查的控制中心
 pub struct HealthCheck {
-    /// 健康检查的重置时间, 失败超过该时间会重新检查, 统一单位秒
-    fail_timeout: usize,
-    /// 最大失败次数, 一定时间内超过该次数认为不可访问
-    max_fails: usize,
-    /// 最小上线次数, 到达这个次数被认为存活
-    min_rises: usize,
+    /// 未配置参数的地址使用的默认参数
+    default: HealthPolicy,
     /// 记录跟地址相关的信息
     health_map: HashMap<SocketAddr, HealthRecord>,
 }
@@ -75,9 +167,11 @@ pub struct HealthCheck {
 impl HealthCheck {
     pub fn new(fail_timeout: usize, max_fails: usize, min_rises: usize) -> Self {
         Self {
-            fail_timeout,
-            max_fails,
-            min_rises,
+            default: HealthPolicy::new(
+                Duration::from_secs(fail_timeout as u64),
+                max_fails,
+                min_rises,
+            ),
             health_map: HashMap::new(),
         }
     }
@@ -88,21 +182,17 @@ impl HealthCheck {
 
     pub fn check_can_request(addr: &SocketAddr, duration: Duration) -> bool {
         if let Ok(mut h) = HEALTH_CHECK.write() {
-            if !h.health_map.contains_key(addr) {
-                let mut health = HealthRecord::new(30);
-                health.fall_times = 0;
-                health.last_request = Some(Instant::now());
-                h.health_map.insert(addr.clone(), health);
-                return true;
-            }
-            let value = h.health_map.get(&addr).unwrap();
-            let can = if let Some(ins) = value.last_request {
-                Instant::now().duration_since(ins) > duration
-            } else {
-                true
+            let now = Instant::now();
+            let value = h
+                .health_map
+                .entry(*addr)
+                .or_insert_with(|| HealthRecord::new(now));
+            let can = match value.last_request {
+                Some(ins) => now.duration_since(ins) > duration,
+                None => true,
             };
             if can {
-                h.health_map.get_mut(&addr).unwrap().last_request = Some(Instant::now());
+                value.last_request = Some(now);
             }
             can
         } else {
@@ -110,69 +200,67 @@ impl HealthCheck {
         }
     }
 
-    /// 检测状态是否能连接
+    /// 注册地址的判定参数, 之后的记录将按该参数计算状态
+    pub fn register(addr: SocketAddr, policy: HealthPolicy) {
+        if let Ok(mut h) = HEALTH_CHECK.write() {
+            let now = Instant::now();
+            h.health_map
+                .entry(addr)
+                .or_insert_with(|| HealthRecord::new(now))
+                .policies
+                .entry(policy)
+                .or_insert_with(|| HealthState::new(now));
+        }
+    }
+
+    /// 检测状态是否能连接, 已配置参数的地址由上游选择时按各自的参数判断, 此处不再拦截
     pub fn is_fall_down(addr: &SocketAddr) -> bool {
         // 只读，获取读锁
         if let Ok(h) = HEALTH_CHECK.read() {
-            if !h.health_map.contains_key(addr) {
-                return false;
-            }
-            let value = h.health_map.get(&addr).unwrap();
-            if Instant::now().duration_since(value.last_record) > value.fail_timeout {
-                return false;
+            match h.health_map.get(addr) {
+                Some(value) if value.policies.is_empty() => {
+                    value.default.is_fall_down(&h.default, Instant::now())
+                }
+                _ => false,
             }
-            value.failed
         } else {
             false
         }
     }
 
-    /// 检测状态是否能连接
-    pub fn check_fall_down(addr: &SocketAddr, fail_timeout: &Duration, fall_times: &usize, rise_times: &usize) -> bool {
+    /// 按指定的判定参数检测是否不可访问
+    pub fn check_fall_down(addr: &SocketAddr, policy: &HealthPolicy) -> bool {
         // 只读，获取读锁
         if let Ok(h) = HEALTH_CHECK.read() {
-            if !h.health_map.contains_key(addr) {
-                return false;
-            }
-            let value = h.health_map.get(&addr).unwrap();
-            if Instant::now().duration_since(value.last_record) > *fail_timeout {
-                return false;
-            }
-            if &value.fall_times >= fall_times {
-                return true;
-            }
-            if &value.rise_times >= rise_times {
-                return false;
+            if let Some(value) = h.health_map.get(addr) {
+                if let Some(state) = value.policies.get(policy) {
+                    return state.is_fall_down(policy, Instant::now());
+                }
             }
-            value.failed
         } else {
-            false
+            return false;
         }
+        Self::register(*addr, *policy);
+        false
+    }
+
+    /// 获取地址从失败中恢复的时间, 仍处于失败中或者从未失败过返回None
+    pub fn recover_since(addr: &SocketAddr, policy: &HealthPolicy) -> Option<Instant> {
+        let h = HEALTH_CHECK.read().ok()?;
+        let state = h.health_map.get(addr)?.policies.get(policy)?;
+        state.recover_since(policy, Instant::now())
     }
 
     /// 失败时调用
     pub fn add_fall_down(addr: SocketAddr) {
         // 需要写入，获取写入锁
         if let Ok(mut h) = HEALTH_CHECK.write() {
-            if !h.health_map.contains_key(&addr) {
-                let mut health = HealthRecord::new(h.fail_timeout);
-                health.fall_times = 1;
-                h.health_map.insert(addr, health);
-            } else {
-                let max_fails = h.max_fails;
-                let value = h.health_map.get_mut(&addr).unwrap();
-                // 超出最大的失败时长，重新计算状态
-                if Instant::now().duration_since(value.last_record) > value.fail_timeout {
-                    value.clear_status();
-                }
-                value.last_record = Instant::now();
-                value.fall_times += 1;
-                value.rise_times = 0;
-
-                if value.fall_times >= max_fails {
-                    value.failed = true;
-                }
-            }
+            let now = Instant::now();
+            let default = h.default;
+            h.health_map
+                .entry(addr)
+                .or_insert_with(|| HealthRecord::new(now))
+                .add_fall_down(&default, now);
         }
     }
 
@@ -180,25 +268,12 @@ impl HealthCheck {
     pub fn add_rise_up(addr: SocketAddr) {
         // 需要写入，获取写入锁
         if let Ok(mut h) = HEALTH_CHECK.write() {
-            if !h.health_map.contains_key(&addr) {
-                let mut health = HealthRecord::new(h.fail_timeout);
-                health.rise_times = 1;
-                h.health_map.insert(addr, health);
-            } else {
-                let min_rises = h.min_rises;
-                let value = h.health_map.get_mut(&addr).unwrap();
-                // 超出最大的失败时长，重新计算状态
-                if Instant::now().duration_since(value.last_record) > value.fail_timeout {
-                    value.clear_status();
-                }
-                value.last_record = Instant::now();
-                value.rise_times += 1;
-                value.fall_times = 0;
-
-                if value.rise_times >= min_rises {
-                    value.failed = false;
-                }
-            }
+            let now = Instant::now();
+            let default = h.default;
+            h.health_map
+                .entry(addr)
+                .or_insert_with(|| HealthRecord::new(now))
+                .add_rise_up(&default, now);
         }
     }
 
@@ -253,8 +328,54 @@ impl HealthCheck {
         } else {
             match tokio::time::timeout(connect.unwrap(), HealthCheck::connect(addr)).await {
                 Ok(s) => s,
-                Err(_) => return Err(io::Error::new(io::ErrorKind::NotConnected, "timeout")),
+                Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "timeout")),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{HealthPolicy, HealthRecord};
+
+    #[test]
+    fn do_test_policy() {
+        let default = HealthPolicy::new(Duration::from_secs(60), 3, 2);
+        let strict = HealthPolicy::new(Duration::from_secs(10), 1, 3);
+        let loose = HealthPolicy::new(Duration::from_secs(30), 5, 1);
+        let now = Instant::now();
+        let mut record = HealthRecord::new(now);
+        record.policies.insert(strict, super::HealthState::new(now));
+        record.policies.insert(loose, super::HealthState::new(now));
+
+        // 同一地址的失败记录, 按各自的参数判定
+        record.add_fall_down(&default, now);
+        assert!(record.policies[&strict].is_fall_down(&strict, now));
+        assert!(!record.policies[&loose].is_fall_down(&loose, now));
+        for _ in 0..4 {
+            record.add_fall_down(&default, now);
+        }
+        assert!(record.policies[&loose].is_fall_down(&loose, now));
+
+        // 连续成功的次数达到各自的参数后恢复
+        record.add_rise_up(&default, now);
+        assert!(!record.policies[&loose].is_fall_down(&loose, now));
+        assert!(record.policies[&strict].is_fall_down(&strict, now));
+        record.add_rise_up(&default, now);
+        record.add_rise_up(&default, now);
+        assert!(!record.policies[&strict].is_fall_down(&strict, now));
+        assert_eq!(record.policies[&strict].recover_since(&strict, now), Some(now));
+
+        // 超出失败的恢复时间后不再拦截
+        record.add_fall_down(&default, now);
+        assert!(record.policies[&strict].is_fall_down(&strict, now));
+        let later = now + Duration::from_secs(11);
+        assert!(!record.policies[&strict].is_fall_down(&strict, later));
+        assert_eq!(
+            record.policies[&strict].recover_since(&strict, later),
+            Some(now + Duration::from_secs(10))
+        );
+    }
+}
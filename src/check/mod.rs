@@ -14,5 +14,5 @@
 mod health;
 mod active;
 
-pub use health::HealthCheck;
-pub use active::{ActiveHealth, OneHealth};
\ No newline at end of file
+pub use health::{HealthCheck, HealthPolicy};
+pub use active::{ActiveCheckConfig, ActiveHealth, OneHealth, StatusRanges};
\ No newline at end of file
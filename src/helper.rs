@@ -16,9 +16,11 @@ use std::{
     fs::{remove_file, File},
     io::{self, Read, Write},
     net::{SocketAddr, ToSocketAddrs},
+    pin::Pin,
     process::id,
     str::FromStr,
     sync::{Arc, Mutex},
+    task::Poll,
 };
 
 use crate::{
@@ -34,8 +36,13 @@ use log4rs::{
 };
 use regex::Regex;
 use socket2::{Domain, Socket, Type};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use webparse::{http2::frame::read_u24, BinaryMut, Buf, Request, Response, Serialize};
+use tokio::{
+    io::AsyncRead,
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc::channel,
+    time::Instant,
+};
+use webparse::{http2::frame::read_u24, Binary, BinaryMut, Buf, HeaderName, Request, Response, Serialize};
 use wenmeng::{Body, HeaderHelper};
 
 thread_local! {
@@ -49,6 +56,16 @@ lazy_static! {
 /// 帮助类相关
 pub struct Helper;
 
+/// 转发body时每块数据的处理结果
+pub enum BodyPump {
+    /// 转发该数据, 为空时不发送, 原body读完时随结束标记一起发送
+    Send(Binary),
+    /// 停止转发, 不结束body
+    Stop,
+    /// 停止转发, 保持body不结束直到读取端释放
+    Hold,
+}
+
 impl Helper {
     pub fn decode_frame(read: &mut BinaryMut) -> ProxyResult<Option<ProtFrame>> {
         let data_len = read.remaining();
@@ -227,6 +244,119 @@ impl Helper {
         }
     }
 
+    /// 在Vary中添加字段, 已存在时忽略
+    pub fn add_vary(res: &mut Response<Body>, name: &str) {
+        match res.headers().get_str_value(&HeaderName::VARY) {
+            Some(vary) => {
+                let exist = vary
+                    .split(',')
+                    .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case(name));
+                if !exist {
+                    res.headers_mut()
+                        .insert(HeaderName::VARY, format!("{}, {}", vary, name));
+                }
+            }
+            None => {
+                res.headers_mut().insert(HeaderName::VARY, name.to_string());
+            }
+        }
+    }
+
+    /// 读取body的数据, 返回0表示已读完
+    pub async fn read_body(body: &mut Body, buf: &mut [u8]) -> io::Result<usize> {
+        std::future::poll_fn(|cx| {
+            let mut read = tokio::io::ReadBuf::new(buf);
+            match Pin::new(&mut *body).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) => {
+                    let size = read.filled().len();
+                    // 未读到数据且未结束表示在等待对端的数据
+                    if size == 0 && !body.is_end() {
+                        Poll::Pending
+                    } else {
+                        Poll::Ready(Ok(size))
+                    }
+                }
+                Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// 以新的流式body替换原body, 原body的数据按原始的压缩方式读出并经on_chunk处理后转发,
+    /// 读完时以空数据及true调用on_chunk, data为最先发送的数据, 到达deadline时停止转发,
+    /// 返回新的body以便调用方修改压缩方式
+    pub fn pump_body<F>(
+        body: &mut Body,
+        data: BinaryMut,
+        deadline: Option<Instant>,
+        mut on_chunk: F,
+    ) -> &mut Body
+    where
+        F: FnMut(&[u8], bool) -> BodyPump + Send + 'static,
+    {
+        let (sender, receiver) = channel::<(bool, Binary)>(10);
+        let mut new_body = Body::new(receiver, data, false);
+        let method = body.get_origin_compress();
+        new_body.set_origin_compress_method(method);
+        let mut origin = std::mem::replace(body, new_body);
+        origin.add_compress_method(method);
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 16384];
+            loop {
+                let size = tokio::select! {
+                    r = Self::read_body(&mut origin, &mut buf) => match r {
+                        Ok(size) => size,
+                        Err(_) => return,
+                    },
+                    _ = Self::sleep_until(deadline) => {
+                        log::warn!("转发数据超出截止时间");
+                        return;
+                    }
+                    _ = sender.closed() => return,
+                };
+                let is_end = size == 0;
+                match on_chunk(&buf[..size], is_end) {
+                    BodyPump::Send(data) => {
+                        if (is_end || !data.is_empty())
+                            && sender.send((is_end, data)).await.is_err()
+                        {
+                            return;
+                        }
+                    }
+                    BodyPump::Stop => return,
+                    BodyPump::Hold => {
+                        sender.closed().await;
+                        return;
+                    }
+                }
+                if is_end {
+                    return;
+                }
+            }
+        });
+        body
+    }
+
+    async fn sleep_until(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// 修改请求的地址, 同时更新url中的path及query
+    pub fn set_req_path(req: &mut Request<Body>, target: String) {
+        let (path, query) = match target.split_once('?') {
+            Some((p, q)) => (p.to_string(), Some(q.to_string())),
+            None => (target.clone(), None),
+        };
+        let url = &mut req.parts_mut().url;
+        url.path = path;
+        url.query = query;
+        req.set_path(target);
+    }
+
     pub fn format_req(req: &Request<Body>, formats: &str) -> String {
         let pw = FORMAT_PATTERN_CACHE.with(|m| {
             if !m.borrow().contains_key(&formats) {
@@ -551,4 +681,13 @@ mod tests {
         let val = Helper::format_req_may_regex(req, format);
         assert_eq!(val, "http://127.0.0.1/formal/st/root?query=1&a=b");
     }
+
+    #[test]
+    fn do_test_header() {
+        let req = &build_request();
+        let val = Helper::format_req(req, "{header(accept)}-{path}");
+        assert_eq!(val, "text/html-/test/root");
+        let val = Helper::format_req(req, "{header(x-user-id)}");
+        assert_eq!(val, "");
+    }
 }
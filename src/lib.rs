@@ -29,6 +29,8 @@ mod config;
 mod plugins;
 pub mod log;
 mod data;
+#[cfg(test)]
+mod test_util;
 pub mod arg;
 
 pub use error::{ProxyResult, ProxyError};
@@ -38,7 +40,7 @@ pub use wmcore::WMCore;
 pub use proxy::http::ProxyHttp;
 pub use proxy::socks5::ProxySocks5;
 pub use streams::*;
-pub use helper::Helper;
+pub use helper::{Helper, BodyPump};
 pub use prot::{ProtFrame, ProtFrameHeader, ProtClose, ProtData, ProtCreate};
 pub use mapping::*;
 pub use check::*;
@@ -130,6 +130,7 @@ use std::{default::Default, io, process, thread};
 // };
 
 use crate::log::{Style, Color, Encode};
+use crate::reverse::{JwtConfig, ServerName};
 
 use self::parser::{Parameters, Alignment, Piece, Parser};
 
@@ -496,6 +497,18 @@ impl<'a> From<Piece<'a>> for Chunk {
                         params: parameters,
                     }
                 }
+                "header" => {
+                    if formatter.args.len() != 1 {
+                        return Chunk::Error("expected exactly one argument".to_owned());
+                    }
+                    match formatter.args[0].first() {
+                        Some(Piece::Text(name)) => Chunk::Formatted {
+                            chunk: FormattedChunk::Header(name.trim().to_owned()),
+                            params: parameters,
+                        },
+                        _ => Chunk::Error("invalid header name".to_owned()),
+                    }
+                }
                 "client_ip" => no_args(&formatter.args, parameters, FormattedChunk::ClientIp),
                 "client_user" => no_args(&formatter.args, parameters, FormattedChunk::ClientUser),
                 "url" => no_args(&formatter.args, parameters, FormattedChunk::Url),
@@ -513,6 +526,30 @@ impl<'a> From<Piece<'a>> for Chunk {
                 "up_addr" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamAddr),
                 "request_time" => no_args(&formatter.args, parameters, FormattedChunk::RequestTime),
                 "up_response_time" => no_args(&formatter.args, parameters, FormattedChunk::UpstreamResponseTime),
+                "cache_status" => no_args(&formatter.args, parameters, FormattedChunk::CacheStatus),
+                "server_name" => match formatter.args.first().map(|a| a.first()) {
+                    None => Chunk::Formatted {
+                        chunk: FormattedChunk::ServerName(None),
+                        params: parameters,
+                    },
+                    Some(Some(Piece::Text(name))) if formatter.args.len() == 1 => Chunk::Formatted {
+                        chunk: FormattedChunk::ServerName(Some(name.trim().to_owned())),
+                        params: parameters,
+                    },
+                    _ => Chunk::Error("invalid server_name capture name".to_owned()),
+                },
+                "jwt" => {
+                    if formatter.args.len() != 1 {
+                        return Chunk::Error("expected exactly one argument".to_owned());
+                    }
+                    match formatter.args[0].first() {
+                        Some(Piece::Text(name)) => Chunk::Formatted {
+                            chunk: FormattedChunk::Jwt(name.trim().to_owned()),
+                            params: parameters,
+                        },
+                        _ => Chunk::Error("invalid jwt claim name".to_owned()),
+                    }
+                }
 
                 "" => {
                     if formatter.args.len() != 1 {
@@ -571,6 +608,7 @@ enum FormattedChunk {
     Mdc(String, String),
 
     /// for request or response
+    Header(String),
     ClientIp,
     ClientUser,
     Url,
@@ -588,6 +626,10 @@ enum FormattedChunk {
     UpstreamAddr,
     RequestTime,
     UpstreamResponseTime,
+    CacheStatus,
+    /// JWT校验通过后的声明
+    Jwt(String),
+    ServerName(Option<String>),
 }
 
 impl FormattedChunk {
@@ -648,9 +690,19 @@ impl FormattedChunk {
                 // log_mdc::get(key, |v| write!(w, "{}", v.unwrap_or(default)))
                 Ok(())
             }
+            FormattedChunk::Header(ref name) => {
+                if let Some(req) = record.req {
+                    if let Some(value) = req.headers().get_str_value(name) {
+                        w.write_all(value.as_bytes())?;
+                    }
+                }
+                Ok(())
+            }
             FormattedChunk::ClientIp => {
                 if let Some(req) = record.req {
-                    if let Some(client_ip) = req.headers().system_get("{client_ip}") {
+                    // 配置了trusted_proxies时记录客户端的真实地址
+                    let ip = req.headers().system_get("{remote_ip}");
+                    if let Some(client_ip) = ip.or(req.headers().system_get("{client_ip}")) {
                         w.write(client_ip.as_bytes())?;
                     } else {
                         w.write("???".as_bytes())?;
@@ -658,6 +710,36 @@ impl FormattedChunk {
                 }
                 Ok(())
             }
+            FormattedChunk::CacheStatus => {
+                if let Some(req) = record.req {
+                    if let Some(status) = req.headers().system_get("{cache_status}") {
+                        w.write_all(status.as_bytes())?;
+                    } else {
+                        w.write_all("-".as_bytes())?;
+                    };
+                }
+                Ok(())
+            }
+            FormattedChunk::ServerName(ref name) => {
+                if let Some(req) = record.req {
+                    let key = match name {
+                        Some(name) => ServerName::capture_key(name),
+                        None => "{server_name}".to_string(),
+                    };
+                    if let Some(value) = req.headers().system_get(&key) {
+                        w.write_all(value.as_bytes())?;
+                    }
+                }
+                Ok(())
+            }
+            FormattedChunk::Jwt(ref name) => {
+                if let Some(req) = record.req {
+                    if let Some(value) = req.headers().system_get(&JwtConfig::claim_key(name)) {
+                        w.write_all(value.as_bytes())?;
+                    }
+                }
+                Ok(())
+            }
             FormattedChunk::ClientUser => {
                 Ok(())
             }
@@ -17,7 +17,6 @@ use std::{
     net::{IpAddr, SocketAddr},
     process,
     sync::Arc,
-    time::Duration,
 };
 
 use bpaf::*;
@@ -708,19 +707,16 @@ impl ConfigOption {
         configs: &Vec<UpstreamConfig>,
     ) {
         for up in configs {
-            if up.bind == "udp" {
-                continue;
-            }
-            for s in &up.server {
-                if already.contains(&s.addr) {
+            let check = match &up.health_check {
+                Some(check) => check,
+                None => continue,
+            };
+            for s in up.get_peers().iter() {
+                if s.down || already.contains(&s.addr) {
                     continue;
                 }
                 already.insert(s.addr);
-                result.push(OneHealth::new(
-                    s.addr,
-                    "http".to_string(),
-                    Duration::from_secs(1),
-                ));
+                result.push(OneHealth::new(s.addr, check.clone(), up.tls.clone()));
             }
         }
     }
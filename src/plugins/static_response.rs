@@ -10,26 +10,151 @@
 // -----
 // Created Date: 2024/01/24 09:42:22
 
-use std::{fmt::Display, str::FromStr};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
 
-use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
-use webparse::Response;
-use wenmeng::{RecvRequest, ProtResult, RecvResponse};
+use serde::{Deserialize, Deserializer, Serialize};
+use webparse::{HeaderName, Response};
+use wenmeng::{ProtError, ProtResult, RecvRequest, RecvResponse};
 
 use crate::{Helper, ProxyError};
 
-/// HTTP静态数据返回
-#[serde_as]
+fn default_template() -> bool {
+    true
+}
+
+/// HTTP静态数据返回, 可直接配置为字符串即返回200的文本
+/// 如 { status = 503, content_type = "text/html", file = "html/maintain.html" }
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct StaticResponse {
-    body: &'static str,
+    /// 返回的状态码, 配置了location时默认为302, 否则为200
+    pub status: Option<u16>,
+    /// 返回的Content-Type, 为空时按内容推断
+    pub content_type: Option<String>,
+    /// 附加的响应头, 值可使用{client_ip}等变量
+    #[serde(default = "HashMap::new")]
+    pub headers: HashMap<String, String>,
+    /// 重定向的地址
+    pub location: Option<String>,
+    /// 返回的内容
+    pub body: Option<String>,
+    /// 从文件中读取返回的内容, 每次请求时读取
+    pub file: Option<String>,
+    /// 以json返回的内容, 其中的字符串值同样可使用变量
+    pub json: Option<serde_json::Value>,
+    /// 是否将内容中的{client_ip}等变量替换, 内容中有{}()时需用双写转义
+    #[serde(default = "default_template")]
+    pub template: bool,
 }
 
 impl StaticResponse {
+    pub fn new(body: String) -> Self {
+        Self {
+            status: None,
+            content_type: None,
+            headers: HashMap::new(),
+            location: None,
+            body: Some(body),
+            file: None,
+            json: None,
+            template: true,
+        }
+    }
+
+    /// 配置中可为字符串或完整的结构
+    pub fn string_or_struct<'de, D>(deserializer: D) -> Result<Option<StaticResponse>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Str(String),
+            Full(StaticResponse),
+        }
+
+        Ok(match Option::<Value>::deserialize(deserializer)? {
+            Some(Value::Str(s)) => Some(StaticResponse::new(s)),
+            Some(Value::Full(v)) => Some(v),
+            None => None,
+        })
+    }
+
+    fn format(&self, req: &RecvRequest, val: &str) -> String {
+        if self.template {
+            Helper::format_req(req, val)
+        } else {
+            val.to_string()
+        }
+    }
+
+    /// 将json中的字符串值按请求格式化
+    fn format_json(&self, req: &RecvRequest, value: &serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::String(s) => serde_json::Value::String(self.format(req, s)),
+            serde_json::Value::Array(list) => {
+                serde_json::Value::Array(list.iter().map(|v| self.format_json(req, v)).collect())
+            }
+            serde_json::Value::Object(map) => serde_json::Value::Object(
+                map.iter()
+                    .map(|(k, v)| (k.clone(), self.format_json(req, v)))
+                    .collect(),
+            ),
+            _ => value.clone(),
+        }
+    }
+
+    /// 未配置Content-Type时按内容推断
+    fn guess_content_type(&self) -> &'static str {
+        if self.json.is_some() {
+            return "application/json; charset=utf-8";
+        }
+        if let Some(file) = &self.file {
+            let file = file.to_lowercase();
+            if file.ends_with(".html") || file.ends_with(".htm") {
+                return "text/html; charset=utf-8";
+            }
+            if file.ends_with(".json") {
+                return "application/json; charset=utf-8";
+            }
+        }
+        "text/plain; charset=utf-8"
+    }
+
     pub async fn deal_request(&self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
-        let val = Helper::format_req(req, self.body);
-        return Ok(Response::text().body(val).unwrap().into_type());
+        let body = if let Some(json) = &self.json {
+            serde_json::to_string(&self.format_json(req, json))
+                .map_err(|_| ProtError::Extension("json format error"))?
+        } else if let Some(file) = &self.file {
+            let data = tokio::fs::read_to_string(file).await?;
+            self.format(req, &data)
+        } else if let Some(body) = &self.body {
+            self.format(req, body)
+        } else {
+            String::new()
+        };
+
+        let status = match (self.status, &self.location) {
+            (Some(status), _) => status,
+            (None, Some(_)) => 302,
+            (None, None) => 200,
+        };
+        let content_type = match &self.content_type {
+            Some(t) => t.clone(),
+            None => self.guess_content_type().to_string(),
+        };
+        let mut builder = Response::builder()
+            .status(status)
+            .header(HeaderName::CONTENT_TYPE, content_type);
+        if let Some(location) = &self.location {
+            builder = builder.header(HeaderName::LOCATION, Helper::format_req(req, location));
+        }
+        for (k, v) in &self.headers {
+            builder = builder.header(k.clone(), Helper::format_req(req, v));
+        }
+        Ok(builder
+            .body(body)
+            .map_err(|_| ProtError::Extension("static response build error"))?
+            .into_type())
     }
 }
 
@@ -37,14 +162,67 @@ impl FromStr for StaticResponse {
     type Err = ProxyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(StaticResponse {
-            body: Helper::get_static_str(s),
-        })
+        Ok(StaticResponse::new(s.to_string()))
     }
 }
 
 impl Display for StaticResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.body)
+        f.write_str(self.body.as_deref().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use webparse::{BinaryMut, Buf, Request};
+    use wenmeng::Body;
+
+    use super::StaticResponse;
+
+    async fn read_body(res: &mut webparse::Response<Body>) -> String {
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        String::from_utf8_lossy(data.chunk()).to_string()
+    }
+
+    #[tokio::test]
+    async fn do_test_static_response() {
+        let mut req = Request::builder()
+            .url("http://wmproxy.net/health")
+            .body(Body::empty())
+            .unwrap();
+
+        let mut res = StaticResponse::new("I'm Ok {host}".to_string())
+            .deal_request(&mut req)
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(read_body(&mut res).await, "I'm Ok wmproxy.net");
+
+        let mut value = StaticResponse::new(String::new());
+        value.location = Some("https://{host}/new".to_string());
+        value.headers = HashMap::from([("X-From".to_string(), "wmproxy".to_string())]);
+        let res = value.deal_request(&mut req).await.unwrap();
+        assert_eq!(res.status(), 302);
+        assert_eq!(
+            res.headers().get_str_value(&"Location").unwrap(),
+            "https://wmproxy.net/new"
+        );
+        assert_eq!(res.headers().get_str_value(&"X-From").unwrap(), "wmproxy");
+
+        let mut value = StaticResponse::new(String::new());
+        value.status = Some(503);
+        value.json = Some(serde_json::json!({"status": "down", "host": "{host}", "code": 503}));
+        let mut res = value.deal_request(&mut req).await.unwrap();
+        assert_eq!(res.status(), 503);
+        assert_eq!(
+            res.headers().get_str_value(&"Content-Type").unwrap(),
+            "application/json; charset=utf-8"
+        );
+        let json: serde_json::Value = serde_json::from_str(&read_body(&mut res).await).unwrap();
+        assert_eq!(json["host"], "wmproxy.net");
+        assert_eq!(json["code"], 503);
     }
 }
@@ -0,0 +1,333 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/13 10:18:25
+
+use std::{collections::HashMap, sync::RwLock, time::SystemTime};
+
+use base64::{engine::general_purpose, Engine};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use sha1::{Digest, Sha1};
+use webparse::{HeaderName, Method, Request, Response, Url};
+use wenmeng::{Body, ProtError, ProtResult};
+
+use crate::Helper;
+
+use super::LocationConfig;
+
+lazy_static! {
+    /// 已加载的用户文件, 文件修改后重新加载
+    static ref USER_FILES: RwLock<HashMap<String, UserFile>> = RwLock::new(HashMap::new());
+}
+
+struct UserFile {
+    modified: Option<SystemTime>,
+    users: HashMap<String, String>,
+}
+
+fn default_realm() -> String {
+    "Restricted".to_string()
+}
+
+/// Basic认证, 用户文件为htpasswd格式, 每行为"用户名:密码哈希"
+/// 密码支持bcrypt($2y$), {SHA}, {SSHA}及{PLAIN}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthBasic {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    pub user_file: String,
+}
+
+impl AuthBasic {
+    /// 解析htpasswd格式的内容, 忽略空行及#开头的注释
+    fn parse_users(content: &str) -> HashMap<String, String> {
+        let mut users = HashMap::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((user, hash)) = line.split_once(':') {
+                users.insert(user.to_string(), hash.to_string());
+            }
+        }
+        users
+    }
+
+    /// 获取用户的密码哈希, 文件有变化时重新加载
+    async fn get_user_hash(&self, user: &str) -> ProtResult<Option<String>> {
+        let modified = tokio::fs::metadata(&self.user_file)
+            .await?
+            .modified()
+            .ok();
+        {
+            let files = USER_FILES.read().unwrap();
+            if let Some(file) = files.get(&self.user_file) {
+                if modified.is_some() && file.modified == modified {
+                    return Ok(file.users.get(user).cloned());
+                }
+            }
+        }
+        let content = tokio::fs::read_to_string(&self.user_file).await?;
+        let users = Self::parse_users(&content);
+        log::trace!("加载认证用户文件{}, 共{}个用户", self.user_file, users.len());
+        let hash = users.get(user).cloned();
+        USER_FILES
+            .write()
+            .unwrap()
+            .insert(self.user_file.clone(), UserFile { modified, users });
+        Ok(hash)
+    }
+
+    /// 校验密码是否与哈希匹配
+    pub fn verify_password(password: &str, hash: &str) -> bool {
+        if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+            return bcrypt::verify(password, hash).unwrap_or(false);
+        }
+        if let Some(hash) = hash.strip_prefix("{SHA}") {
+            return general_purpose::STANDARD.encode(Sha1::digest(password)) == hash;
+        }
+        if let Some(hash) = hash.strip_prefix("{SSHA}") {
+            let data = match general_purpose::STANDARD.decode(hash) {
+                Ok(data) if data.len() > 20 => data,
+                _ => return false,
+            };
+            let mut sha = Sha1::new();
+            sha.update(password);
+            sha.update(&data[20..]);
+            return sha.finalize()[..] == data[..20];
+        }
+        if let Some(hash) = hash.strip_prefix("{PLAIN}") {
+            return password == hash;
+        }
+        log::warn!("不支持的密码格式, 仅支持bcrypt, {{SHA}}, {{SSHA}}及{{PLAIN}}");
+        false
+    }
+
+    /// 从Authorization头中获取用户名及密码
+    fn get_user_password(req: &Request<Body>) -> Option<(String, String)> {
+        let value = req.headers().get_str_value(&HeaderName::AUTHORIZATION)?;
+        let (scheme, token) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let data = general_purpose::STANDARD.decode(token.trim()).ok()?;
+        let value = String::from_utf8(data).ok()?;
+        let (user, password) = value.split_once(':')?;
+        Some((user.to_string(), password.to_string()))
+    }
+
+    fn unauthorized(&self) -> Response<Body> {
+        Response::builder()
+            .status(401)
+            .header(
+                HeaderName::WWW_AUTHENTICATE,
+                format!("Basic realm=\"{}\"", self.realm),
+            )
+            .body("401 Authorization Required")
+            .unwrap()
+            .into_type()
+    }
+
+    /// 认证通过返回None, 否则返回401
+    pub async fn deal_request(&self, req: &mut Request<Body>) -> ProtResult<Option<Response<Body>>> {
+        let (user, password) = match Self::get_user_password(req) {
+            Some(v) => v,
+            None => return Ok(Some(self.unauthorized())),
+        };
+        let hash = match self.get_user_hash(&user).await? {
+            Some(hash) => hash,
+            None => {
+                log::warn!("认证的用户{}不存在", user);
+                return Ok(Some(self.unauthorized()));
+            }
+        };
+        // bcrypt的计算较耗时, 不阻塞当前的线程
+        let ok = tokio::task::spawn_blocking(move || Self::verify_password(&password, &hash))
+            .await
+            .unwrap_or(false);
+        if !ok {
+            log::warn!("认证的用户{}密码错误", user);
+            return Ok(Some(self.unauthorized()));
+        }
+        Ok(None)
+    }
+}
+
+/// 子请求认证, 向url发送不带body的GET请求, 2xx为通过, 401及403为拒绝, 其它返回500
+/// 通过时将headers中的响应头复制到代理的请求中, 如X-User-Id
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthRequest {
+    #[serde_as(as = "DisplayFromStr")]
+    pub url: Url,
+    #[serde(default = "Vec::new")]
+    pub headers: Vec<String>,
+}
+
+impl AuthRequest {
+    /// 构建子请求, 保留原请求的头, 附加X-Original-URI及X-Original-Method
+    fn build_request(&self, req: &mut Request<Body>) -> Request<Body> {
+        let mut sub = req.replace_clone(Body::empty());
+        sub.set_method(Method::Get);
+        sub.headers_mut().remove(&HeaderName::CONTENT_LENGTH);
+        sub.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+        sub.headers_mut()
+            .insert("X-Original-URI", req.path().clone());
+        sub.headers_mut()
+            .insert("X-Original-Method", req.method().as_str().to_string());
+        let path = match &self.url.query {
+            Some(query) => format!("{}?{}", self.url.path, query),
+            None => self.url.path.clone(),
+        };
+        Helper::set_req_path(&mut sub, path);
+        sub
+    }
+
+    /// 认证通过返回None, 拒绝时返回认证服务的状态
+    pub async fn deal_request(
+        &self,
+        location: &LocationConfig,
+        req: &mut Request<Body>,
+    ) -> ProtResult<Option<Response<Body>>> {
+        // 防止客户端伪造转发给上游的头
+        for name in &self.headers {
+            req.headers_mut().remove(name);
+        }
+        let mut sub = self.build_request(req);
+        let res = location.deal_reverse_proxy(&mut sub, &self.url).await?;
+        let status = res.status().as_u16();
+        if res.status().is_success() {
+            for name in &self.headers {
+                if let Some(value) = res.headers().get_str_value(name) {
+                    req.headers_mut().insert(name.clone(), value);
+                }
+            }
+            return Ok(None);
+        }
+        if status == 401 || status == 403 {
+            let mut builder = Response::builder().status(status);
+            if let Some(value) = res.headers().get_str_value(&HeaderName::WWW_AUTHENTICATE) {
+                builder = builder.header(HeaderName::WWW_AUTHENTICATE, value);
+            }
+            return Ok(Some(builder.body(Body::empty()).unwrap()));
+        }
+        log::warn!("认证子请求返回了非预期的状态码{}", status);
+        Err(ProtError::Extension("auth request unexpected status"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{HeaderName, Request, Url};
+    use wenmeng::Body;
+
+    use crate::{reverse::LocationConfig, test_util::fake_upstream};
+
+    use super::{AuthBasic, AuthRequest};
+
+    #[tokio::test]
+    async fn do_test_auth_basic() {
+        assert!(AuthBasic::verify_password("wmproxy", "{SHA}qi9VdXlNIDj9B9NBC4oeee/miCU="));
+        assert!(!AuthBasic::verify_password("other", "{SHA}qi9VdXlNIDj9B9NBC4oeee/miCU="));
+        assert!(AuthBasic::verify_password("wmproxy", "{PLAIN}wmproxy"));
+        let hash = bcrypt::hash("wmproxy", 4).unwrap();
+        assert!(AuthBasic::verify_password("wmproxy", &hash));
+        assert!(!AuthBasic::verify_password("wmproxy", "$apr1$abc$def"));
+
+        let file = std::env::temp_dir().join("wmproxy_htpasswd_test");
+        std::fs::write(&file, format!("# users\nadmin:{}\nguest:{{PLAIN}}123\n", hash)).unwrap();
+        let auth = AuthBasic {
+            realm: "wmproxy".to_string(),
+            user_file: file.to_string_lossy().to_string(),
+        };
+        let build_req = |auth: Option<&str>| {
+            let mut builder = Request::builder().url("http://wmproxy.net/");
+            if let Some(auth) = auth {
+                builder = builder.header(HeaderName::AUTHORIZATION, auth.to_string());
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let res = auth.deal_request(&mut build_req(None)).await.unwrap().unwrap();
+        assert_eq!(res.status(), 401);
+        assert_eq!(
+            res.headers().get_str_value(&"WWW-Authenticate").unwrap(),
+            "Basic realm=\"wmproxy\""
+        );
+        // admin:wmproxy
+        let mut req = build_req(Some("Basic YWRtaW46d21wcm94eQ=="));
+        assert!(auth.deal_request(&mut req).await.unwrap().is_none());
+        // admin:wrong
+        let mut req = build_req(Some("Basic YWRtaW46d3Jvbmc="));
+        assert!(auth.deal_request(&mut req).await.unwrap().is_some());
+        // guest:123
+        let mut req = build_req(Some("Basic Z3Vlc3Q6MTIz"));
+        assert!(auth.deal_request(&mut req).await.unwrap().is_none());
+        let _ = std::fs::remove_file(&file);
+    }
+
+    #[tokio::test]
+    async fn do_test_auth_request() {
+        let (addr, _) = fake_upstream(|req| {
+            let data = String::from_utf8_lossy(req).to_lowercase();
+            assert!(data.starts_with("get /verify?app=1 "));
+            assert!(data.contains("x-original-uri: /api/list"));
+            let res: &[u8] = if data.contains("authorization: token ok") {
+                b"HTTP/1.1 200 OK\r\nX-User-Id: 10\r\nContent-Length: 2\r\n\r\nok"
+            } else if data.contains("authorization: token guest") {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+            } else if data.contains("authorization:") {
+                b"HTTP/1.1 403 Forbidden\r\nContent-Length: 2\r\n\r\nok"
+            } else {
+                b"HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Bearer\r\nContent-Length: 2\r\n\r\nok"
+            };
+            res.to_vec()
+        })
+        .await;
+
+        let auth = AuthRequest {
+            url: Url::parse(format!("http://{}/verify?app=1", addr).into_bytes()).unwrap(),
+            headers: vec!["X-User-Id".to_string()],
+        };
+        let location = LocationConfig::new();
+        let build_req = |auth: Option<&str>| {
+            let mut builder = Request::builder()
+                .method("POST")
+                .url("http://wmproxy.net/api/list");
+            if let Some(auth) = auth {
+                builder = builder.header(HeaderName::AUTHORIZATION, auth.to_string());
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let mut req = build_req(Some("Token ok"));
+        assert!(auth.deal_request(&location, &mut req).await.unwrap().is_none());
+        assert_eq!(req.headers().get_str_value(&"X-User-Id").unwrap(), "10");
+
+        // 认证服务未返回的头不能由客户端伪造
+        let mut req = build_req(Some("Token guest"));
+        req.headers_mut().insert("X-User-Id", "1");
+        assert!(auth.deal_request(&location, &mut req).await.unwrap().is_none());
+        assert!(req.headers().get_str_value(&"X-User-Id").is_none());
+
+        let mut req = build_req(None);
+        let res = auth.deal_request(&location, &mut req).await.unwrap().unwrap();
+        assert_eq!(res.status(), 401);
+        assert_eq!(res.headers().get_str_value(&"WWW-Authenticate").unwrap(), "Bearer");
+
+        let mut req = build_req(Some("Token bad"));
+        let res = auth.deal_request(&location, &mut req).await.unwrap().unwrap();
+        assert_eq!(res.status(), 403);
+        assert!(req.headers().get_str_value(&"X-User-Id").is_none());
+    }
+}
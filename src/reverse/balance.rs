@@ -0,0 +1,321 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/01 10:12:31
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use webparse::Request;
+use wenmeng::Body;
+
+use crate::{Helper, ProxyError};
+
+lazy_static! {
+    static ref PEER_STATS: RwLock<HashMap<SocketAddr, PeerStat>> = RwLock::new(HashMap::new());
+}
+
+/// EWMA的衰减时间, 越大则历史数据的影响越久
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// 负载均衡的选择策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BalanceStrategy {
+    /// 按权重随机选择, 默认策略
+    #[default]
+    Random,
+    /// 平滑加权轮询
+    RoundRobin,
+    /// 最少活跃连接, 会结合权重计算
+    LeastConn,
+    /// 随机选择两个, 取EWMA延时及活跃连接综合较低的那个
+    P2cEwma,
+    /// 按hash_key计算的一致性哈希
+    Hash,
+}
+
+impl FromStr for BalanceStrategy {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "" | "random" | "weight" => Ok(BalanceStrategy::Random),
+            "round_robin" | "rr" => Ok(BalanceStrategy::RoundRobin),
+            "least_conn" => Ok(BalanceStrategy::LeastConn),
+            "p2c" | "ewma" | "p2c_ewma" => Ok(BalanceStrategy::P2cEwma),
+            "hash" | "consistent_hash" => Ok(BalanceStrategy::Hash),
+            _ => Err(ProxyError::Extension("unknow balance strategy")),
+        }
+    }
+}
+
+impl Display for BalanceStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BalanceStrategy::Random => f.write_str("random"),
+            BalanceStrategy::RoundRobin => f.write_str("round_robin"),
+            BalanceStrategy::LeastConn => f.write_str("least_conn"),
+            BalanceStrategy::P2cEwma => f.write_str("p2c_ewma"),
+            BalanceStrategy::Hash => f.write_str("hash"),
+        }
+    }
+}
+
+/// 选择地址时的参考来源, 用于生成一致性哈希的key
+#[derive(Clone, Copy)]
+pub enum BalanceKey<'a> {
+    /// 无参考来源, hash策略将退化成按权重随机
+    None,
+    /// http请求, key由hash_key模板格式化生成
+    Req(&'a Request<Body>),
+    /// 四层转发, key为客户端的IP
+    Ip(IpAddr),
+}
+
+impl<'a> BalanceKey<'a> {
+    pub fn build_key(&self, format: &str) -> Option<String> {
+        match self {
+            BalanceKey::None => None,
+            BalanceKey::Req(req) => Some(Helper::format_req(req, format)),
+            BalanceKey::Ip(ip) => Some(ip.to_string()),
+        }
+    }
+}
+
+/// 每个后端地址的运行时数据
+struct PeerStat {
+    /// 当前活跃的连接数
+    active: usize,
+    /// 响应时间的EWMA值, 单位毫秒
+    ewma: f64,
+    /// 最后一次更新EWMA的时间
+    last_update: Instant,
+}
+
+impl PeerStat {
+    pub fn new() -> Self {
+        Self {
+            active: 0,
+            ewma: 0f64,
+            last_update: Instant::now(),
+        }
+    }
+
+    pub fn observe(&mut self, cost: Duration) {
+        let now = Instant::now();
+        let cost = cost.as_secs_f64() * 1000f64;
+        if self.ewma == 0f64 {
+            self.ewma = cost;
+        } else {
+            let elapsed = now.duration_since(self.last_update).as_secs_f64();
+            let w = (-elapsed / EWMA_DECAY.as_secs_f64()).exp();
+            self.ewma = self.ewma * w + cost * (1f64 - w);
+        }
+        self.last_update = now;
+    }
+}
+
+/// 后端地址的活跃连接数及响应延时统计, 进程内共享
+pub struct PeerStats;
+
+impl PeerStats {
+    /// 当前地址的活跃连接数
+    pub fn active(addr: &SocketAddr) -> usize {
+        if let Ok(h) = PEER_STATS.read() {
+            h.get(addr).map(|s| s.active).unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
+    /// 当前地址的EWMA延时, 未有记录时为0
+    pub fn ewma(addr: &SocketAddr) -> f64 {
+        if let Ok(h) = PEER_STATS.read() {
+            h.get(addr).map(|s| s.ewma).unwrap_or(0f64)
+        } else {
+            0f64
+        }
+    }
+
+    /// 开始一次连接, 返回的PeerGuard释放时将活跃连接数-1
+    pub fn begin(addr: SocketAddr) -> PeerGuard {
+        if let Ok(mut h) = PEER_STATS.write() {
+            h.entry(addr).or_insert_with(PeerStat::new).active += 1;
+        }
+        PeerGuard {
+            addr,
+            start: Instant::now(),
+        }
+    }
+
+    fn end(addr: &SocketAddr) {
+        if let Ok(mut h) = PEER_STATS.write() {
+            if let Some(stat) = h.get_mut(addr) {
+                stat.active = stat.active.saturating_sub(1);
+            }
+        }
+    }
+
+    fn observe(addr: &SocketAddr, cost: Duration) {
+        if let Ok(mut h) = PEER_STATS.write() {
+            h.entry(*addr).or_insert_with(PeerStat::new).observe(cost);
+        }
+    }
+}
+
+/// 活跃连接的守卫, 生命周期内计为该地址的一个活跃连接
+pub struct PeerGuard {
+    addr: SocketAddr,
+    start: Instant,
+}
+
+impl PeerGuard {
+    /// 记录从开始到现在的耗时, 参与EWMA的计算
+    pub fn record_latency(&self) {
+        PeerStats::observe(&self.addr, Instant::now().duration_since(self.start));
+    }
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        PeerStats::end(&self.addr);
+    }
+}
+
+/// 平滑加权轮询, current为每个地址的当前权重, 返回选中的下标
+pub(crate) fn smooth_round_robin(current: &mut [i64], candidates: &[(usize, u16)]) -> usize {
+    let mut total = 0i64;
+    let mut best: Option<usize> = None;
+    for (idx, weight) in candidates {
+        let weight = *weight as i64;
+        current[*idx] += weight;
+        total += weight;
+        if best.is_none() || current[*idx] > current[best.unwrap()] {
+            best = Some(*idx);
+        }
+    }
+    let best = best.unwrap_or(0);
+    current[best] -= total;
+    best
+}
+
+/// FNV-1a 并做一次混淆, 保证不同进程及版本间结果一致
+pub(crate) fn hash_bytes(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// 一致性哈希环, 每个地址按权重生成虚拟节点
+#[derive(Debug, Default)]
+pub(crate) struct HashRing {
+    addrs: Vec<SocketAddr>,
+    nodes: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new(servers: &[(SocketAddr, u16)]) -> Self {
+        let mut nodes = vec![];
+        for (idx, (addr, weight)) in servers.iter().enumerate() {
+            for i in 0..(*weight).max(1) {
+                nodes.push((hash_bytes(format!("{}-{}", addr, i).as_bytes()), idx));
+            }
+        }
+        nodes.sort();
+        Self {
+            addrs: servers.iter().map(|(addr, _)| *addr).collect(),
+            nodes,
+        }
+    }
+
+    /// 地址列表是否与当前环一致, 不一致需重建
+    pub fn is_same(&self, addrs: &[SocketAddr]) -> bool {
+        self.addrs == addrs
+    }
+
+    /// 从key的位置顺时针查找第一个可用的地址, 均不可用则返回None
+    pub fn get<F: Fn(usize) -> bool>(&self, key: &str, usable: F) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let hash = hash_bytes(key.as_bytes());
+        let start = self.nodes.partition_point(|(h, _)| *h < hash);
+        for i in 0..self.nodes.len() {
+            let (_, idx) = self.nodes[(start + i) % self.nodes.len()];
+            if usable(idx) {
+                return Some(idx);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{smooth_round_robin, HashRing};
+
+    #[test]
+    fn do_test_round_robin() {
+        let mut current = vec![0i64; 3];
+        let candidates = vec![(0, 5), (1, 1), (2, 1)];
+        let mut picks = vec![];
+        for _ in 0..7 {
+            picks.push(smooth_round_robin(&mut current, &candidates));
+        }
+        assert_eq!(picks, vec![0, 0, 1, 0, 2, 0, 0]);
+
+        // 不可用的地址不在候选中, 不应被选择
+        let candidates = vec![(0, 1), (2, 1)];
+        for _ in 0..4 {
+            assert_ne!(smooth_round_robin(&mut current, &candidates), 1);
+        }
+    }
+
+    #[test]
+    fn do_test_hash_ring() {
+        let addrs: Vec<(SocketAddr, u16)> = vec![
+            ("127.0.0.1:8080".parse().unwrap(), 100),
+            ("127.0.0.1:8081".parse().unwrap(), 100),
+            ("127.0.0.1:8082".parse().unwrap(), 100),
+        ];
+        let ring = HashRing::new(&addrs);
+        let mut hits = [0; 3];
+        for i in 0..300 {
+            let key = format!("/path/{}", i);
+            let idx = ring.get(&key, |_| true).unwrap();
+            hits[idx] += 1;
+            assert_eq!(ring.get(&key, |_| true), Some(idx));
+            // 下线的地址被跳过, 其它key不受影响
+            let skip = ring.get(&key, |i| i != 1).unwrap();
+            assert_ne!(skip, 1);
+            if idx != 1 {
+                assert_eq!(skip, idx);
+            }
+        }
+        assert!(hits.iter().all(|h| *h > 50));
+        assert_eq!(ring.get("key", |_| false), None);
+    }
+}
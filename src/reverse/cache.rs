@@ -0,0 +1,1194 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/09 09:40:18
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use webparse::{Binary, BinaryMut, Buf, HeaderName, Method, Request, Response, Url};
+use wenmeng::{Body, Consts, ProtResult};
+
+use crate::{BodyPump, ConfigDuration, ConfigSize, Helper, ProxyError};
+
+use super::LocationConfig;
+
+lazy_static! {
+    static ref CACHE_ZONES: Mutex<HashMap<String, Arc<Mutex<CacheStore>>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// 临时文件的序号, 同一个key同时写入时互不覆盖
+static TEMP_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// 可缓存的状态码
+const CACHEABLE_STATUS: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// 缓存区域, 格式如 "memory=10m path=/tmp/wmproxy max_size=1g max_object=8m inactive=10min"
+/// 内存为第一层, 配置了path时以磁盘为第二层, 均按LRU淘汰
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheZone {
+    /// 磁盘缓存的目录, 为空则只缓存在内存中
+    pub path: Option<String>,
+    /// 内存缓存的最大字节数
+    pub memory: u64,
+    /// 磁盘缓存的最大字节数
+    pub max_size: u64,
+    /// 单个响应的最大字节数, 超出则不缓存
+    pub max_object: u64,
+    /// 超过该时长未被访问则淘汰
+    pub inactive: Duration,
+}
+
+impl Default for CacheZone {
+    fn default() -> Self {
+        Self {
+            path: None,
+            memory: 10 * 1024 * 1024,
+            max_size: 1024 * 1024 * 1024,
+            max_object: 8 * 1024 * 1024,
+            inactive: Duration::from_secs(600),
+        }
+    }
+}
+
+impl FromStr for CacheZone {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut zone = CacheZone::default();
+        for val in s.split_whitespace() {
+            let (key, value) = val
+                .split_once('=')
+                .ok_or(ProxyError::Extension("CacheZone的输入异常,无法正确解析"))?;
+            match key {
+                "path" => zone.path = Some(value.to_string()),
+                "memory" => zone.memory = ConfigSize::from_str(value)?.0,
+                "max_size" => zone.max_size = ConfigSize::from_str(value)?.0,
+                "max_object" => zone.max_object = ConfigSize::from_str(value)?.0,
+                "inactive" => zone.inactive = ConfigDuration::from_str(value)?.0,
+                _ => return Err(ProxyError::Extension("CacheZone的输入异常,无法正确解析")),
+            }
+        }
+        Ok(zone)
+    }
+}
+
+impl Display for CacheZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(path) = &self.path {
+            f.write_fmt(format_args!("path={} ", path))?;
+        }
+        f.write_fmt(format_args!(
+            "memory={} max_size={} max_object={} inactive={}",
+            ConfigSize::new(self.memory),
+            ConfigSize::new(self.max_size),
+            ConfigSize::new(self.max_object),
+            ConfigDuration::new(self.inactive)
+        ))
+    }
+}
+
+/// location中的缓存配置, 格式如 "zone key={host}{url} valid=10min stale_while_revalidate=10s stale_if_error=1min"
+/// key按format_req格式化, valid为上游未指定缓存时长时的默认时长
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCache {
+    pub zone: String,
+    pub key: String,
+    pub valid: Option<Duration>,
+    pub stale_while_revalidate: Duration,
+    pub stale_if_error: Duration,
+}
+
+impl FromStr for ProxyCache {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = s.split_whitespace().collect::<Vec<&str>>();
+        if vals.is_empty() {
+            return Err(ProxyError::Extension("ProxyCache的输入异常,无法正确解析"));
+        }
+        let mut cache = ProxyCache {
+            zone: vals[0].to_string(),
+            key: "{host}{url}".to_string(),
+            valid: None,
+            stale_while_revalidate: Duration::ZERO,
+            stale_if_error: Duration::ZERO,
+        };
+        for val in &vals[1..] {
+            let (key, value) = val
+                .split_once('=')
+                .ok_or(ProxyError::Extension("ProxyCache的输入异常,无法正确解析"))?;
+            match key {
+                "key" => cache.key = value.to_string(),
+                "valid" => cache.valid = Some(ConfigDuration::from_str(value)?.0),
+                "stale_while_revalidate" => {
+                    cache.stale_while_revalidate = ConfigDuration::from_str(value)?.0
+                }
+                "stale_if_error" => cache.stale_if_error = ConfigDuration::from_str(value)?.0,
+                _ => return Err(ProxyError::Extension("ProxyCache的输入异常,无法正确解析")),
+            }
+        }
+        Ok(cache)
+    }
+}
+
+impl Display for ProxyCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{} key={}", self.zone, self.key))?;
+        if let Some(valid) = &self.valid {
+            f.write_fmt(format_args!(" valid={}", ConfigDuration::new(*valid)))?;
+        }
+        f.write_fmt(format_args!(
+            " stale_while_revalidate={} stale_if_error={}",
+            ConfigDuration::new(self.stale_while_revalidate),
+            ConfigDuration::new(self.stale_if_error)
+        ))
+    }
+}
+
+/// 缓存命中的状态, 写入X-Cache及日志的{cache_status}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    Expired,
+    Stale,
+    Updating,
+    Revalidated,
+    Bypass,
+}
+
+impl Display for CacheStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Expired => "EXPIRED",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Updating => "UPDATING",
+            CacheStatus::Revalidated => "REVALIDATED",
+            CacheStatus::Bypass => "BYPASS",
+        })
+    }
+}
+
+/// 缓存的响应信息, 时间均为unix毫秒
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheMeta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    /// Vary指定的请求头及存储时的值
+    vary: Vec<(String, String)>,
+    date: u64,
+    fresh_until: u64,
+    stale_while_revalidate: u64,
+    stale_if_error: u64,
+}
+
+impl CacheMeta {
+    fn is_fresh(&self, now: u64) -> bool {
+        now < self.fresh_until
+    }
+
+    fn in_stale_while_revalidate(&self, now: u64) -> bool {
+        now < self.fresh_until + self.stale_while_revalidate
+    }
+
+    fn in_stale_if_error(&self, now: u64) -> bool {
+        now < self.fresh_until + self.stale_if_error
+    }
+
+    fn get_header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    /// 按Vary指定的请求头区分的key, 不同的值分别缓存
+    fn variant_key(&self, req: Option<&Request<Body>>) -> String {
+        let mut key = self.key.clone();
+        for (name, value) in &self.vary {
+            let value = match req {
+                Some(req) => req.headers().get_str_value(name).unwrap_or_default(),
+                None => value.clone(),
+            };
+            key.push_str(&format!("\n{}:{}", name, value));
+        }
+        key
+    }
+
+    fn match_vary(&self, req: &Request<Body>) -> bool {
+        self.vary.iter().all(|(name, value)| {
+            &req.headers().get_str_value(name).unwrap_or_default() == value
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    meta: Arc<CacheMeta>,
+    body: Binary,
+}
+
+impl CacheEntry {
+    fn size(&self) -> u64 {
+        self.body.remaining() as u64
+            + self
+                .meta
+                .headers
+                .iter()
+                .map(|(k, v)| (k.len() + v.len()) as u64)
+                .sum::<u64>()
+    }
+}
+
+/// 单层缓存的LRU记录
+#[derive(Debug, Default)]
+struct LruList {
+    /// 文件名对应的大小, 访问序号及最后访问时间
+    items: HashMap<String, (u64, u64, u64)>,
+    order: BTreeMap<u64, String>,
+    size: u64,
+}
+
+impl LruList {
+    fn touch(&mut self, name: &str, tick: u64, now: u64) {
+        if let Some(item) = self.items.get_mut(name) {
+            self.order.remove(&item.1);
+            item.1 = tick;
+            item.2 = now;
+            self.order.insert(tick, name.to_string());
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64, tick: u64, now: u64) {
+        self.remove(&name);
+        self.size += size;
+        self.order.insert(tick, name.clone());
+        self.items.insert(name, (size, tick, now));
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        if let Some((size, tick, _)) = self.items.remove(name) {
+            self.order.remove(&tick);
+            self.size -= size;
+            return true;
+        }
+        false
+    }
+
+    /// 淘汰过久未访问的及超出容量的, 返回被淘汰的名字
+    fn evict(&mut self, max_size: u64, inactive: u64, now: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while let Some((_, name)) = self.order.first_key_value() {
+            let name = name.clone();
+            let used = self.items[&name].2;
+            if self.size <= max_size && used + inactive >= now {
+                break;
+            }
+            self.remove(&name);
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+/// 缓存区域的数据
+#[derive(Debug)]
+struct CacheStore {
+    zone: CacheZone,
+    memory: HashMap<String, CacheEntry>,
+    memory_lru: LruList,
+    disk_lru: LruList,
+    tick: u64,
+    /// 正在后台更新的缓存
+    updating: HashSet<String>,
+}
+
+impl CacheStore {
+    fn new(zone: CacheZone) -> Self {
+        let mut store = Self {
+            zone,
+            memory: HashMap::new(),
+            memory_lru: LruList::default(),
+            disk_lru: LruList::default(),
+            tick: 0,
+            updating: HashSet::new(),
+        };
+        store.load_disk();
+        store
+    }
+
+    /// 启动时按修改时间加载磁盘中已有的缓存
+    fn load_disk(&mut self) {
+        let path = match &self.zone.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        if let Err(e) = std::fs::create_dir_all(&path) {
+            log::warn!("创建缓存目录{}失败:{:?}", path, e);
+            return;
+        }
+        let mut files = vec![];
+        if let Ok(dir) = std::fs::read_dir(&path) {
+            for entry in dir.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if let (Some(name), Ok(meta)) = (name.strip_suffix(".cache"), entry.metadata()) {
+                    let modified = meta
+                        .modified()
+                        .ok()
+                        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+                        .map(|d| d.as_millis() as u64)
+                        .unwrap_or(0);
+                    files.push((modified, name.to_string(), meta.len()));
+                }
+            }
+        }
+        files.sort();
+        for (modified, name, size) in files {
+            self.tick += 1;
+            self.disk_lru.insert(name, size, self.tick, modified);
+        }
+    }
+
+    fn file_path(&self, name: &str) -> Option<PathBuf> {
+        self.zone
+            .path
+            .as_ref()
+            .map(|p| PathBuf::from(p).join(format!("{}.cache", name)))
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn put_memory(&mut self, name: String, entry: CacheEntry, now: u64) {
+        let size = entry.size();
+        if size > self.zone.memory {
+            return;
+        }
+        let tick = self.next_tick();
+        self.memory_lru.insert(name.clone(), size, tick, now);
+        self.memory.insert(name, entry);
+        let inactive = self.zone.inactive.as_millis() as u64;
+        for name in self.memory_lru.evict(self.zone.memory, inactive, now) {
+            self.memory.remove(&name);
+        }
+    }
+
+    /// 记录写入磁盘的文件, 返回需要删除的文件
+    fn put_disk(&mut self, name: String, size: u64, now: u64) -> Vec<PathBuf> {
+        let tick = self.next_tick();
+        self.disk_lru.insert(name, size, tick, now);
+        let inactive = self.zone.inactive.as_millis() as u64;
+        self.disk_lru
+            .evict(self.zone.max_size, inactive, now)
+            .iter()
+            .filter_map(|name| self.file_path(name))
+            .collect()
+    }
+}
+
+/// 缓存的存取
+pub struct CacheData;
+
+impl CacheData {
+    /// 加载配置时注册缓存区域, 配置未变化的保留已有的缓存
+    pub fn register(name: String, zone: CacheZone) {
+        if let Ok(mut zones) = CACHE_ZONES.lock() {
+            if let Some(store) = zones.get(&name) {
+                if store.lock().unwrap().zone == zone {
+                    return;
+                }
+            }
+            zones.insert(name, Arc::new(Mutex::new(CacheStore::new(zone))));
+        }
+    }
+
+    fn get_store(zone: &str) -> Option<Arc<Mutex<CacheStore>>> {
+        CACHE_ZONES.lock().ok()?.get(zone).cloned()
+    }
+
+    /// 以FNV-1a计算文件名, 保证重启后一致
+    fn file_name(key: &str) -> String {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for b in key.as_bytes() {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        format!("{:016x}", hash)
+    }
+
+    fn encode_file(entry: &CacheEntry) -> Option<Vec<u8>> {
+        let mut data = serde_json::to_vec(&*entry.meta).ok()?;
+        data.push(b'\n');
+        data.extend_from_slice(entry.body.chunk());
+        Some(data)
+    }
+
+    fn decode_file(data: Vec<u8>) -> Option<CacheEntry> {
+        let pos = data.iter().position(|b| *b == b'\n')?;
+        let meta: CacheMeta = serde_json::from_slice(&data[..pos]).ok()?;
+        Some(CacheEntry {
+            meta: Arc::new(meta),
+            body: Binary::from(data[pos + 1..].to_vec()),
+        })
+    }
+
+    /// 查找缓存, 带Vary的响应先找到最近存储的一份, 不匹配时再按请求头的值查找对应的一份
+    async fn lookup(zone: &str, key: &str, req: &Request<Body>) -> Option<CacheEntry> {
+        let entry = Self::lookup_name(zone, &Self::file_name(key), key).await?;
+        if entry.meta.match_vary(req) {
+            return Some(entry);
+        }
+        if entry.meta.vary.is_empty() {
+            return None;
+        }
+        let name = Self::file_name(&entry.meta.variant_key(Some(req)));
+        Self::lookup_name(zone, &name, key)
+            .await
+            .filter(|e| e.meta.match_vary(req))
+    }
+
+    async fn lookup_name(zone: &str, name: &str, key: &str) -> Option<CacheEntry> {
+        let store = Self::get_store(zone)?;
+        let now = now_millis();
+        let path = {
+            let mut store = store.lock().unwrap();
+            let tick = store.next_tick();
+            if let Some(entry) = store.memory.get(name).cloned() {
+                store.memory_lru.touch(name, tick, now);
+                store.disk_lru.touch(name, tick, now);
+                return Some(entry).filter(|e| e.meta.key == key);
+            }
+            if !store.disk_lru.items.contains_key(name) {
+                return None;
+            }
+            store.disk_lru.touch(name, tick, now);
+            store.file_path(name)?
+        };
+        let entry = Self::decode_file(tokio::fs::read(&path).await.ok()?)?;
+        if entry.meta.key != key {
+            return None;
+        }
+        store
+            .lock()
+            .unwrap()
+            .put_memory(name.to_string(), entry.clone(), now);
+        Some(entry)
+    }
+
+    /// 存储缓存, 带Vary的响应同时按请求头的值另存一份
+    async fn store(zone: &str, entry: CacheEntry) {
+        let store = match Self::get_store(zone) {
+            Some(store) => store,
+            None => return,
+        };
+        Self::store_name(&store, Self::file_name(&entry.meta.key), &entry).await;
+        if !entry.meta.vary.is_empty() {
+            let name = Self::file_name(&entry.meta.variant_key(None));
+            Self::store_name(&store, name, &entry).await;
+        }
+    }
+
+    async fn store_name(store: &Mutex<CacheStore>, name: String, entry: &CacheEntry) {
+        let now = now_millis();
+        let path = {
+            let mut store = store.lock().unwrap();
+            store.put_memory(name.clone(), entry.clone(), now);
+            store.file_path(&name)
+        };
+        let (path, data) = match (path, Self::encode_file(entry)) {
+            (Some(path), Some(data)) => (path, data),
+            _ => return,
+        };
+        // 先写临时文件再改名, 避免读到写了一半的文件
+        let seq = TEMP_SEQ.fetch_add(1, Ordering::Relaxed);
+        let temp = path.with_extension(format!("{}.{}.tmp", std::process::id(), seq));
+        if let Err(e) = tokio::fs::write(&temp, &data).await {
+            log::warn!("写入缓存文件{:?}失败:{:?}", temp, e);
+            return;
+        }
+        if tokio::fs::rename(&temp, &path).await.is_err() {
+            return;
+        }
+        let removes = store.lock().unwrap().put_disk(name, data.len() as u64, now);
+        for path in removes {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    fn try_begin_update(zone: &str, key: &str) -> bool {
+        match Self::get_store(zone) {
+            Some(store) => store.lock().unwrap().updating.insert(key.to_string()),
+            None => false,
+        }
+    }
+
+    fn end_update(zone: &str, key: &str) {
+        if let Some(store) = Self::get_store(zone) {
+            store.lock().unwrap().updating.remove(key);
+        }
+    }
+}
+
+/// 解析Cache-Control, 返回指令及参数
+fn parse_cache_control(value: &str) -> HashMap<String, Option<String>> {
+    let mut map = HashMap::new();
+    for item in value.split(',') {
+        let item = item.trim();
+        if item.is_empty() {
+            continue;
+        }
+        match item.split_once('=') {
+            Some((k, v)) => map.insert(
+                k.trim().to_lowercase(),
+                Some(v.trim().trim_matches('"').to_string()),
+            ),
+            None => map.insert(item.to_lowercase(), None),
+        };
+    }
+    map
+}
+
+fn parse_http_date(value: &str) -> Option<u64> {
+    chrono::DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|d| d.timestamp_millis().max(0) as u64)
+}
+
+impl ProxyCache {
+    fn get_seconds(control: &HashMap<String, Option<String>>, key: &str) -> Option<u64> {
+        control.get(key)?.as_ref()?.parse::<u64>().ok()
+    }
+
+    /// 根据响应生成缓存信息, 不可缓存时返回None
+    fn build_meta(
+        &self,
+        key: String,
+        req: &Request<Body>,
+        res: &Response<Body>,
+        now: u64,
+    ) -> Option<CacheMeta> {
+        if !CACHEABLE_STATUS.contains(&res.status().as_u16())
+            || res.headers().contains(&HeaderName::SET_COOKIE)
+        {
+            return None;
+        }
+        let control = res
+            .headers()
+            .get_str_value(&HeaderName::CACHE_CONTROL)
+            .map(|v| parse_cache_control(&v))
+            .unwrap_or_default();
+        if control.contains_key("no-store") || control.contains_key("private") {
+            return None;
+        }
+        let ttl = if control.contains_key("no-cache") {
+            Some(0)
+        } else if let Some(s) = Self::get_seconds(&control, "s-maxage")
+            .or(Self::get_seconds(&control, "max-age"))
+        {
+            Some(s * 1000)
+        } else if let Some(expires) = res.headers().get_str_value(&HeaderName::EXPIRES) {
+            let date = res
+                .headers()
+                .get_str_value(&HeaderName::DATE)
+                .and_then(|d| parse_http_date(&d))
+                .unwrap_or(now);
+            // 无法解析的Expires视为已过期
+            Some(parse_http_date(&expires).unwrap_or(0).saturating_sub(date))
+        } else {
+            self.valid.map(|v| v.as_millis() as u64)
+        }?;
+        // 带认证的请求只有明确允许共享时才缓存
+        if req.headers().contains(&HeaderName::AUTHORIZATION)
+            && !control.contains_key("public")
+            && !control.contains_key("s-maxage")
+        {
+            return None;
+        }
+        let mut vary = vec![];
+        if let Some(value) = res.headers().get_str_value(&HeaderName::VARY) {
+            for name in value.split(',').map(|v| v.trim().to_lowercase()) {
+                if name == "*" {
+                    return None;
+                }
+                if !name.is_empty() {
+                    let value = req.headers().get_str_value(&name).unwrap_or_default();
+                    vary.push((name, value));
+                }
+            }
+        }
+
+        let mut headers = vec![];
+        for (name, value) in res.headers().iter() {
+            let name = name.to_string();
+            // 逐跳的头及由缓存重新生成的头不保存
+            if ["connection", "keep-alive", "transfer-encoding", "content-length", "age", "x-cache"]
+                .contains(&&*name.to_lowercase())
+            {
+                continue;
+            }
+            headers.push((name, String::from_utf8_lossy(value.as_bytes()).to_string()));
+        }
+        let stale_while_revalidate = Self::get_seconds(&control, "stale-while-revalidate")
+            .map(|s| s * 1000)
+            .unwrap_or(self.stale_while_revalidate.as_millis() as u64);
+        let stale_if_error = Self::get_seconds(&control, "stale-if-error")
+            .map(|s| s * 1000)
+            .unwrap_or(self.stale_if_error.as_millis() as u64);
+        Some(CacheMeta {
+            key,
+            status: res.status().as_u16(),
+            headers,
+            vary,
+            date: now,
+            fresh_until: now + ttl,
+            stale_while_revalidate,
+            stale_if_error,
+        })
+    }
+
+    /// 尝试缓存上游的响应, 边读取边判断大小, 读取完整的body后重新生成响应
+    async fn try_store(
+        &self,
+        key: &str,
+        req: &Request<Body>,
+        mut res: Response<Body>,
+    ) -> Response<Body> {
+        let now = now_millis();
+        let meta = match self.build_meta(key.to_string(), req, &res, now) {
+            Some(meta) if req.method() == &Method::Get => meta,
+            _ => return res,
+        };
+        let max_object = match Self::get_store(&self.zone) {
+            Some(store) => store.lock().unwrap().zone.max_object,
+            None => return res,
+        };
+        let body_len = res.headers().get_body_len();
+        if body_len > 0 && body_len as u64 > max_object {
+            return res;
+        }
+        // 先按原始数据读取, 超出限制时可原样返回给客户端
+        let method = res.body().get_origin_compress();
+        res.body_mut().add_compress_method(method);
+        let mut data = BinaryMut::new();
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let size = match Helper::read_body(res.body_mut(), &mut buf).await {
+                Ok(size) => size,
+                Err(_) => return Self::stream_uncached(res, data),
+            };
+            if size == 0 {
+                break;
+            }
+            data.put_slice(&buf[..size]);
+            if data.remaining() as u64 > max_object {
+                return Self::stream_uncached(res, data);
+            }
+        }
+        let mut meta = meta;
+        // 缓存解压后的数据
+        if method != Consts::COMPRESS_METHOD_NONE {
+            let mut body = Body::only(data.freeze());
+            body.set_origin_compress_method(method);
+            data = BinaryMut::new();
+            let _ = body.read_all(&mut data).await;
+            meta.headers
+                .retain(|(k, _)| !k.eq_ignore_ascii_case("content-encoding"));
+        }
+        let entry = CacheEntry {
+            meta: Arc::new(meta),
+            body: data.freeze(),
+        };
+        if entry.body.remaining() as u64 <= max_object {
+            CacheData::store(&self.zone, entry.clone()).await;
+        }
+        Self::build_response(&entry, req, CacheStatus::Miss, now)
+    }
+
+    /// 超出max_object时不再缓存, 已读取的数据及剩余的数据原样返回给客户端
+    fn stream_uncached(mut res: Response<Body>, data: BinaryMut) -> Response<Body> {
+        Helper::pump_body(res.body_mut(), data, None, |data, _| {
+            BodyPump::Send(Binary::from(data.to_vec()))
+        });
+        res
+    }
+
+    fn get_store(zone: &str) -> Option<Arc<Mutex<CacheStore>>> {
+        CacheData::get_store(zone)
+    }
+
+    /// 由缓存生成响应, 客户端的协商缓存匹配时返回304
+    fn build_response(
+        entry: &CacheEntry,
+        req: &Request<Body>,
+        status: CacheStatus,
+        now: u64,
+    ) -> Response<Body> {
+        let meta = &entry.meta;
+        // 带If-None-Match时忽略If-Modified-Since
+        let not_modified = match req.headers().get_str_value(&HeaderName::IF_NONE_MATCH) {
+            Some(v) => match meta.get_header("etag") {
+                Some(etag) => v == "*" || v.split(',').any(|v| v.trim() == etag),
+                None => false,
+            },
+            None => match (
+                req.headers()
+                    .get_str_value(&HeaderName::IF_MODIFIED_SINCE)
+                    .and_then(|v| parse_http_date(&v)),
+                meta.get_header("last-modified").and_then(|v| parse_http_date(v)),
+            ) {
+                (Some(since), Some(last)) => last <= since,
+                _ => false,
+            },
+        };
+        let mut builder = Response::builder().status(if not_modified { 304 } else { meta.status });
+        for (k, v) in &meta.headers {
+            builder = builder.header(k.clone(), v.clone());
+        }
+        let body = if not_modified || req.method() == &Method::Head {
+            Body::empty()
+        } else {
+            Body::only(entry.body.clone())
+        };
+        let mut res = builder.body(body).unwrap();
+        res.headers_mut()
+            .insert("Age", (now.saturating_sub(meta.date) / 1000).to_string());
+        res.headers_mut().insert("X-Cache", status.to_string());
+        res
+    }
+
+    /// 用304的响应刷新缓存的时长及头信息
+    fn refresh_entry(&self, entry: &CacheEntry, req: &Request<Body>, res: &Response<Body>) -> CacheEntry {
+        let now = now_millis();
+        let mut merged = Response::builder().status(entry.meta.status);
+        for (k, v) in &entry.meta.headers {
+            if !res.headers().contains(k) {
+                merged = merged.header(k.clone(), v.clone());
+            }
+        }
+        for (k, v) in res.headers().iter() {
+            merged = merged.header(k.to_string(), String::from_utf8_lossy(v.as_bytes()).to_string());
+        }
+        let merged: Response<Body> = merged.body(()).unwrap().into_type();
+        let meta = self
+            .build_meta(entry.meta.key.clone(), req, &merged, now)
+            .unwrap_or_else(|| {
+                let mut meta = (*entry.meta).clone();
+                meta.date = now;
+                meta.fresh_until = now;
+                meta
+            });
+        CacheEntry {
+            meta: Arc::new(meta),
+            body: entry.body.clone(),
+        }
+    }
+
+    /// 增加协商缓存的请求头向上游确认缓存是否可用
+    fn add_conditional(req: &mut Request<Body>, entry: &CacheEntry) {
+        if let Some(etag) = entry.meta.get_header("etag") {
+            req.headers_mut()
+                .insert(HeaderName::IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last) = entry.meta.get_header("last-modified") {
+            req.headers_mut()
+                .insert(HeaderName::IF_MODIFIED_SINCE, last.clone());
+        }
+    }
+
+    fn set_status(req: &mut Request<Body>, res: &mut Response<Body>, status: CacheStatus) {
+        req.headers_mut()
+            .system_insert("{cache_status}".to_string(), status.to_string());
+        res.headers_mut().insert("X-Cache", status.to_string());
+    }
+
+    /// 后台向上游更新缓存
+    fn spawn_update(&self, location: &LocationConfig, req: &mut Request<Body>, url: &Url, key: String, entry: CacheEntry) {
+        if !CacheData::try_begin_update(&self.zone, &key) {
+            return;
+        }
+        let cache = self.clone();
+        let location = location.clone();
+        let url = url.clone();
+        let mut req = req.replace_clone(Body::empty());
+        tokio::spawn(async move {
+            Self::add_conditional(&mut req, &entry);
+            match location.deal_reverse_proxy(&mut req, &url).await {
+                Ok(res) if res.status() == 304 => {
+                    let entry = cache.refresh_entry(&entry, &req, &res);
+                    CacheData::store(&cache.zone, entry).await;
+                }
+                Ok(res) => {
+                    let mut res = cache.try_store(&key, &req, res).await;
+                    res.body_mut().wait_all().await;
+                }
+                Err(e) => log::warn!("后台更新缓存{}失败:{:?}", key, e),
+            }
+            CacheData::end_update(&cache.zone, &key);
+        });
+    }
+
+    /// 带缓存的反向代理
+    pub async fn deal_request(
+        &self,
+        location: &LocationConfig,
+        req: &mut Request<Body>,
+        url: &Url,
+    ) -> ProtResult<Response<Body>> {
+        if req.method() != &Method::Get && req.method() != &Method::Head {
+            let mut res = location.deal_reverse_proxy(req, url).await?;
+            Self::set_status(req, &mut res, CacheStatus::Bypass);
+            return Ok(res);
+        }
+        let key = Helper::format_req(req, &self.key);
+        let entry = CacheData::lookup(&self.zone, &key, req).await;
+        let now = now_millis();
+        let entry = match entry {
+            Some(entry) if entry.meta.is_fresh(now) => {
+                let mut res = Self::build_response(&entry, req, CacheStatus::Hit, now);
+                Self::set_status(req, &mut res, CacheStatus::Hit);
+                return Ok(res);
+            }
+            Some(entry) if entry.meta.in_stale_while_revalidate(now) => {
+                let mut res = Self::build_response(&entry, req, CacheStatus::Updating, now);
+                Self::set_status(req, &mut res, CacheStatus::Updating);
+                self.spawn_update(location, req, url, key, entry);
+                return Ok(res);
+            }
+            entry => entry,
+        };
+
+        let entry = match entry {
+            Some(entry) => entry,
+            None => {
+                let res = location.deal_reverse_proxy(req, url).await?;
+                let status = if req.method() == &Method::Get {
+                    CacheStatus::Miss
+                } else {
+                    CacheStatus::Bypass
+                };
+                let mut res = self.try_store(&key, req, res).await;
+                Self::set_status(req, &mut res, status);
+                return Ok(res);
+            }
+        };
+
+        // 已过期, 向上游确认, 上游出错时在stale_if_error内返回旧的缓存
+        let mut upstream_req = req.replace_clone(Body::empty());
+        Self::add_conditional(&mut upstream_req, &entry);
+        let result = location.deal_reverse_proxy(&mut upstream_req, url).await;
+        let now = now_millis();
+        match result {
+            Ok(res) if res.status() == 304 => {
+                let entry = self.refresh_entry(&entry, req, &res);
+                CacheData::store(&self.zone, entry.clone()).await;
+                let mut res = Self::build_response(&entry, req, CacheStatus::Revalidated, now);
+                Self::set_status(req, &mut res, CacheStatus::Revalidated);
+                Ok(res)
+            }
+            Ok(res) if res.status().is_server_error() && entry.meta.in_stale_if_error(now) => {
+                let mut res = Self::build_response(&entry, req, CacheStatus::Stale, now);
+                Self::set_status(req, &mut res, CacheStatus::Stale);
+                Ok(res)
+            }
+            Ok(res) => {
+                let mut res = self.try_store(&key, req, res).await;
+                Self::set_status(req, &mut res, CacheStatus::Expired);
+                Ok(res)
+            }
+            Err(_) if entry.meta.in_stale_if_error(now) => {
+                let mut res = Self::build_response(&entry, req, CacheStatus::Stale, now);
+                Self::set_status(req, &mut res, CacheStatus::Stale);
+                Ok(res)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use webparse::{Binary, BinaryMut, Buf, Request, Response, Url};
+    use wenmeng::Body;
+
+    use super::{now_millis, CacheData, CacheEntry, CacheStatus, CacheZone, ProxyCache};
+    use crate::{reverse::LocationConfig, test_util::fake_upstream};
+
+    #[test]
+    fn do_test_parse() {
+        let zone = CacheZone::from_str("memory=1m path=/tmp/wmproxy max_size=1g inactive=1h").unwrap();
+        assert_eq!(zone.memory, 1024 * 1024);
+        assert_eq!(zone.path, Some("/tmp/wmproxy".to_string()));
+        assert_eq!(zone.inactive, Duration::from_secs(3600));
+        assert_eq!(CacheZone::from_str(&zone.to_string()).unwrap(), zone);
+        assert!(CacheZone::from_str("memory").is_err());
+
+        let cache = ProxyCache::from_str("one key={host}{path} valid=10min stale_if_error=1min").unwrap();
+        assert_eq!(cache.zone, "one");
+        assert_eq!(cache.key, "{host}{path}");
+        assert_eq!(cache.valid, Some(Duration::from_secs(600)));
+        assert_eq!(cache.stale_while_revalidate, Duration::ZERO);
+        assert_eq!(ProxyCache::from_str(&cache.to_string()).unwrap(), cache);
+    }
+
+    #[test]
+    fn do_test_freshness() {
+        let cache = ProxyCache::from_str("fresh").unwrap();
+        let req = Request::builder().url("/").body(Body::empty()).unwrap();
+        let build = |control: &str| {
+            let res: Response<Body> = Response::builder()
+                .header("Cache-Control", control.to_string())
+                .header("Vary", "Accept-Encoding")
+                .body(())
+                .unwrap()
+                .into_type();
+            cache.build_meta("k".to_string(), &req, &res, 0)
+        };
+        let meta = build("max-age=60, stale-while-revalidate=5").unwrap();
+        assert_eq!(meta.fresh_until, 60_000);
+        assert_eq!(meta.stale_while_revalidate, 5_000);
+        assert_eq!(meta.vary, vec![("accept-encoding".to_string(), String::new())]);
+        assert_eq!(build("max-age=60, s-maxage=10").unwrap().fresh_until, 10_000);
+        assert_eq!(build("no-cache").unwrap().fresh_until, 0);
+        assert!(build("no-store").is_none());
+        assert!(build("private, max-age=60").is_none());
+
+        let res: Response<Body> = Response::builder()
+            .header("Date", "Sat, 10 Feb 2024 10:00:00 GMT")
+            .header("Expires", "Sat, 10 Feb 2024 10:01:00 GMT")
+            .body(())
+            .unwrap()
+            .into_type();
+        assert_eq!(cache.build_meta("k".to_string(), &req, &res, 0).unwrap().fresh_until, 60_000);
+        // 无缓存信息且未配置valid时不缓存
+        let res: Response<Body> = Response::builder().body(()).unwrap().into_type();
+        assert!(cache.build_meta("k".to_string(), &req, &res, 0).is_none());
+    }
+
+    fn build_entry(cache: &ProxyCache, key: &str, body: &str) -> CacheEntry {
+        let req = Request::builder().url("/").body(Body::empty()).unwrap();
+        let res: Response<Body> = Response::builder()
+            .header("Cache-Control", "max-age=60")
+            .body(())
+            .unwrap()
+            .into_type();
+        CacheEntry {
+            meta: Arc::new(cache.build_meta(key.to_string(), &req, &res, now_millis()).unwrap()),
+            body: Binary::from(body.as_bytes().to_vec()),
+        }
+    }
+
+    #[tokio::test]
+    async fn do_test_lru() {
+        let path = std::env::temp_dir().join(format!("wmproxy_cache_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let zone = CacheZone::from_str(&format!(
+            "memory=2500 max_size=3800 path={}",
+            path.to_string_lossy()
+        ))
+        .unwrap();
+        CacheData::register("lru".to_string(), zone);
+        let cache = ProxyCache::from_str("lru").unwrap();
+        let body = "a".repeat(1000);
+        for key in ["k1", "k2", "k3"] {
+            CacheData::store("lru", build_entry(&cache, key, &body)).await;
+        }
+        {
+            let store = CacheData::get_store("lru").unwrap();
+            let store = store.lock().unwrap();
+            // 内存只能容纳两个, 最早的k1被淘汰
+            assert!(store.memory_lru.size <= 2500);
+            assert!(!store.memory.contains_key(&CacheData::file_name("k1")));
+            assert_eq!(store.disk_lru.items.len(), 3);
+        }
+        // 从磁盘读取并重新放入内存
+        let req = Request::builder().url("/").body(Body::empty()).unwrap();
+        let entry = CacheData::lookup("lru", "k1", &req).await.unwrap();
+        assert_eq!(entry.body.chunk(), body.as_bytes());
+        CacheData::store("lru", build_entry(&cache, "k4", &body)).await;
+        {
+            let store = CacheData::get_store("lru").unwrap();
+            let store = store.lock().unwrap();
+            // 磁盘超出容量, 最久未访问的k2被淘汰
+            assert!(!store.disk_lru.items.contains_key(&CacheData::file_name("k2")));
+            assert!(store.disk_lru.items.contains_key(&CacheData::file_name("k1")));
+        }
+        assert!(CacheData::lookup("lru", "k2", &req).await.is_none());
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn do_test_if_modified_since() {
+        let cache = ProxyCache::from_str("ims").unwrap();
+        let req = Request::builder().url("/").body(Body::empty()).unwrap();
+        let res: Response<Body> = Response::builder()
+            .header("Cache-Control", "max-age=60")
+            .header("Last-Modified", "Sat, 10 Feb 2024 10:00:00 GMT")
+            .body(())
+            .unwrap()
+            .into_type();
+        let entry = CacheEntry {
+            meta: Arc::new(cache.build_meta("k".to_string(), &req, &res, 0).unwrap()),
+            body: Binary::from("ok"),
+        };
+        let status = |since: &str, etag: Option<&str>| {
+            let mut builder = Request::builder().url("/").header("If-Modified-Since", since.to_string());
+            if let Some(etag) = etag {
+                builder = builder.header("If-None-Match", etag.to_string());
+            }
+            let req = builder.body(Body::empty()).unwrap();
+            ProxyCache::build_response(&entry, &req, CacheStatus::Hit, 0).status().as_u16()
+        };
+        assert_eq!(status("Sat, 10 Feb 2024 10:00:00 GMT", None), 304);
+        assert_eq!(status("Sat, 10 Feb 2024 11:00:00 GMT", None), 304);
+        assert_eq!(status("Sat, 10 Feb 2024 09:00:00 GMT", None), 200);
+        assert_eq!(status("invalid", None), 200);
+        // If-None-Match优先
+        assert_eq!(status("Sat, 10 Feb 2024 10:00:00 GMT", Some("\"v1\"")), 200);
+    }
+
+    #[tokio::test]
+    async fn do_test_vary() {
+        CacheData::register("vary".to_string(), CacheZone::from_str("memory=1m").unwrap());
+        let cache = ProxyCache::from_str("vary").unwrap();
+        let build = |encoding: &str| {
+            Request::builder()
+                .url("/")
+                .header("Accept-Encoding", encoding.to_string())
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res: Response<Body> = Response::builder()
+            .header("Cache-Control", "max-age=60")
+            .header("Vary", "Accept-Encoding")
+            .body(())
+            .unwrap()
+            .into_type();
+        for (encoding, body) in [("gzip", "g"), ("br", "b")] {
+            let meta = cache.build_meta("k".to_string(), &build(encoding), &res, now_millis()).unwrap();
+            let entry = CacheEntry {
+                meta: Arc::new(meta),
+                body: Binary::from(body.as_bytes().to_vec()),
+            };
+            CacheData::store("vary", entry).await;
+        }
+        // 后存储的不覆盖之前的变体
+        for (encoding, body) in [("gzip", "g"), ("br", "b")] {
+            let entry = CacheData::lookup("vary", "k", &build(encoding)).await.unwrap();
+            assert_eq!(entry.body.chunk(), body.as_bytes());
+        }
+        assert!(CacheData::lookup("vary", "k", &build("deflate")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn do_test_cache_proxy() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        let (addr, _) = fake_upstream(move |_| {
+            count.fetch_add(1, Ordering::SeqCst);
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nhello".to_vec()
+        })
+        .await;
+
+        CacheData::register("proxy".to_string(), CacheZone::default());
+        let mut location = LocationConfig::new();
+        location.comm.proxy_url = Some(Url::parse(format!("http://{}/", addr).into_bytes()).unwrap());
+        location.comm.proxy_cache = Some(ProxyCache::from_str("proxy").unwrap());
+
+        let build_req = || {
+            Request::builder()
+                .url(format!("http://{}/index", addr))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut req = build_req();
+        let mut res = location.deal_request(&mut req).await.unwrap();
+        assert_eq!(res.headers().get_str_value(&"X-Cache").unwrap(), "MISS");
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert_eq!(data.as_slice(), b"hello");
+
+        let mut req = build_req();
+        let mut res = location.deal_request(&mut req).await.unwrap();
+        assert_eq!(res.headers().get_str_value(&"X-Cache").unwrap(), "HIT");
+        assert_eq!(req.headers().system_get("{cache_status}").unwrap(), "HIT");
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert_eq!(data.as_slice(), b"hello");
+
+        let mut req = build_req();
+        req.headers_mut().insert("If-None-Match", "\"v1\"");
+        let res = location.deal_request(&mut req).await.unwrap();
+        assert_eq!(res.status(), 304);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn do_test_cache_max_object() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let count = requests.clone();
+        let (addr, _) = fake_upstream(move |_| {
+            count.fetch_add(1, Ordering::SeqCst);
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nTransfer-Encoding: chunked\r\n\r\n6\r\nhello \r\n6\r\nworld!\r\n0\r\n\r\n".to_vec()
+        })
+        .await;
+
+        CacheData::register("small".to_string(), CacheZone::from_str("max_object=8").unwrap());
+        let mut location = LocationConfig::new();
+        location.comm.proxy_url = Some(Url::parse(format!("http://{}/", addr).into_bytes()).unwrap());
+        location.comm.proxy_cache = Some(ProxyCache::from_str("small").unwrap());
+
+        // 未知长度的响应超出max_object时不缓存, 数据完整返回
+        for _ in 0..2 {
+            let mut req = Request::builder()
+                .url(format!("http://{}/large", addr))
+                .body(Body::empty())
+                .unwrap();
+            let mut res = location.deal_request(&mut req).await.unwrap();
+            let mut data = BinaryMut::new();
+            res.body_mut().read_all(&mut data).await;
+            assert_eq!(data.as_slice(), b"hello world!");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}
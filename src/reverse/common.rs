@@ -10,17 +10,20 @@
 // -----
 // Created Date: 2023/11/03 05:01:37
 
-use std::collections::HashMap;
+use std::{collections::HashMap, net::IpAddr};
 
-use crate::{ConfigDuration, ConfigLog, ConfigRate, IpSets};
+use crate::{ConfigDuration, ConfigLog, ConfigRate, ConfigSize, IpSets};
 use crate::{DisplayFromStrOrNumber};
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr};
-use webparse::Url;
-use wenmeng::RateLimitLayer;
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
+use webparse::{Request, Url};
+use wenmeng::{Body, RateLimitLayer};
 use wenmeng::TimeoutLayer;
 
-use super::{LimitReq, Matcher};
+use super::{
+    AuthBasic, AuthRequest, CompressConfig, CorsConfig, ErrorPage, JwtConfig, LimitReq, Matcher, NextUpstream,
+    ProxyCache, ProxyTlsConfig, UpstreamProtocol,
+};
 
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -47,6 +50,44 @@ pub struct CommonConfig {
     #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
     pub proxy_timeout: Option<ConfigDuration>,
 
+    /// 访问上游失败时切换下一个地址的条件
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub proxy_next_upstream: Option<NextUpstream>,
+    /// 最大的尝试次数, 0表示不限制
+    pub proxy_next_upstream_tries: Option<usize>,
+    /// 重试的总时长限制
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    pub proxy_next_upstream_timeout: Option<ConfigDuration>,
+    /// 以https或wss访问上游时的TLS配置
+    #[serde(default)]
+    pub proxy_tls: Option<ProxyTlsConfig>,
+    /// 访问上游的协议, 可为auto, http1, h2, h2c
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy_protocol: Option<UpstreamProtocol>,
+    /// 响应缓存, 格式如 "zone key={host}{url} valid=10min"
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub proxy_cache: Option<ProxyCache>,
+    /// 响应的动态压缩
+    #[serde(default)]
+    pub compress: Option<CompressConfig>,
+    /// 跨域的策略
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    /// 按状态码返回的错误页, 如 "404 500-599 /50x.html"
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub error_page: Vec<ErrorPage>,
+    /// 上游返回的错误状态是否也按error_page处理
+    pub proxy_intercept_errors: Option<bool>,
+    /// 请求体的最大长度, 超出时返回413, 0表示不限制, 开启proxy_request_buffering时不限制为1m
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub client_max_body_size: Option<ConfigSize>,
+    /// 是否读取完整的请求体后再发送到上游, 开启后带请求体的请求也可切换上游重试
+    pub proxy_request_buffering: Option<bool>,
+
     #[serde(default = "HashMap::new")]
     pub log_format: HashMap<String, String>,
     #[serde(default = "HashMap::new")]
@@ -61,13 +102,26 @@ pub struct CommonConfig {
     pub allow_ip: Option<IpSets>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub deny_ip: Option<IpSets>,
+    /// 信任的代理地址, 同nginx的set_real_ip_from, 在http或server中配置,
+    /// 连接地址在其中时按X-Forwarded-For或X-Real-IP取客户端的真实地址
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub trusted_proxies: Option<IpSets>,
+    /// Basic认证, 如 { realm = "Restricted", user_file = "config/htpasswd" }
+    #[serde(default)]
+    pub auth_basic: Option<AuthBasic>,
+    /// 子请求认证, 如 { url = "http://auth/verify", headers = ["X-User-Id"] }
+    #[serde(default)]
+    pub auth_request: Option<AuthRequest>,
+    /// JWT校验, server中配置的在匹配location前校验, 声明可用于location的匹配
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub domain: Option<String>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub proxy_url: Option<Url>,
     
     #[serde(default = "HashMap::new")]
-    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    #[serde_as(as = "HashMap<_, PickFirst<(_, DisplayFromStr)>>")]
     pub match_names: HashMap<String, Matcher>,
 }
 
@@ -87,6 +141,19 @@ impl CommonConfig {
             proxy_read_timeout: None,
             proxy_write_timeout: None,
 
+            proxy_next_upstream: None,
+            proxy_next_upstream_tries: None,
+            proxy_next_upstream_timeout: None,
+            proxy_tls: None,
+            proxy_protocol: None,
+            proxy_cache: None,
+            compress: None,
+            cors: None,
+            error_page: vec![],
+            proxy_intercept_errors: None,
+            client_max_body_size: None,
+            proxy_request_buffering: None,
+
             log_format: HashMap::new(),
             log_names: HashMap::new(),
 
@@ -96,6 +163,10 @@ impl CommonConfig {
             limit_req: None,
             allow_ip: None,
             deny_ip: None,
+            trusted_proxies: None,
+            auth_basic: None,
+            auth_request: None,
+            jwt: None,
 
             domain: None,
             proxy_url: None,
@@ -124,6 +195,42 @@ impl CommonConfig {
         if self.client_timeout.is_none() && parent.client_timeout.is_some() {
             self.client_timeout = parent.client_timeout.clone();
         }
+        if self.proxy_next_upstream.is_none() {
+            self.proxy_next_upstream = parent.proxy_next_upstream.clone();
+        }
+        if self.proxy_next_upstream_tries.is_none() {
+            self.proxy_next_upstream_tries = parent.proxy_next_upstream_tries;
+        }
+        if self.proxy_next_upstream_timeout.is_none() {
+            self.proxy_next_upstream_timeout = parent.proxy_next_upstream_timeout.clone();
+        }
+        if self.proxy_tls.is_none() {
+            self.proxy_tls = parent.proxy_tls.clone();
+        }
+        if self.proxy_protocol.is_none() {
+            self.proxy_protocol = parent.proxy_protocol;
+        }
+        if self.proxy_cache.is_none() {
+            self.proxy_cache = parent.proxy_cache.clone();
+        }
+        if self.compress.is_none() {
+            self.compress = parent.compress.clone();
+        }
+        if self.cors.is_none() {
+            self.cors = parent.cors.clone();
+        }
+        if self.error_page.is_empty() {
+            self.error_page = parent.error_page.clone();
+        }
+        if self.proxy_intercept_errors.is_none() {
+            self.proxy_intercept_errors = parent.proxy_intercept_errors;
+        }
+        if self.client_max_body_size.is_none() {
+            self.client_max_body_size = parent.client_max_body_size.clone();
+        }
+        if self.proxy_request_buffering.is_none() {
+            self.proxy_request_buffering = parent.proxy_request_buffering;
+        }
         for h in &parent.log_names {
             self.log_names.insert(h.0.clone(), h.1.clone());
         }
@@ -147,6 +254,18 @@ impl CommonConfig {
         if self.deny_ip.is_none() {
             self.deny_ip = parent.deny_ip.clone();
         }
+        if self.trusted_proxies.is_none() {
+            self.trusted_proxies = parent.trusted_proxies.clone();
+        }
+        if self.auth_basic.is_none() {
+            self.auth_basic = parent.auth_basic.clone();
+        }
+        if self.auth_request.is_none() {
+            self.auth_request = parent.auth_request.clone();
+        }
+        if self.jwt.is_none() {
+            self.jwt = parent.jwt.clone();
+        }
         
         for p in &parent.match_names {
             if !self.match_names.contains_key(p.0) {
@@ -162,6 +281,45 @@ impl CommonConfig {
         
     }
 
+    /// 记录客户端的真实地址到{remote_ip}, 连接地址为信任的代理时取X-Forwarded-For中
+    /// 从右往左首个不信任的地址或X-Real-IP, 否则同{client_ip}
+    pub fn set_remote_ip(&self, req: &mut Request<Body>) {
+        if let Some(ip) = self.get_remote_ip(req) {
+            req.headers_mut()
+                .system_insert("{remote_ip}".to_string(), ip.to_string());
+        }
+    }
+
+    fn get_remote_ip(&self, req: &Request<Body>) -> Option<IpAddr> {
+        let client_ip = req.headers().system_get("{client_ip}")?.parse::<IpAddr>().ok()?;
+        let trusted = match &self.trusted_proxies {
+            Some(trusted) if trusted.contains(&client_ip) => trusted,
+            _ => return Some(client_ip),
+        };
+        if let Some(forward) = req.headers().get_str_value(&"X-Forwarded-For") {
+            // 左侧的地址可被客户端伪造, 只取到首个不信任的地址为止
+            let mut remote = client_ip;
+            for ip in forward.rsplit(',') {
+                match ip.trim().parse::<IpAddr>() {
+                    Ok(ip) => {
+                        remote = ip;
+                        if !trusted.contains(&ip) {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+            return Some(remote);
+        }
+        if let Some(ip) = req.headers().get_str_value(&"X-Real-IP") {
+            if let Ok(ip) = ip.trim().parse::<IpAddr>() {
+                return Some(ip);
+            }
+        }
+        Some(client_ip)
+    }
+
     pub fn get_rate_limit(&self) -> Option<RateLimitLayer> {
         if self.rate_limit.is_some() {
             return Some(RateLimitLayer::new(self.rate_limit.clone().unwrap().0));
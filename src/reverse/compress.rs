@@ -0,0 +1,410 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/10 14:21:06
+
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    str::FromStr,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use webparse::{Binary, BinaryMut, HeaderName, Method, Request, Response, Version};
+use wenmeng::{Body, Consts};
+
+use crate::{BodyPump, ConfigSize, DisplayFromStrOrNumber, Helper, ProxyError};
+
+/// 动态压缩的算法
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressMethod {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+impl CompressMethod {
+    /// 未配置压缩级别时的默认值
+    fn default_level(&self) -> u32 {
+        match self {
+            CompressMethod::Gzip => 6,
+            CompressMethod::Br => 4,
+            CompressMethod::Zstd => 3,
+        }
+    }
+
+    /// 将压缩级别限制在算法支持的范围内
+    fn fix_level(&self, level: Option<u32>) -> u32 {
+        let level = level.unwrap_or(self.default_level());
+        match self {
+            CompressMethod::Gzip => level.clamp(1, 9),
+            CompressMethod::Br => level.min(11),
+            CompressMethod::Zstd => level.clamp(1, 22),
+        }
+    }
+}
+
+impl FromStr for CompressMethod {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "gzip" => Ok(CompressMethod::Gzip),
+            "br" => Ok(CompressMethod::Br),
+            "zstd" => Ok(CompressMethod::Zstd),
+            _ => Err(ProxyError::Extension("unknow compress method")),
+        }
+    }
+}
+
+impl Display for CompressMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompressMethod::Gzip => f.write_str("gzip"),
+            CompressMethod::Br => f.write_str("br"),
+            CompressMethod::Zstd => f.write_str("zstd"),
+        }
+    }
+}
+
+fn default_methods() -> Vec<CompressMethod> {
+    vec![CompressMethod::Br, CompressMethod::Zstd, CompressMethod::Gzip]
+}
+
+fn default_min_length() -> ConfigSize {
+    ConfigSize::new(1024)
+}
+
+fn default_types() -> Vec<String> {
+    vec![
+        "text/*".to_string(),
+        "application/javascript".to_string(),
+        "application/json".to_string(),
+        "application/xml".to_string(),
+        "image/svg+xml".to_string(),
+    ]
+}
+
+/// 响应的动态压缩配置, 可在http, server及location中配置
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompressConfig {
+    /// 支持的算法, 客户端的权重相同时按配置的顺序优先
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "default_methods")]
+    pub methods: Vec<CompressMethod>,
+    /// 压缩级别, 为空时各算法使用各自的默认值
+    pub level: Option<u32>,
+    /// 小于该长度的响应不压缩, 未知长度的一律压缩
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_min_length")]
+    pub min_length: ConfigSize,
+    /// 压缩的Content-Type, 支持如"text/*"的通配, "*"表示全部
+    #[serde(default = "default_types")]
+    pub types: Vec<String>,
+}
+
+impl Default for CompressConfig {
+    fn default() -> Self {
+        Self {
+            methods: default_methods(),
+            level: None,
+            min_length: default_min_length(),
+            types: default_types(),
+        }
+    }
+}
+
+/// 流式的压缩器, 每次写入后取出已压缩的数据
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Br(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(method: CompressMethod, level: u32) -> io::Result<Self> {
+        Ok(match method {
+            CompressMethod::Gzip => Encoder::Gzip(GzEncoder::new(vec![], Compression::new(level))),
+            CompressMethod::Br => {
+                Encoder::Br(Box::new(brotli::CompressorWriter::new(vec![], 4096, level, 22)))
+            }
+            CompressMethod::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(vec![], level as i32)?)
+            }
+        })
+    }
+
+    /// 写入数据并刷新, 保证流式的响应能及时送达
+    fn write(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Encoder::Gzip(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Br(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            }
+            Encoder::Zstd(e) => {
+                e.write_all(data)?;
+                e.flush()?;
+                e.get_mut()
+            }
+        };
+        Ok(std::mem::take(out))
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Br(e) => Ok(e.into_inner()),
+            Encoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl CompressConfig {
+    /// 按Accept-Encoding选择算法, q值相同时按配置的顺序,
+    /// *只匹配未明确列出的算法, 防止选中q=0拒绝的算法
+    pub fn choose_method(&self, accept: &str) -> Option<CompressMethod> {
+        let mut items = vec![];
+        for item in accept.split(',') {
+            let mut parts = item.split(';');
+            let name = parts.next().unwrap_or_default().trim();
+            let mut q = 1.0;
+            for p in parts {
+                if let Some(v) = p.trim().strip_prefix("q=") {
+                    q = v.trim().parse::<f32>().unwrap_or(0.0);
+                }
+            }
+            items.push((name, q));
+        }
+        let is_listed = |method: &CompressMethod| {
+            items
+                .iter()
+                .any(|(name, _)| name.eq_ignore_ascii_case(&method.to_string()))
+        };
+        let mut best: Option<(CompressMethod, f32, usize)> = None;
+        for (name, q) in &items {
+            if *q <= 0.0 {
+                continue;
+            }
+            for (idx, method) in self.methods.iter().enumerate() {
+                let is_match = if *name == "*" {
+                    !is_listed(method)
+                } else {
+                    name.eq_ignore_ascii_case(&method.to_string())
+                };
+                if !is_match {
+                    continue;
+                }
+                let better = match best {
+                    None => true,
+                    Some((_, bq, bidx)) => *q > bq || (*q == bq && idx < bidx),
+                };
+                if better {
+                    best = Some((*method, *q, idx));
+                }
+            }
+        }
+        best.map(|(m, _, _)| m)
+    }
+
+    fn is_match_type(&self, content_type: &str) -> bool {
+        let mime = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+        self.types.iter().any(|t| {
+            if t == "*" {
+                return true;
+            }
+            match t.strip_suffix("/*") {
+                Some(prefix) => mime.split('/').next() == Some(prefix),
+                None => mime == t.to_lowercase(),
+            }
+        })
+    }
+
+    /// 该响应是否适合压缩, 与客户端是否支持无关
+    fn is_compressible(&self, res: &Response<Body>) -> bool {
+        let status = res.status().as_u16();
+        if status < 200 || status == 204 || status == 206 || status == 304 {
+            return false;
+        }
+        let headers = res.headers();
+        // 已编码或者主动关闭压缩的(Content-Encoding为空)不再处理
+        if headers.contains(&HeaderName::CONTENT_ENCODING) {
+            return false;
+        }
+        if let Some(control) = headers.get_str_value(&HeaderName::CACHE_CONTROL) {
+            if control.to_lowercase().contains("no-transform") {
+                return false;
+            }
+        }
+        match headers.get_str_value(&HeaderName::CONTENT_TYPE) {
+            Some(t) if self.is_match_type(&t) => {}
+            _ => return false,
+        }
+        let len = match headers.get_body_len() {
+            0 if res.body().is_end() => res.body().origin_len() as u64,
+            0 => u64::MAX,
+            len => len as u64,
+        };
+        len >= self.min_length.0
+    }
+
+    /// 处理响应的压缩, 不适合压缩时原样返回
+    pub fn deal_response(&self, req: &Request<Body>, mut res: Response<Body>) -> Response<Body> {
+        if !self.is_compressible(&res) {
+            return res;
+        }
+        Helper::add_vary(&mut res, "Accept-Encoding");
+        if req.method() == &Method::Head || req.version() == Version::Http10 {
+            return res;
+        }
+        let method = match req
+            .headers()
+            .get_str_value(&HeaderName::ACCEPT_ENCODING)
+            .and_then(|a| self.choose_method(&a))
+        {
+            Some(method) => method,
+            None => return res,
+        };
+        let encoder = match Encoder::new(method, method.fix_level(self.level)) {
+            Ok(encoder) => encoder,
+            Err(e) => {
+                log::warn!("创建{}压缩失败:{:?}", method, e);
+                return res;
+            }
+        };
+
+        let headers = res.headers_mut();
+        headers.remove(&HeaderName::CONTENT_LENGTH);
+        headers.remove(&HeaderName::ACCEPT_RANGES);
+        headers.insert(HeaderName::CONTENT_ENCODING, method.to_string());
+        if req.version() == Version::Http11 {
+            headers.insert(HeaderName::TRANSFER_ENCODING, "chunked");
+        }
+        // 内容已变化, 强校验的ETag转成弱校验
+        if let Some(etag) = headers.get_str_value(&HeaderName::ETAG) {
+            if !etag.starts_with("W/") {
+                headers.insert(HeaderName::ETAG, format!("W/{}", etag));
+            }
+        }
+
+        let mut encoder = Some(encoder);
+        let body = Helper::pump_body(res.body_mut(), BinaryMut::new(), None, move |data, is_end| {
+            let result = match encoder.as_mut() {
+                Some(e) if !is_end => e.write(data),
+                Some(_) => encoder.take().unwrap().finish(),
+                None => return BodyPump::Stop,
+            };
+            match result {
+                Ok(data) => BodyPump::Send(Binary::from(data)),
+                Err(e) => {
+                    log::trace!("压缩响应数据失败:{:?}", e);
+                    BodyPump::Stop
+                }
+            }
+        });
+        // 数据已经过压缩, 标记为原始的压缩方式防止再次压缩
+        match method {
+            CompressMethod::Gzip => body.set_compress_origin_gzip(),
+            CompressMethod::Br => body.set_compress_origin_brotli(),
+            CompressMethod::Zstd => {
+                body.set_origin_compress_method(Consts::COMPRESS_METHOD_NONE);
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use webparse::{BinaryMut, Buf, Request, Response, Version};
+    use wenmeng::{Body, Consts};
+
+    use super::{CompressConfig, CompressMethod};
+
+    fn build_res(len: usize) -> Response<Body> {
+        Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Content-Length", len)
+            .header("ETag", "\"abc\"")
+            .body(Body::from("a".repeat(len)))
+            .unwrap()
+    }
+
+    #[test]
+    fn do_test_choose() {
+        let config = CompressConfig::default();
+        assert_eq!(config.choose_method("gzip, deflate, br"), Some(CompressMethod::Br));
+        assert_eq!(config.choose_method("gzip;q=1.0, br;q=0.5"), Some(CompressMethod::Gzip));
+        assert_eq!(config.choose_method("br;q=0, gzip"), Some(CompressMethod::Gzip));
+        assert_eq!(config.choose_method("*"), Some(CompressMethod::Br));
+        // *不匹配已明确列出的算法
+        assert_eq!(config.choose_method("br;q=0, *"), Some(CompressMethod::Zstd));
+        assert_eq!(config.choose_method("gzip;q=0.5, *;q=0.8"), Some(CompressMethod::Br));
+        assert_eq!(config.choose_method("identity"), None);
+        assert!(config.is_match_type("text/plain"));
+        assert!(config.is_match_type("application/json; charset=utf-8"));
+        assert!(!config.is_match_type("image/png"));
+    }
+
+    #[tokio::test]
+    async fn do_test_compress() {
+        let config = CompressConfig::default();
+        let req = Request::builder()
+            .version(Version::Http11)
+            .header("Accept-Encoding", "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = config.deal_response(&req, build_res(10));
+        assert!(!res.headers().contains(&"Content-Encoding"));
+        assert_eq!(res.headers().get_str_value(&"Vary"), None);
+
+        let mut res = config.deal_response(&req, build_res(4096));
+        assert_eq!(res.headers().get_str_value(&"Content-Encoding").unwrap(), "gzip");
+        // 模拟服务端按Content-Encoding的处理, 已压缩的数据原样输出
+        res.body_mut().add_compress_method(Consts::COMPRESS_METHOD_GZIP);
+        assert_eq!(res.headers().get_str_value(&"Vary").unwrap(), "Accept-Encoding");
+        assert_eq!(res.headers().get_str_value(&"ETag").unwrap(), "W/\"abc\"");
+        assert!(!res.headers().contains(&"Content-Length"));
+
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert!(data.remaining() < 4096);
+        let mut decoder = flate2::read::GzDecoder::new(data.chunk());
+        let mut text = String::new();
+        decoder.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "a".repeat(4096));
+
+        let req = Request::builder()
+            .version(Version::Http11)
+            .header("Accept-Encoding", "zstd")
+            .body(Body::empty())
+            .unwrap();
+        let mut res = config.deal_response(&req, build_res(4096));
+        assert_eq!(res.headers().get_str_value(&"Content-Encoding").unwrap(), "zstd");
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert_eq!(zstd::decode_all(data.chunk()).unwrap(), "a".repeat(4096).into_bytes());
+    }
+}
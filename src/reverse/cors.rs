@@ -0,0 +1,250 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/15 14:08:51
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use webparse::{Method, Request, Response};
+use wenmeng::Body;
+
+use crate::{ConfigDuration, DisplayFromStrOrNumber, Helper};
+
+/// 跨域的策略, 预检请求由代理直接返回, 不访问上游
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// 允许的来源, 可为*或如 https://*.wmproxy.net 的通配
+    #[serde(default = "Vec::new")]
+    pub allow_origins: Vec<String>,
+    /// 按正则匹配允许的来源
+    pub allow_origin_regex: Option<String>,
+    /// 允许的方法, 为空时允许请求的方法
+    #[serde(default = "Vec::new")]
+    pub allow_methods: Vec<String>,
+    /// 允许的请求头, 为空时允许请求的头
+    #[serde(default = "Vec::new")]
+    pub allow_headers: Vec<String>,
+    /// 允许浏览器读取的响应头
+    #[serde(default = "Vec::new")]
+    pub expose_headers: Vec<String>,
+    /// 是否允许携带Cookie等凭证, 开启时不返回*而是返回请求的来源, 且allow_origins中的*不再生效
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// 预检结果的缓存时长
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub max_age: Option<ConfigDuration>,
+}
+
+impl CorsConfig {
+    /// 是否允许任意来源且返回*
+    fn is_any_origin(&self) -> bool {
+        !self.allow_credentials && self.allow_origins.iter().any(|o| o == "*")
+    }
+
+    /// 返回Access-Control-Allow-Origin的值, 不允许时返回None
+    fn get_allow_origin(&self, origin: &str) -> Option<String> {
+        if self.is_any_origin() {
+            return Some("*".to_string());
+        }
+        let is_match = self.allow_origins.iter().any(|o| {
+            // 携带凭证时不允许任意来源, 防止回显任意的Origin
+            if o == "*" {
+                false
+            } else if o.contains('*') {
+                Helper::is_match(origin, o)
+            } else {
+                o.eq_ignore_ascii_case(origin)
+            }
+        });
+        if is_match {
+            return Some(origin.to_string());
+        }
+        if let Some(regex) = &self.allow_origin_regex {
+            if let Some(re) = Helper::try_cache_regex(regex) {
+                if re.is_match(origin) {
+                    return Some(origin.to_string());
+                }
+            }
+        }
+        None
+    }
+
+    fn is_allow_method(&self, method: &str) -> bool {
+        self.allow_methods.is_empty()
+            || self
+                .allow_methods
+                .iter()
+                .any(|m| m == "*" || m.eq_ignore_ascii_case(method))
+    }
+
+    /// 处理预检请求, 非预检请求返回None
+    pub fn deal_preflight(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        if req.method() != &Method::Options {
+            return None;
+        }
+        let origin = req.headers().get_str_value(&"Origin")?;
+        let method = req
+            .headers()
+            .get_str_value(&"Access-Control-Request-Method")?;
+        let mut res = Response::builder().status(204).body(Body::empty()).unwrap();
+        Helper::add_vary(&mut res, "Origin");
+        Helper::add_vary(&mut res, "Access-Control-Request-Method");
+        Helper::add_vary(&mut res, "Access-Control-Request-Headers");
+        let allow_origin = match self.get_allow_origin(&origin) {
+            Some(allow_origin) if self.is_allow_method(&method) => allow_origin,
+            _ => {
+                log::trace!("拒绝跨域的预检请求, 来源:{} 方法:{}", origin, method);
+                *res.status_mut() = webparse::StatusCode::FORBIDDEN;
+                return Some(res);
+            }
+        };
+        let headers = res.headers_mut();
+        headers.insert("Access-Control-Allow-Origin", allow_origin);
+        if self.allow_credentials {
+            headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+        if self.allow_methods.is_empty() {
+            headers.insert("Access-Control-Allow-Methods", method);
+        } else {
+            headers.insert("Access-Control-Allow-Methods", self.allow_methods.join(", "));
+        }
+        if !self.allow_headers.is_empty() {
+            headers.insert("Access-Control-Allow-Headers", self.allow_headers.join(", "));
+        } else if let Some(request_headers) =
+            req.headers().get_str_value(&"Access-Control-Request-Headers")
+        {
+            headers.insert("Access-Control-Allow-Headers", request_headers);
+        }
+        if let Some(max_age) = &self.max_age {
+            headers.insert("Access-Control-Max-Age", max_age.0.as_secs().to_string());
+        }
+        Some(res)
+    }
+
+    /// 给响应加上跨域的头, 上游返回的跨域头将被替换
+    pub fn deal_response(&self, req: &Request<Body>, mut res: Response<Body>) -> Response<Body> {
+        for name in [
+            "Access-Control-Allow-Origin",
+            "Access-Control-Allow-Credentials",
+            "Access-Control-Expose-Headers",
+        ] {
+            res.headers_mut().remove(&name);
+        }
+        // 返回值随Origin变化时需告知缓存
+        if !self.is_any_origin() {
+            Helper::add_vary(&mut res, "Origin");
+        }
+        let origin = match req.headers().get_str_value(&"Origin") {
+            Some(origin) => origin,
+            None => return res,
+        };
+        let allow_origin = match self.get_allow_origin(&origin) {
+            Some(allow_origin) => allow_origin,
+            None => return res,
+        };
+        let headers = res.headers_mut();
+        headers.insert("Access-Control-Allow-Origin", allow_origin);
+        if self.allow_credentials {
+            headers.insert("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.expose_headers.is_empty() {
+            headers.insert("Access-Control-Expose-Headers", self.expose_headers.join(", "));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webparse::{Method, Request, Response};
+    use wenmeng::Body;
+
+    use super::CorsConfig;
+
+    fn build_req(method: Method, origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .url("http://api.wmproxy.net/list")
+            .header("Origin", origin.to_string())
+            .header("Access-Control-Request-Method", "PUT")
+            .header("Access-Control-Request-Headers", "X-Token")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn do_test_cors() {
+        let cors: CorsConfig = toml::from_str(
+            r#"
+            allow_origins = ["https://*.wmproxy.net", "http://localhost:8080"]
+            allow_origin_regex = "^https://wmproxy\\.(com|org)$"
+            allow_methods = ["GET", "POST", "PUT"]
+            expose_headers = ["X-Request-Id"]
+            allow_credentials = true
+            max_age = "10min"
+            "#,
+        )
+        .unwrap();
+
+        let req = build_req(Method::Options, "https://app.wmproxy.net");
+        let res = cors.deal_preflight(&req).unwrap();
+        assert_eq!(res.status(), 204);
+        let headers = res.headers();
+        assert_eq!(
+            headers.get_str_value(&"Access-Control-Allow-Origin").unwrap(),
+            "https://app.wmproxy.net"
+        );
+        assert_eq!(headers.get_str_value(&"Access-Control-Allow-Methods").unwrap(), "GET, POST, PUT");
+        assert_eq!(headers.get_str_value(&"Access-Control-Allow-Headers").unwrap(), "X-Token");
+        assert_eq!(headers.get_str_value(&"Access-Control-Allow-Credentials").unwrap(), "true");
+        assert_eq!(headers.get_str_value(&"Access-Control-Max-Age").unwrap(), "600");
+        assert!(headers.get_str_value(&"Vary").unwrap().starts_with("Origin"));
+
+        let req = build_req(Method::Options, "https://evil.com");
+        assert_eq!(cors.deal_preflight(&req).unwrap().status(), 403);
+        let req = build_req(Method::Get, "https://app.wmproxy.net");
+        assert!(cors.deal_preflight(&req).is_none());
+
+        let res = Response::builder()
+            .header("Access-Control-Allow-Origin", "*")
+            .header("Vary", "Accept-Encoding")
+            .body(Body::empty())
+            .unwrap();
+        let res = cors.deal_response(&build_req(Method::Get, "https://wmproxy.org"), res);
+        let headers = res.headers();
+        assert_eq!(
+            headers.get_str_value(&"Access-Control-Allow-Origin").unwrap(),
+            "https://wmproxy.org"
+        );
+        assert_eq!(headers.get_str_value(&"Access-Control-Expose-Headers").unwrap(), "X-Request-Id");
+        assert_eq!(headers.get_str_value(&"Vary").unwrap(), "Accept-Encoding, Origin");
+
+        let res = Response::builder().body(Body::empty()).unwrap();
+        let res = cors.deal_response(&build_req(Method::Get, "https://evil.com"), res);
+        assert!(res.headers().get_str_value(&"Access-Control-Allow-Origin").is_none());
+        assert_eq!(res.headers().get_str_value(&"Vary").unwrap(), "Origin");
+
+        let cors: CorsConfig = toml::from_str(r#"allow_origins = ["*"]"#).unwrap();
+        let res = Response::builder().body(Body::empty()).unwrap();
+        let res = cors.deal_response(&build_req(Method::Get, "https://evil.com"), res);
+        assert_eq!(res.headers().get_str_value(&"Access-Control-Allow-Origin").unwrap(), "*");
+        assert!(res.headers().get_str_value(&"Vary").is_none());
+
+        let cors: CorsConfig =
+            toml::from_str("allow_origins = [\"*\"]\nallow_credentials = true").unwrap();
+        let req = build_req(Method::Options, "https://evil.com");
+        assert_eq!(cors.deal_preflight(&req).unwrap().status(), 403);
+        let res = Response::builder().body(Body::empty()).unwrap();
+        let res = cors.deal_response(&build_req(Method::Get, "https://evil.com"), res);
+        assert!(res.headers().get_str_value(&"Access-Control-Allow-Origin").is_none());
+    }
+}
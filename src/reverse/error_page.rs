@@ -0,0 +1,205 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/12 15:32:48
+
+use std::{fmt::Display, str::FromStr};
+
+use webparse::{HeaderName, Response};
+use wenmeng::{Body, ProtError, ProtResult, RecvRequest};
+
+use crate::{Helper, ProxyError};
+
+/// 错误页返回的状态码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPageStatus {
+    /// 保持原有的错误状态码
+    Keep,
+    /// 使用内部跳转后的状态码, 配置为"="
+    Response,
+    /// 使用指定的状态码, 配置为"=200"
+    Set(u16),
+}
+
+/// 错误页的内容
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorPageTarget {
+    /// 以"/"开头, 以该路径重新匹配location
+    Internal(String),
+    /// 以http://或https://开头, 302跳转到该地址
+    Redirect(String),
+    /// 以"file:"开头, 返回文件的内容
+    File(String),
+    /// 其它的均返回该文本
+    Body(String),
+}
+
+/// 按状态码配置的错误页, 格式如 "404 500-599 =200 /50x.html"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorPage {
+    pub codes: Vec<(u16, u16)>,
+    pub status: ErrorPageStatus,
+    pub target: ErrorPageTarget,
+}
+
+impl ErrorPage {
+    pub fn is_match(&self, status: u16) -> bool {
+        self.codes.iter().any(|(min, max)| *min <= status && status <= *max)
+    }
+
+    /// 查找第一个匹配该状态码的错误页
+    pub fn find(pages: &[ErrorPage], status: u16) -> Option<&ErrorPage> {
+        pages.iter().find(|p| p.is_match(status))
+    }
+
+    /// 错误页最终返回的状态码
+    pub fn get_status(&self, origin: u16, response: u16) -> u16 {
+        match self.status {
+            ErrorPageStatus::Keep => origin,
+            ErrorPageStatus::Response => response,
+            ErrorPageStatus::Set(status) => status,
+        }
+    }
+
+    /// 生成错误页, 内部跳转需由调用方重新匹配location
+    pub async fn build_response(
+        &self,
+        req: &RecvRequest,
+        origin: u16,
+    ) -> ProtResult<Option<Response<Body>>> {
+        let status = self.get_status(origin, origin);
+        let res = match &self.target {
+            ErrorPageTarget::Internal(_) => return Ok(None),
+            ErrorPageTarget::Redirect(url) => Response::builder()
+                .status(if self.status == ErrorPageStatus::Keep { 302 } else { status })
+                .header(HeaderName::LOCATION, Helper::format_req(req, url))
+                .body(Body::empty()),
+            ErrorPageTarget::File(file) => {
+                let data = tokio::fs::read(file).await?;
+                Response::builder()
+                    .status(status)
+                    .header(HeaderName::CONTENT_TYPE, "text/html; charset=utf-8")
+                    .body(Body::from(data))
+            }
+            ErrorPageTarget::Body(body) => Response::builder()
+                .status(status)
+                .header(HeaderName::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from(Helper::format_req(req, body))),
+        };
+        Ok(Some(res.map_err(|_| ProtError::Extension("error page build error"))?))
+    }
+}
+
+impl FromStr for ErrorPage {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = Helper::split_by_whitespace(s);
+        if vals.len() < 2 {
+            return Err(ProxyError::Extension("error_page格式错误, 应为: 状态码 [=状态码] 内容"));
+        }
+        let parse_code = |v: &str| v.parse::<u16>().ok().filter(|c| (300..=599).contains(c));
+        let code_err = || ProxyError::Extension("error_page的状态码需在300-599之间");
+        let mut codes = vec![];
+        let mut status = ErrorPageStatus::Keep;
+        for val in &vals[..vals.len() - 1] {
+            if let Some(code) = val.strip_prefix('=') {
+                status = if code.is_empty() {
+                    ErrorPageStatus::Response
+                } else {
+                    ErrorPageStatus::Set(
+                        code.parse::<u16>()
+                            .map_err(|_| ProxyError::Extension("error_page的状态码错误"))?,
+                    )
+                };
+            } else if let Some((min, max)) = val.split_once('-') {
+                let min = parse_code(min).ok_or_else(code_err)?;
+                codes.push((min, parse_code(max).ok_or_else(code_err)?));
+            } else {
+                let code = parse_code(val).ok_or_else(code_err)?;
+                codes.push((code, code));
+            }
+        }
+        if codes.is_empty() {
+            return Err(ProxyError::Extension("error_page未配置状态码"));
+        }
+        let target = vals[vals.len() - 1];
+        let target = if target.starts_with('/') {
+            ErrorPageTarget::Internal(target.to_string())
+        } else if target.starts_with("http://") || target.starts_with("https://") {
+            ErrorPageTarget::Redirect(target.to_string())
+        } else if let Some(file) = target.strip_prefix("file:") {
+            ErrorPageTarget::File(file.to_string())
+        } else {
+            ErrorPageTarget::Body(target.to_string())
+        };
+        Ok(ErrorPage {
+            codes,
+            status,
+            target,
+        })
+    }
+}
+
+impl Display for ErrorPage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (min, max) in &self.codes {
+            if min == max {
+                f.write_fmt(format_args!("{} ", min))?;
+            } else {
+                f.write_fmt(format_args!("{}-{} ", min, max))?;
+            }
+        }
+        match self.status {
+            ErrorPageStatus::Keep => {}
+            ErrorPageStatus::Response => f.write_str("= ")?,
+            ErrorPageStatus::Set(s) => f.write_fmt(format_args!("={} ", s))?,
+        }
+        match &self.target {
+            ErrorPageTarget::Internal(v) | ErrorPageTarget::Redirect(v) => f.write_str(v),
+            ErrorPageTarget::File(v) => f.write_fmt(format_args!("file:{}", v)),
+            ErrorPageTarget::Body(v) => f.write_fmt(format_args!("'{}'", v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::{ErrorPage, ErrorPageStatus, ErrorPageTarget};
+
+    #[test]
+    fn do_test_error_page() {
+        let page = ErrorPage::from_str("404 500-599 /50x.html").unwrap();
+        assert_eq!(page.codes, vec![(404, 404), (500, 599)]);
+        assert_eq!(page.target, ErrorPageTarget::Internal("/50x.html".to_string()));
+        assert!(page.is_match(502) && page.is_match(404) && !page.is_match(403));
+        assert_eq!(page.get_status(502, 200), 502);
+        assert_eq!(ErrorPage::from_str(&page.to_string()).unwrap(), page);
+
+        let page = ErrorPage::from_str("502 503 =200 '服务暂不可用 {client_ip}'").unwrap();
+        assert_eq!(page.status, ErrorPageStatus::Set(200));
+        assert_eq!(
+            page.target,
+            ErrorPageTarget::Body("服务暂不可用 {client_ip}".to_string())
+        );
+        assert_eq!(ErrorPage::from_str(&page.to_string()).unwrap(), page);
+
+        let page = ErrorPage::from_str("404 = /index.html").unwrap();
+        assert_eq!(page.get_status(404, 200), 200);
+        let page = ErrorPage::from_str("500 file:html/50x.html").unwrap();
+        assert_eq!(page.target, ErrorPageTarget::File("html/50x.html".to_string()));
+
+        assert!(ErrorPage::from_str("/50x.html").is_err());
+        assert!(ErrorPage::from_str("200 /50x.html").is_err());
+        assert!(ErrorPage::from_str("=200 /50x.html").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/08 10:12:26
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+use webparse::{Binary, BinaryMut, HeaderName, Request, Response};
+use wenmeng::{Body, ProtError};
+
+use crate::{BodyPump, Helper};
+
+/// gRPC请求的处理辅助
+pub struct GrpcHelper;
+
+impl GrpcHelper {
+    pub const STATUS_OK: u32 = 0;
+    pub const STATUS_UNKNOWN: u32 = 2;
+    pub const STATUS_DEADLINE_EXCEEDED: u32 = 4;
+    pub const STATUS_PERMISSION_DENIED: u32 = 7;
+    pub const STATUS_UNIMPLEMENTED: u32 = 12;
+    pub const STATUS_INTERNAL: u32 = 13;
+    pub const STATUS_UNAVAILABLE: u32 = 14;
+    pub const STATUS_UNAUTHENTICATED: u32 = 16;
+
+    /// 是否为gRPC的请求
+    pub fn is_grpc<T: webparse::Serialize>(req: &Request<T>) -> bool {
+        req.headers()
+            .get_option_value(&HeaderName::CONTENT_TYPE)
+            .map(|v| v.as_bytes().starts_with(b"application/grpc"))
+            .unwrap_or(false)
+    }
+
+    /// 解析grpc-timeout, 格式为最多8位数字加单位, 如 "10S" "200m"
+    pub fn parse_timeout(value: &str) -> Option<Duration> {
+        if value.len() < 2 || value.len() > 9 {
+            return None;
+        }
+        let (num, unit) = value.split_at(value.len() - 1);
+        let num = num.parse::<u64>().ok()?;
+        match unit {
+            "H" => Some(Duration::from_secs(num * 3600)),
+            "M" => Some(Duration::from_secs(num * 60)),
+            "S" => Some(Duration::from_secs(num)),
+            "m" => Some(Duration::from_millis(num)),
+            "u" => Some(Duration::from_micros(num)),
+            "n" => Some(Duration::from_nanos(num)),
+            _ => None,
+        }
+    }
+
+    /// 请求中的截止时长
+    pub fn get_timeout<T: webparse::Serialize>(req: &Request<T>) -> Option<Duration> {
+        let value = req.headers().get_option_value(&"grpc-timeout")?;
+        Self::parse_timeout(&value.to_string())
+    }
+
+    /// 响应体在截止时间前未结束时中断响应, 客户端将收到流被重置的错误
+    pub fn limit_body(res: &mut Response<Body>, deadline: Instant) {
+        if res.body().is_end() {
+            return;
+        }
+        // 超出截止时间时不结束响应体, 防止客户端将被截断的数据当作完整的响应
+        Helper::pump_body(res.body_mut(), BinaryMut::new(), Some(deadline), |data, _| {
+            BodyPump::Send(Binary::from(data.to_vec()))
+        });
+    }
+
+    /// 按gRPC规范将HTTP状态码转成gRPC的状态码
+    pub fn status_from_http(status: u16) -> u32 {
+        match status {
+            200 => Self::STATUS_OK,
+            400 => Self::STATUS_INTERNAL,
+            401 => Self::STATUS_UNAUTHENTICATED,
+            403 => Self::STATUS_PERMISSION_DENIED,
+            404 => Self::STATUS_UNIMPLEMENTED,
+            408 | 504 => Self::STATUS_DEADLINE_EXCEEDED,
+            429 | 502 | 503 => Self::STATUS_UNAVAILABLE,
+            _ => Self::STATUS_UNKNOWN,
+        }
+    }
+
+    /// 代理过程中的错误转成gRPC的状态码
+    pub fn status_from_error(err: &ProtError) -> u32 {
+        let is_timeout = match err {
+            ProtError::IoError(e) => e.kind() == std::io::ErrorKind::TimedOut,
+            _ => err.is_timeout().0,
+        };
+        if is_timeout {
+            Self::STATUS_DEADLINE_EXCEEDED
+        } else {
+            Self::STATUS_UNAVAILABLE
+        }
+    }
+
+    /// 生成只有头信息的gRPC响应(Trailers-Only), 状态放在头信息中返回
+    pub fn build_response(status: u32, message: &str) -> Response<Body> {
+        let mut res: Response<Body> = Response::builder()
+            .status(200)
+            .header(HeaderName::CONTENT_TYPE, "application/grpc")
+            .header("grpc-status", status.to_string())
+            .body(())
+            .unwrap()
+            .into_type();
+        if !message.is_empty() {
+            res.headers_mut().insert("grpc-message", Self::encode_message(message));
+        }
+        res
+    }
+
+    /// 代理生成的非gRPC响应(如404, 503)转成对应的gRPC状态
+    pub fn fix_response(res: Response<Body>) -> Response<Body> {
+        let is_grpc = res
+            .headers()
+            .get_option_value(&HeaderName::CONTENT_TYPE)
+            .map(|v| v.as_bytes().starts_with(b"application/grpc"))
+            .unwrap_or(false);
+        if is_grpc && res.status() == 200 {
+            return res;
+        }
+        let status = res.status().as_u16();
+        let message = format!("proxy return http status {}", status);
+        Self::build_response(Self::status_from_http(status), &message)
+    }
+
+    /// grpc-message需按百分号编码非可见字符
+    fn encode_message(message: &str) -> String {
+        let mut result = String::new();
+        for b in message.bytes() {
+            if (0x20..0x7f).contains(&b) && b != b'%' {
+                result.push(b as char);
+            } else {
+                result.push_str(&format!("%{:02X}", b));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::mpsc::channel, time::Instant};
+    use webparse::{Binary, BinaryMut, Request, Response};
+    use wenmeng::{Body, ProtError};
+
+    use super::GrpcHelper;
+
+    #[test]
+    fn do_test_grpc() {
+        assert_eq!(GrpcHelper::parse_timeout("10S"), Some(Duration::from_secs(10)));
+        assert_eq!(GrpcHelper::parse_timeout("200m"), Some(Duration::from_millis(200)));
+        assert_eq!(GrpcHelper::parse_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(GrpcHelper::parse_timeout("123456789S"), None);
+        assert_eq!(GrpcHelper::parse_timeout("10s"), None);
+
+        let req = Request::builder()
+            .url("/helloworld.Greeter/SayHello")
+            .header("content-type", "application/grpc+proto")
+            .header("grpc-timeout", "1S")
+            .body(Body::empty())
+            .unwrap();
+        assert!(GrpcHelper::is_grpc(&req));
+        assert_eq!(GrpcHelper::get_timeout(&req), Some(Duration::from_secs(1)));
+
+        assert_eq!(GrpcHelper::status_from_http(404), GrpcHelper::STATUS_UNIMPLEMENTED);
+        assert_eq!(GrpcHelper::status_from_http(503), GrpcHelper::STATUS_UNAVAILABLE);
+        assert_eq!(
+            GrpcHelper::status_from_error(&ProtError::time_timeout("grpc")),
+            GrpcHelper::STATUS_DEADLINE_EXCEEDED
+        );
+        assert_eq!(
+            GrpcHelper::status_from_error(&ProtError::Extension("no more upstream to try")),
+            GrpcHelper::STATUS_UNAVAILABLE
+        );
+
+        let res = GrpcHelper::build_response(GrpcHelper::STATUS_UNAVAILABLE, "no upstream\n");
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers().get_option_value(&"grpc-status").unwrap(), &"14");
+        assert_eq!(
+            res.headers().get_option_value(&"grpc-message").unwrap(),
+            &"no upstream%0A"
+        );
+    }
+
+    #[tokio::test]
+    async fn do_test_limit_body() {
+        // 截止时间内未结束的响应体不会正常结束
+        let (sender, receiver) = channel(10);
+        sender.send((false, Binary::from("part"))).await.unwrap();
+        let mut res: Response<Body> = Response::builder()
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        GrpcHelper::limit_body(&mut res, Instant::now() + Duration::from_millis(50));
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert_eq!(data.as_slice(), b"part");
+        assert!(!res.body().is_end());
+        drop(sender);
+
+        let (sender, receiver) = channel(10);
+        let origin = Body::new(receiver, BinaryMut::new(), false);
+        let mut res: Response<Body> = Response::builder().body(origin).unwrap();
+        GrpcHelper::limit_body(&mut res, Instant::now() + Duration::from_secs(10));
+        sender.send((false, Binary::from("ok"))).await.unwrap();
+        sender.send((true, Binary::new())).await.unwrap();
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await;
+        assert_eq!(data.as_slice(), b"ok");
+        assert!(res.body().is_end());
+    }
+}
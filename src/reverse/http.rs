@@ -24,7 +24,6 @@ use console::Style;
 use rustls::{
     crypto::ring::sign::any_supported_type,
     pki_types::{CertificateDer, PrivateKeyDer},
-    server::ResolvesServerCertUsingSni,
     sign::CertifiedKey,
 };
 use serde::{Deserialize, Serialize};
@@ -32,17 +31,18 @@ use serde_with::{serde_as, DisplayFromStr};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     net::TcpListener,
-    sync::mpsc::{Receiver, Sender},
 };
 use tokio_rustls::TlsAcceptor;
-use webparse::{Request, Response};
+use webparse::{Method, Request, Response, StatusCode};
 use wenmeng::{
     Body, HttpTrait, Middleware, ProtError, ProtResult, RecvRequest, RecvResponse, Server,
 };
 
 use super::{
-    common::CommonConfig, limit_req::LimitReqZone, ws::ServerWsOperate, LimitReqMiddleware,
-    LocationConfig, ServerConfig, UpstreamConfig,
+    common::CommonConfig, limit_req::LimitReqZone, ws::ServerWsOperate, CacheData, CacheZone,
+    ErrorPage, ErrorPageTarget, GrpcHelper, LimitReqMiddleware, LocationConfig, PoolConfig,
+    RequestBody, ReverseHelper, RewriteConfig, RewriteResult, ServerConfig, ServerName,
+    ServerNameResolver, UpstreamConfig, UpstreamPool,
 };
 use async_recursion::async_recursion;
 
@@ -73,16 +73,11 @@ impl HttpTrait for Operate {
 
 struct InnerHttpOper {
     pub servers: Vec<Arc<ServerConfig>>,
-    pub cache_sender:
-        HashMap<LocationConfig, (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>)>,
 }
 
 impl InnerHttpOper {
     pub fn new(http: Vec<Arc<ServerConfig>>) -> Self {
-        Self {
-            servers: http,
-            cache_sender: HashMap::new(),
-        }
+        Self { servers: http }
     }
 }
 
@@ -98,6 +93,15 @@ pub struct HttpConfig {
     #[serde(default = "HashMap::new")]
     pub limit_req_zone: HashMap<String, LimitReqZone>,
 
+    /// 响应缓存的区域
+    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    #[serde(default = "HashMap::new")]
+    pub proxy_cache_zone: HashMap<String, CacheZone>,
+
+    /// 上游连接池的配置
+    #[serde(default)]
+    pub keepalive: PoolConfig,
+
     #[serde(flatten)]
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
@@ -109,6 +113,8 @@ impl HttpConfig {
             server: vec![],
             upstream: vec![],
             limit_req_zone: HashMap::new(),
+            proxy_cache_zone: HashMap::new(),
+            keepalive: PoolConfig::default(),
             comm: CommonConfig::new(),
         }
     }
@@ -118,9 +124,13 @@ impl HttpConfig {
             self.comm.log_format.insert("main".to_string(), "{d(%Y-%m-%d %H:%M:%S)} {client_ip} {l} {url} path:{path} query:{query} host:{host} status: {status} {up_status} referer: {referer} user_agent: {user_agent} cookie: {cookie}".to_string());
         }
         self.copy_to_child();
+        UpstreamPool::set_config(self.keepalive.clone());
         for (k, zone) in &self.limit_req_zone {
             LimitReqData::cache(k.to_string(), zone.limit, zone.rate.nums, zone.rate.per)?;
         }
+        for (k, zone) in &self.proxy_cache_zone {
+            CacheData::register(k.to_string(), zone.clone());
+        }
         Ok(())
     }
 
@@ -182,37 +192,36 @@ impl HttpConfig {
         }
     }
 
-    pub async fn bind(
-        &mut self,
-    ) -> ProxyResult<(Option<TlsAcceptor>, Vec<bool>, Vec<TcpListener>)> {
+    /// 绑定监听的端口, 返回每个端口的TlsAcceptor, 非SSL端口为None
+    pub async fn bind(&mut self) -> ProxyResult<(Vec<Option<TlsAcceptor>>, Vec<TcpListener>)> {
         let mut listeners = vec![];
-        let mut tlss = vec![];
+        let mut accepts = vec![];
         let mut bind_addr_set = HashSet::new();
-        let config = rustls::ServerConfig::builder();
-        let mut resolve = ResolvesServerCertUsingSni::new();
-        let mut one_key = None;
         let mut one_cert = None;
+        let mut certs = vec![];
         let is_single = self.server.len() == 1;
-        for value in &self.server.clone() {
-            let mut is_ssl = false;
+        ReverseHelper::resolve_upstreams(&self.upstream).await;
+        for value in &self.server {
+            ReverseHelper::resolve_upstreams(&value.upstream).await;
+            for l in &value.location {
+                ReverseHelper::resolve_upstreams(&l.upstream).await;
+            }
+        }
+        for (idx, value) in self.server.iter().enumerate() {
             if value.cert.is_some() && value.key.is_some() {
                 let key = Self::load_keys(&value.key)?;
                 let cert = Self::load_certs(&value.cert)?;
                 if is_single {
-                    one_key = Some(key.clone_key());
-                    one_cert = Some(cert.clone());
+                    one_cert = Some((cert, key));
                 } else {
                     let singed_key = any_supported_type(&key)
                         .map_err(|_| ProtError::Extension("unvaild key"))?;
-                    let ck = CertifiedKey::new(cert, singed_key);
-                    let name = value.comm.domain.clone().unwrap_or(value.up_name.clone());
-                    resolve.add(&name, ck).map_err(|e| {
-                        log::warn!("添加证书时失败:{:?}", e);
-                        ProtError::Extension("key error")
-                    })?;
+                    certs.push((idx, Arc::new(CertifiedKey::new(cert, singed_key))));
                 }
-                is_ssl = true;
             }
+        }
+        for value in &self.server {
+            let is_ssl = value.cert.is_some() && value.key.is_some();
             for v in &value.bind_addr.0 {
                 if bind_addr_set.contains(&v) {
                     continue;
@@ -222,7 +231,7 @@ impl HttpConfig {
                 log::info!("HTTP服务：{}，提供http处理及转发功能。", Style::new().blink().green().apply_to(url));
                 let listener = Helper::bind(v).await?;
                 listeners.push(listener);
-                tlss.push(false);
+                accepts.push(None);
             }
 
             for v in &value.bind_ssl.0 {
@@ -237,42 +246,55 @@ impl HttpConfig {
                 log::info!("HTTPs服务：{}，提供https处理及转发功能。", Style::new().blink().green().apply_to(url));
                 let listener = Helper::bind(v).await?;
                 listeners.push(listener);
-                tlss.push(is_ssl);
+                accepts.push(Some(self.build_tls_accept(v.port(), &one_cert, &certs)?));
             }
         }
+        Ok((accepts, listeners))
+    }
 
-        let mut config = if is_single && one_cert.is_some() && one_key.is_some() {
-            config
-                .with_no_client_auth()
-                .with_single_cert(one_cert.unwrap(), one_key.unwrap())
+    /// 按SSL端口创建TlsAcceptor, 只从绑定该端口的server中按SNI选择证书及default_server
+    fn build_tls_accept(
+        &self,
+        port: u16,
+        one_cert: &Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+        certs: &[(usize, Arc<CertifiedKey>)],
+    ) -> ProxyResult<TlsAcceptor> {
+        let config = rustls::ServerConfig::builder().with_no_client_auth();
+        let mut config = match one_cert {
+            Some((cert, key)) => config
+                .with_single_cert(cert.clone(), key.clone_key())
                 .map_err(|e| {
                     log::warn!("添加证书时失败:{:?}", e);
                     ProtError::Extension("key error")
-                })?
-        } else {
-            config
-                .with_no_client_auth()
-                .with_cert_resolver(Arc::new(resolve))
+                })?,
+            None => {
+                let mut resolve = ServerNameResolver::new();
+                for (idx, key) in certs {
+                    let value = &self.server[*idx];
+                    if value.bind_ssl.contains(port) {
+                        resolve.add(value.get_cert_names(), key.clone(), value.default_server);
+                    }
+                }
+                config.with_cert_resolver(Arc::new(resolve))
+            }
         };
         config.alpn_protocols.push("h2".as_bytes().to_vec());
         config.alpn_protocols.push("http/1.1".as_bytes().to_vec());
-        Ok((Some(TlsAcceptor::from(Arc::new(config))), tlss, listeners))
+        Ok(TlsAcceptor::from(Arc::new(config)))
     }
 
     #[async_recursion]
     async fn deal_match_location(
         req: &mut Request<Body>,
         // 缓存客户端请求
-        cache: &mut HashMap<
-            LocationConfig,
-            (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>),
-        >,
         // 该Server的配置选项
         server: Arc<ServerConfig>,
         // 已处理的匹配路由
         deals: &mut HashSet<usize>,
         // 已处理的TryPath匹配路由
         try_deals: &mut HashSet<usize>,
+        // 已执行过rewrite重新匹配的路由
+        rewrite_deals: &mut HashSet<usize>,
     ) -> ProtResult<Response<Body>> {
         let path = req.path().clone();
         let mut l = None;
@@ -288,13 +310,33 @@ impl HttpConfig {
             }
         }
         if l.is_none() {
-            return Ok(Response::status404()
+            let res = Response::status404()
                 .body("unknow location to deal")
                 .unwrap()
-                .into_type());
+                .into_type();
+            return Self::deal_error_page(req, server.clone(), &server.comm, Ok(res), false).await;
         }
 
         let l = l.unwrap();
+        if !l.rewrite.is_empty() {
+            match RewriteConfig::deal_request(&l.rewrite, req) {
+                RewriteResult::Response(res) => return Ok(*res),
+                RewriteResult::Last => {
+                    // 同一location再次重新匹配表示出现了循环
+                    if !rewrite_deals.insert(now) {
+                        log::warn!("rewrite出现循环, 路径:{}", req.path());
+                        return Ok(Response::status500()
+                            .body("rewrite cycle")
+                            .unwrap()
+                            .into_type());
+                    }
+                    deals.clear();
+                    return Self::deal_match_location(req, server, deals, try_deals, rewrite_deals)
+                        .await;
+                }
+                RewriteResult::Break | RewriteResult::None => {}
+            }
+        }
         if let Some(limit_req) = &l.comm.limit_req {
             if let Some(res) = LimitReqMiddleware::new(limit_req.clone())
                 .process_request(req)
@@ -310,20 +352,39 @@ impl HttpConfig {
                     .map_err(|_| ProtError::Extension("client ip error"))?;
                 if let Some(allow) = &l.comm.allow_ip {
                     if !allow.contains(&ip) {
-                        return Ok(Response::status503()
+                        let res = Response::status503()
                             .body("now allow ip")
                             .unwrap()
-                            .into_type());
+                            .into_type();
+                        return Self::deal_error_page(req, server.clone(), &l.comm, Ok(res), false)
+                            .await;
                     }
                 }
                 if let Some(deny) = &l.comm.deny_ip {
                     if deny.contains(&ip) {
-                        return Ok(Response::status503().body("deny ip").unwrap().into_type());
+                        let res = Response::status503().body("deny ip").unwrap().into_type();
+                        return Self::deal_error_page(req, server.clone(), &l.comm, Ok(res), false)
+                            .await;
                     }
                 }
             }
         }
 
+        // 跨域的预检请求不需认证, 直接返回
+        if let Some(cors) = &l.comm.cors {
+            if let Some(res) = cors.deal_preflight(req) {
+                return Ok(res);
+            }
+        }
+        if let Some(result) = Self::deal_auth(req, &server, l).await {
+            let result = Self::deal_error_page(req, server.clone(), &l.comm, result, false).await;
+            return result.map(|res| Self::deal_cors(req, &l.comm, res));
+        }
+        if let Some(result) = RequestBody::deal_request(&l.comm, req).await.transpose() {
+            let result = Self::deal_error_page(req, server.clone(), &l.comm, result, false).await;
+            return result.map(|res| Self::deal_cors(req, &l.comm, res));
+        }
+
         // 判定该try是否处理过, 防止死循环
         if !try_deals.contains(&now) && l.try_paths.is_some() {
             let try_paths = l.try_paths.as_ref().unwrap();
@@ -337,7 +398,8 @@ impl HttpConfig {
                 // 重写path好方便后续处理无感
                 req.set_path(new_path);
                 if let Ok(res) =
-                    Self::deal_match_location(req, cache, server.clone(), deals, try_deals).await
+                    Self::deal_match_location(req, server.clone(), deals, try_deals, rewrite_deals)
+                        .await
                 {
                     if !res.status().is_client_error() && !res.status().is_server_error() {
                         return Ok(res);
@@ -349,72 +411,204 @@ impl HttpConfig {
                 .body("未发现合适的Try进行服务")
                 .unwrap()
                 .into_type());
-        } else {
-            deals.insert(now);
-            let clone = l.clone_only_hash();
-            if cache.contains_key(&clone) {
-                let mut cache_client = cache.remove(&clone).unwrap();
-                if !cache_client.0.is_closed() {
-                    let _send = cache_client.0.send(req.replace_clone(Body::empty())).await;
-                    match cache_client.1.recv().await {
-                        Some(res) => {
-                            if let Ok(r) = &res {
-                                log::trace!("复用连接收到Response {}", r.status());
-                                cache.insert(clone, cache_client);
-                            }
-                            return res;
-                        }
-                        None => {
-                            log::trace!("复用连接收到空消息,关闭复用连接");
-                            return Ok(Response::status503()
-                                .body("意外的服务端关闭连接")
-                                .unwrap()
-                                .into_type());
-                        }
-                    }
+        }
+        deals.insert(now);
+        let result = match RequestBody::get_over_limit(req) {
+            // 流式发送的请求体超出限制时中断上游的请求
+            Some(notify) => {
+                tokio::select! {
+                    result = l.deal_request(req) => result,
+                    _ = notify.notified() => Ok(RequestBody::too_large()),
                 }
-            } else {
-                let (res, sender, receiver) = l.deal_request(req).await?;
-                if sender.is_some() && receiver.is_some() {
-                    cache.insert(clone, (sender.unwrap(), receiver.unwrap()));
+            }
+            None => l.deal_request(req).await,
+        };
+        let from_upstream =
+            l.file_server.is_none() && l.static_response.is_none() && l.comm.proxy_url.is_some();
+        let result = Self::deal_error_page(req, server.clone(), &l.comm, result, from_upstream).await;
+        result.map(|res| Self::deal_cors(req, &l.comm, res))
+    }
+
+    /// 认证, 均配置时需同时通过, 未通过时返回结果
+    async fn deal_auth(
+        req: &mut Request<Body>,
+        server: &ServerConfig,
+        l: &LocationConfig,
+    ) -> Option<ProtResult<Response<Body>>> {
+        let result = async {
+            if let Some(auth) = &l.comm.auth_basic {
+                if let Some(res) = auth.deal_request(req).await? {
+                    return Ok(Some(res));
                 }
-                return Ok(res);
+            }
+            // 与server相同的配置已在匹配location前校验
+            if let Some(jwt) = &l.comm.jwt {
+                if Some(jwt) != server.comm.jwt.as_ref() {
+                    if let Some(res) = jwt.deal_request(req).await? {
+                        return Ok(Some(res));
+                    }
+                }
+            }
+            if let Some(auth) = &l.comm.auth_request {
+                return auth.deal_request(l, req).await;
+            }
+            Ok(None)
+        }
+        .await;
+        match result {
+            Ok(None) => None,
+            Ok(Some(res)) => Some(Ok(res)),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    /// WebSocket握手前的访问控制, 与HTTP请求相同, 未通过时返回拒绝的响应
+    pub async fn deal_ws_auth(
+        req: &mut Request<Body>,
+        servers: &[Arc<ServerConfig>],
+    ) -> Option<Response<Body>> {
+        let host = req.get_host().unwrap_or_default();
+        let (s, _) = ReverseHelper::get_server_by_host(servers, &host)?;
+        let path = req.path().clone();
+        let l = s.location.iter().find(|l| l.is_match_rule(&path, req))?;
+        let mut result = match &s.comm.jwt {
+            Some(jwt) => jwt.deal_request(req).await.transpose(),
+            None => None,
+        };
+        if result.is_none() {
+            result = Self::deal_auth(req, s, l).await;
+        }
+        match result? {
+            Ok(res) => Some(res),
+            Err(e) => {
+                log::warn!("WebSocket认证发生错误: {:?}", e);
+                Some(Response::status500().body("auth error").unwrap().into_type())
             }
         }
+    }
+
+    /// 给响应加上跨域的头
+    fn deal_cors(req: &Request<Body>, comm: &CommonConfig, res: Response<Body>) -> Response<Body> {
+        match &comm.cors {
+            Some(cors) => cors.deal_response(req, res),
+            None => res,
+        }
+    }
+
+    /// 按error_page处理错误, 上游返回的错误需开启proxy_intercept_errors才处理
+    async fn deal_error_page(
+        req: &mut Request<Body>,
+        server: Arc<ServerConfig>,
+        comm: &CommonConfig,
+        result: ProtResult<Response<Body>>,
+        from_upstream: bool,
+    ) -> ProtResult<Response<Body>> {
+        // gRPC的错误需转成grpc-status, 已处理过错误页的不再处理, 防止循环
+        if comm.error_page.is_empty()
+            || GrpcHelper::is_grpc(req)
+            || req.headers().system_get("{error_page}").is_some()
+        {
+            return result;
+        }
+        let status = match &result {
+            Ok(_) if from_upstream && !comm.proxy_intercept_errors.unwrap_or(false) => {
+                return result
+            }
+            Ok(res) => res.status().as_u16(),
+            Err(e) => Self::error_status(e),
+        };
+        let page = match ErrorPage::find(&comm.error_page, status) {
+            Some(page) => page,
+            None => return result,
+        };
+        if let Err(e) = &result {
+            log::trace!("处理HTTP服务发生错误: {:?}, 返回错误页", e);
+        }
+        req.headers_mut()
+            .system_insert("{error_page}".to_string(), status.to_string());
+        if let Some(res) = page.build_response(req, status).await? {
+            return Ok(res);
+        }
+        if let ErrorPageTarget::Internal(path) = &page.target {
+            if req.method() != &Method::Head {
+                req.set_method(Method::Get);
+            }
+            Helper::set_req_path(req, path.clone());
+            let mut res = Self::deal_match_location(
+                req,
+                server,
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+                &mut HashSet::new(),
+            )
+            .await?;
+            let code = page.get_status(status, res.status().as_u16());
+            *res.status_mut() = StatusCode::from_u16(code)
+                .map_err(|_| ProtError::Extension("error page status error"))?;
+            return Ok(res);
+        }
+        result
+    }
 
-        return Ok(Response::status404()
-            .body("unknow location to deal")
-            .unwrap()
-            .into_type());
+    /// 处理出错时返回的状态码
+    fn error_status(e: &ProtError) -> u16 {
+        let (is_timeout, is_client) = e.is_read_timeout();
+        if is_timeout && !is_client {
+            408
+        } else {
+            500
+        }
     }
 
     async fn inner_operate_by_http(
         req: &mut Request<Body>,
-        cache: &mut HashMap<
-            LocationConfig,
-            (Sender<Request<Body>>, Receiver<ProtResult<Response<Body>>>),
-        >,
         servers: Vec<Arc<ServerConfig>>,
     ) -> ProtResult<Response<Body>> {
-        let server_len = servers.len();
-        let host = req.get_host().unwrap_or(String::new());
-        // 不管有没有匹配, 都执行最后一个
-        for (index, s) in servers.iter().enumerate() {
-            if s.up_name == host || host.is_empty() || index == server_len - 1 {
-                return Self::deal_match_location(
-                    req,
-                    cache,
-                    s.clone(),
-                    &mut HashSet::new(),
-                    &mut HashSet::new(),
-                )
-                .await;
+        let host = req.get_host().unwrap_or_default();
+        let (s, m) = match ReverseHelper::get_server_by_host(&servers, &host) {
+            Some(v) => v,
+            None => {
+                return Ok(Response::status503()
+                    .body("unknow location")
+                    .unwrap()
+                    .into_type())
+            }
+        };
+        req.headers_mut()
+            .system_insert("{server_name}".to_string(), s.get_server_name());
+        s.comm.set_remote_ip(req);
+        for (name, value) in m.map(|m| m.captures).unwrap_or_default() {
+            req.headers_mut()
+                .system_insert(ServerName::capture_key(&name), value);
+        }
+        if let RewriteResult::Response(res) = RewriteConfig::deal_request(&s.rewrite, req) {
+            return Ok(*res);
+        }
+        if let Some(jwt) = &s.comm.jwt {
+            let path = req.path().clone();
+            let comm = match s.location.iter().find(|l| l.is_match_rule(&path, req)) {
+                Some(l) => &l.comm,
+                None => &s.comm,
+            };
+            // 跨域的预检请求不需认证, 直接返回
+            if let Some(cors) = &comm.cors {
+                if let Some(res) = cors.deal_preflight(req) {
+                    return Ok(res);
+                }
+            }
+            if let Some(res) = jwt.deal_request(req).await? {
+                let result = Self::deal_error_page(req, s.clone(), &s.comm, Ok(res), false).await;
+                return result.map(|res| Self::deal_cors(req, comm, res));
             }
         }
-        return Ok(Response::status503()
-            .body("unknow location")
-            .unwrap()
-            .into_type());
+        Self::deal_match_location(
+            req,
+            s.clone(),
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+            &mut HashSet::new(),
+        )
+        .await
     }
 
     async fn inner_operate(
@@ -422,7 +616,7 @@ impl HttpConfig {
         data: &mut InnerHttpOper,
     ) -> ProtResult<Response<Body>> {
         let servers = data.servers.clone();
-        return Self::inner_operate_by_http(req, &mut data.cache_sender, servers).await;
+        return Self::inner_operate_by_http(req, servers).await;
     }
 
     async fn operate(
@@ -430,15 +624,26 @@ impl HttpConfig {
         data: &mut InnerHttpOper,
     ) -> ProtResult<Response<Body>> {
         // body的内容可能重新解密又再重新再加过密, 后续可考虑直接做数据
+        let is_grpc = GrpcHelper::is_grpc(req);
         match Self::inner_operate(req, data).await {
+            // gRPC的客户端无法识别html的错误信息, 转成对应的gRPC状态
+            Ok(value) if is_grpc => {
+                let mut value = GrpcHelper::fix_response(value);
+                value.headers_mut().insert("server", "wmproxy");
+                Ok(value)
+            }
+            Err(e) if is_grpc => {
+                log::trace!("处理gRPC请求发生错误: {:?}", e);
+                let status = GrpcHelper::status_from_error(&e);
+                Ok(GrpcHelper::build_response(status, &format!("{:?}", e)))
+            }
             Ok(mut value) => {
                 value.headers_mut().insert("server", "wmproxy");
                 Ok(value)
             }
             Err(e) => {
                 log::trace!("处理HTTP服务发生错误: {:?}", e);
-                let (is_timeout, is_client) = e.is_read_timeout();
-                if is_timeout && !is_client {
+                if Self::error_status(&e) == 408 {
                     Ok(Response::text()
                         .status(408)
                         .body("operate timeout")?
@@ -502,3 +707,54 @@ impl HttpConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use webparse::{Method, Request};
+    use wenmeng::Body;
+
+    use crate::reverse::ServerConfig;
+
+    use super::HttpConfig;
+
+    #[tokio::test]
+    async fn do_test_server_jwt_cors() {
+        let server: ServerConfig = toml::from_str(
+            r#"
+            bind_addr = "127.0.0.1:80"
+            bind_ssl = ""
+            jwt = { secret = "wmproxy" }
+            [[location]]
+            rule = "/"
+            cors = { allow_origins = ["https://app.wmproxy.net"], allow_credentials = true }
+            "#,
+        )
+        .unwrap();
+        let servers = vec![Arc::new(server)];
+        let build_req = |method: Method| {
+            Request::builder()
+                .method(method)
+                .url("http://api.wmproxy.net/list")
+                .header("Origin", "https://app.wmproxy.net")
+                .header("Access-Control-Request-Method", "POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // 预检请求在server的JWT认证前返回
+        let mut req = build_req(Method::Options);
+        let res = HttpConfig::inner_operate_by_http(&mut req, servers.clone()).await.unwrap();
+        assert_eq!(res.status(), 204);
+
+        // 认证失败的响应带上跨域的头, 浏览器可读取401
+        let mut req = build_req(Method::Get);
+        let res = HttpConfig::inner_operate_by_http(&mut req, servers).await.unwrap();
+        assert_eq!(res.status(), 401);
+        assert_eq!(
+            res.headers().get_str_value(&"Access-Control-Allow-Origin").unwrap(),
+            "https://app.wmproxy.net"
+        );
+    }
+}
@@ -0,0 +1,344 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/14 09:36:12
+
+use std::{collections::HashMap, sync::RwLock, time::SystemTime};
+
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Header, Validation};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serde_with::serde_as;
+use webparse::{HeaderName, Request, Response};
+use wenmeng::{Body, ProtError, ProtResult};
+
+use crate::{ConfigDuration, DisplayFromStrOrNumber};
+
+lazy_static! {
+    /// 从文件加载的密钥, 文件修改后重新加载
+    static ref JWT_KEYS: RwLock<HashMap<String, (Option<SystemTime>, JwtKey)>> =
+        RwLock::new(HashMap::new());
+}
+
+#[derive(Clone)]
+enum JwtKey {
+    Single(DecodingKey),
+    Set(JwkSet),
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+/// JWT的校验, 从Authorization: Bearer中获取token
+/// 密钥可为secret, key_file(HS256为密钥内容, RS256/ES256为PEM公钥)或jwks_file
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtConfig {
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    pub secret: Option<String>,
+    pub key_file: Option<String>,
+    pub jwks_file: Option<String>,
+    /// 配置后校验iss
+    pub iss: Option<String>,
+    /// 配置后校验aud, 满足其一即可
+    #[serde(default = "Vec::new")]
+    pub aud: Vec<String>,
+    /// 校验exp及nbf时允许的时间误差
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub leeway: Option<ConfigDuration>,
+    /// 需满足的声明, 每项的值满足其一即可, 如 { role = ["admin", "ops"] }
+    #[serde(default = "HashMap::new")]
+    pub allow: HashMap<String, Vec<String>>,
+    /// 拒绝的声明, 任一项满足即返回403
+    #[serde(default = "HashMap::new")]
+    pub deny: HashMap<String, Vec<String>>,
+    /// 转发给上游的头及对应的声明, 如 { "X-User-Id" = "sub" }
+    #[serde(default = "HashMap::new")]
+    pub headers: HashMap<String, String>,
+}
+
+impl JwtConfig {
+    /// 声明在系统头中保存的名字, 可在日志及匹配中以{jwt(sub)}使用
+    pub fn claim_key(name: &str) -> String {
+        format!("{{jwt.{}}}", name)
+    }
+
+    /// 声明的值, 数组时取各项的值
+    fn claim_values(value: &Value) -> Vec<String> {
+        match value {
+            Value::String(s) => vec![s.clone()],
+            Value::Array(list) => list.iter().flat_map(Self::claim_values).collect(),
+            Value::Null => vec![],
+            v => vec![v.to_string()],
+        }
+    }
+
+    fn is_claim_match(claims: &serde_json::Map<String, Value>, name: &str, values: &[String]) -> bool {
+        match claims.get(name) {
+            Some(value) => Self::claim_values(value).iter().any(|v| values.contains(v)),
+            None => false,
+        }
+    }
+
+    async fn load_key(&self, file: &str, is_jwks: bool) -> ProtResult<JwtKey> {
+        let modified = tokio::fs::metadata(file).await?.modified().ok();
+        let name = format!("{:?}:{}", self.algorithm, file);
+        {
+            let keys = JWT_KEYS.read().unwrap();
+            if let Some((m, key)) = keys.get(&name) {
+                if modified.is_some() && *m == modified {
+                    return Ok(key.clone());
+                }
+            }
+        }
+        let data = tokio::fs::read(file).await?;
+        let key = if is_jwks {
+            JwtKey::Set(
+                serde_json::from_slice(&data)
+                    .map_err(|_| ProtError::Extension("jwks file format error"))?,
+            )
+        } else {
+            let key = match self.algorithm {
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                    Ok(DecodingKey::from_secret(String::from_utf8_lossy(&data).trim().as_bytes()))
+                }
+                Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(&data),
+                Algorithm::EdDSA => DecodingKey::from_ed_pem(&data),
+                _ => DecodingKey::from_rsa_pem(&data),
+            };
+            JwtKey::Single(key.map_err(|_| ProtError::Extension("jwt key file format error"))?)
+        };
+        log::trace!("加载JWT密钥文件{}", file);
+        JWT_KEYS
+            .write()
+            .unwrap()
+            .insert(name, (modified, key.clone()));
+        Ok(key)
+    }
+
+    /// 获取校验的密钥, jwks按kid查找, 未带kid时取第一个同算法的密钥
+    async fn get_key(&self, header: &Header) -> ProtResult<Option<DecodingKey>> {
+        if let Some(secret) = &self.secret {
+            return Ok(Some(DecodingKey::from_secret(secret.as_bytes())));
+        }
+        if let Some(file) = &self.key_file {
+            if let JwtKey::Single(key) = self.load_key(file, false).await? {
+                return Ok(Some(key));
+            }
+        }
+        if let Some(file) = &self.jwks_file {
+            if let JwtKey::Set(set) = self.load_key(file, true).await? {
+                let jwk = match &header.kid {
+                    Some(kid) => set.find(kid),
+                    None => set.keys.iter().find(|k| {
+                        k.common
+                            .key_algorithm
+                            .map(|a| format!("{:?}", a) == format!("{:?}", self.algorithm))
+                            .unwrap_or(true)
+                    }),
+                };
+                return Ok(jwk.and_then(|jwk| DecodingKey::from_jwk(jwk).ok()));
+            }
+        }
+        Err(ProtError::Extension("jwt key not config"))
+    }
+
+    fn get_token(req: &Request<Body>) -> Option<String> {
+        let value = req.headers().get_str_value(&HeaderName::AUTHORIZATION)?;
+        let (scheme, token) = value.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        Some(token.trim().to_string())
+    }
+
+    fn build_response(status: u16, error: &str) -> Response<Body> {
+        let mut builder = Response::builder().status(status);
+        if status == 401 {
+            builder = builder.header(
+                HeaderName::WWW_AUTHENTICATE,
+                format!("Bearer error=\"{}\"", error),
+            );
+        }
+        builder.body(error.to_string()).unwrap().into_type()
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::new(self.algorithm);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_ref().map(|l| l.0.as_secs()).unwrap_or(0);
+        if self.aud.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.aud);
+        }
+        if let Some(iss) = &self.iss {
+            validation.set_issuer(&[iss]);
+        }
+        validation
+    }
+
+    /// 校验通过返回None, 并将声明写入系统头及转发的头中, 否则返回401或403
+    pub async fn deal_request(&self, req: &mut Request<Body>) -> ProtResult<Option<Response<Body>>> {
+        // 防止客户端伪造转发给上游的头
+        for name in self.headers.keys() {
+            req.headers_mut().remove(name);
+        }
+        let token = match Self::get_token(req) {
+            Some(token) => token,
+            None => return Ok(Some(Self::build_response(401, "invalid_request"))),
+        };
+        let header = match jsonwebtoken::decode_header(&token) {
+            Ok(header) => header,
+            Err(_) => return Ok(Some(Self::build_response(401, "invalid_token"))),
+        };
+        let key = match self.get_key(&header).await? {
+            Some(key) => key,
+            None => return Ok(Some(Self::build_response(401, "invalid_token"))),
+        };
+        let data = match jsonwebtoken::decode::<serde_json::Map<String, Value>>(
+            &token,
+            &key,
+            &self.validation(),
+        ) {
+            Ok(data) => data,
+            Err(e) => {
+                log::trace!("JWT校验失败:{:?}", e);
+                return Ok(Some(Self::build_response(401, "invalid_token")));
+            }
+        };
+        let claims = data.claims;
+        for (name, values) in &self.allow {
+            if !Self::is_claim_match(&claims, name, values) {
+                return Ok(Some(Self::build_response(403, "insufficient_scope")));
+            }
+        }
+        for (name, values) in &self.deny {
+            if Self::is_claim_match(&claims, name, values) {
+                return Ok(Some(Self::build_response(403, "insufficient_scope")));
+            }
+        }
+        for (name, value) in &claims {
+            req.headers_mut()
+                .system_insert(Self::claim_key(name), Self::claim_values(value).join(","));
+        }
+        for (header, name) in &self.headers {
+            if let Some(value) = claims.get(name) {
+                req.headers_mut()
+                    .insert(header.clone(), Self::claim_values(value).join(","));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use webparse::{HeaderName, Request};
+    use wenmeng::Body;
+
+    use crate::{reverse::Matcher, Helper};
+
+    use super::JwtConfig;
+
+    fn build_req(token: &str) -> Request<Body> {
+        Request::builder()
+            .url("http://wmproxy.net/api")
+            .header(HeaderName::AUTHORIZATION, format!("Bearer {}", token))
+            .header("X-User-Id", "fake")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn build_token(claims: serde_json::Value, kid: Option<&str>) -> String {
+        let mut header = Header::default();
+        header.kid = kid.map(|k| k.to_string());
+        encode(&header, &claims, &EncodingKey::from_secret(b"wmproxy")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn do_test_jwt() {
+        let config: JwtConfig = toml::from_str(
+            r#"
+            secret = "wmproxy"
+            iss = "wmproxy.net"
+            aud = ["api"]
+            leeway = "5s"
+            allow = { role = ["admin", "ops"] }
+            deny = { sub = ["blocked"] }
+            headers = { "X-User-Id" = "sub" }
+            "#,
+        )
+        .unwrap();
+        let exp = get_current_timestamp() + 60;
+        let claims = serde_json::json!({"sub": "10", "iss": "wmproxy.net", "aud": "api", "exp": exp, "role": ["user", "admin"]});
+        let mut req = build_req(&build_token(claims.clone(), None));
+        assert!(config.deal_request(&mut req).await.unwrap().is_none());
+        assert_eq!(req.headers().get_str_value(&"X-User-Id").unwrap(), "10");
+        assert_eq!(
+            req.headers().system_get(&JwtConfig::claim_key("role")).unwrap(),
+            "user,admin"
+        );
+        assert_eq!(Helper::format_req(&req, "user:{jwt(sub)}"), "user:10");
+        let matcher: Matcher = toml::from_str("path = \"/api\"\njwt = { role = \"admin\" }").unwrap();
+        assert!(matcher.is_match_rule(&"/api".to_string(), &req).unwrap());
+        let matcher: Matcher = toml::from_str("jwt = { role = \"ops*\" }").unwrap();
+        assert!(!matcher.is_match_rule(&"/api".to_string(), &req).unwrap());
+
+        let mut req = build_req("bad.token");
+        let res = config.deal_request(&mut req).await.unwrap().unwrap();
+        assert_eq!(res.status(), 401);
+        assert!(req.headers().get_str_value(&"X-User-Id").is_none());
+
+        let mut expired = claims.clone();
+        expired["exp"] = (exp - 120).into();
+        let mut req = build_req(&build_token(expired, None));
+        assert_eq!(config.deal_request(&mut req).await.unwrap().unwrap().status(), 401);
+
+        let mut other_aud = claims.clone();
+        other_aud["aud"] = "web".into();
+        let mut req = build_req(&build_token(other_aud, None));
+        assert_eq!(config.deal_request(&mut req).await.unwrap().unwrap().status(), 401);
+
+        let mut user = claims.clone();
+        user["role"] = "user".into();
+        let mut req = build_req(&build_token(user, None));
+        assert_eq!(config.deal_request(&mut req).await.unwrap().unwrap().status(), 403);
+
+        let mut blocked = claims.clone();
+        blocked["sub"] = "blocked".into();
+        let mut req = build_req(&build_token(blocked, None));
+        assert_eq!(config.deal_request(&mut req).await.unwrap().unwrap().status(), 403);
+
+        // 从jwks中按kid查找密钥, d21wcm94eQ为wmproxy的base64url
+        let file = std::env::temp_dir().join("wmproxy_jwks_test.json");
+        std::fs::write(
+            &file,
+            r#"{"keys": [{"kty": "oct", "kid": "k1", "alg": "HS256", "k": "d21wcm94eQ"}]}"#,
+        )
+        .unwrap();
+        let mut config = config.clone();
+        config.secret = None;
+        config.allow = HashMap::new();
+        config.jwks_file = Some(file.to_string_lossy().to_string());
+        let mut req = build_req(&build_token(claims.clone(), Some("k1")));
+        assert!(config.deal_request(&mut req).await.unwrap().is_none());
+        let mut req = build_req(&build_token(claims, Some("k2")));
+        assert_eq!(config.deal_request(&mut req).await.unwrap().unwrap().status(), 401);
+        let _ = std::fs::remove_file(&file);
+    }
+}
@@ -10,17 +10,16 @@
 // -----
 // Created Date: 2023/10/18 02:31:52
 
-use std::{collections::HashMap, hash::Hash, net::SocketAddr};
+use std::{collections::HashMap, hash::Hash, net::SocketAddr, time::Instant};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
-use tokio::sync::mpsc::{Receiver, Sender};
 use webparse::{HeaderName, Request, Response, Scheme, Url};
-use wenmeng::{Body, Client, ProtError, ProtResult, RecvRequest};
+use wenmeng::{Body, ProtError, ProtResult, RecvRequest};
 
 use crate::{ConfigHeader, FileServer, HealthCheck, Helper, StaticResponse};
 
-use super::{common::CommonConfig, ReverseHelper, TryPathsConfig, UpstreamConfig, Matcher, string_or_struct};
+use super::{common::CommonConfig, BalanceKey, GrpcHelper, PeerStats, ProxyTlsConfig, RequestBody, ReverseHelper, RewriteConfig, TryPathsConfig, UpstreamConfig, UpstreamPool, UpstreamProtocol, Matcher, string_or_struct};
 
 /// 负载均衡中的location匹配，将匹配合适的处理逻辑
 #[serde_as]
@@ -30,7 +29,7 @@ pub struct LocationConfig {
     pub rule: Matcher,
     pub file_server: Option<FileServer>,
     
-    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, deserialize_with = "StaticResponse::string_or_struct")]
     pub static_response: Option<StaticResponse>,
 
     #[serde_as(as = "Vec<DisplayFromStr>")]
@@ -51,6 +50,11 @@ pub struct LocationConfig {
     #[serde_as(as = "Option<DisplayFromStr>")]
     pub try_paths: Option<TryPathsConfig>,
 
+    /// 按顺序执行的路径改写规则
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub rewrite: Vec<RewriteConfig>,
+
     #[serde(flatten)]
     #[serde(default = "CommonConfig::new")]
     pub comm: CommonConfig,
@@ -90,6 +94,7 @@ impl LocationConfig {
             root: None,
             upstream: vec![],
             try_paths: None,
+            rewrite: vec![],
             comm: CommonConfig::new(),
         }
     }
@@ -103,6 +108,7 @@ impl LocationConfig {
             static_response: None,
             headers: vec![],
             try_paths: None,
+            rewrite: vec![],
             root: None,
             upstream: vec![],
             comm: CommonConfig::new(),
@@ -118,92 +124,187 @@ impl LocationConfig {
         
     }
 
-    async fn deal_client(
+    async fn deal_proxy_addr(
+        &self,
         req: &mut Request<Body>,
-        client: Client,
-    ) -> ProtResult<(
-        Response<Body>,
-        Option<Sender<Request<Body>>>,
-        Option<Receiver<ProtResult<Response<Body>>>>,
-    )> {
-        println!("处理客户端!!!!");
-        let (mut recv, sender) = client.send2(req.replace_clone(Body::empty())).await?;
-        match recv.recv().await {
-            Some(res) => Ok((res?, Some(sender), Some(recv))),
-            None => Err(ProtError::Extension("already close by other")),
+        url: &Url,
+        tls: Option<&ProxyTlsConfig>,
+        protocol: UpstreamProtocol,
+    ) -> ProtResult<Response<Body>> {
+        if let Some(connect) = url.get_connect_url() {
+            req.headers_mut().insert(HeaderName::HOST, connect.clone());
         }
+        UpstreamPool::send(url, req, self.comm.build_proxy_timeout(), true, tls, protocol).await
+    }
+
+    /// 访问上游的TLS配置
+    pub fn get_proxy_tls(&self) -> Option<&ProxyTlsConfig> {
+        let name = self
+            .comm
+            .proxy_url
+            .as_ref()
+            .and_then(|r| r.domain.clone())
+            .unwrap_or_default();
+        ReverseHelper::get_proxy_tls(&self.upstream, &name, &self.comm)
     }
 
     pub async fn deal_reverse_proxy(
         &self,
         req: &mut Request<Body>,
         url: &Url,
-    ) -> ProtResult<(
-        Response<Body>,
-        Option<Sender<Request<Body>>>,
-        Option<Receiver<ProtResult<Response<Body>>>>,
-    )> {
+    ) -> ProtResult<Response<Body>> {
         let mut url = url.clone();
         let domain = url.domain.clone().unwrap();
-
-        if let Some(addr) = ReverseHelper::get_upstream_addr(&self.upstream, &*domain) {
-            url.domain = Some(addr.ip().to_string());
-            url.port = Some(addr.port());
-        }
         if url.scheme == Scheme::None {
             url.scheme = req.scheme().clone();
         }
-        if let Some(connect) = url.get_connect_url() {
-            req.headers_mut().insert(HeaderName::HOST, connect.clone());
-        }
-        let proxy_timeout = self.comm.build_proxy_timeout();
-
-        let mut connect_timeout = None;
-        if proxy_timeout.is_some() {
-            connect_timeout = proxy_timeout.as_ref().unwrap().connect_timeout.clone();
+        let upstream = ReverseHelper::get_upstream(&self.upstream, &domain);
+        let tls = ReverseHelper::get_proxy_tls(&self.upstream, &domain, &self.comm);
+        let mut protocol = ReverseHelper::get_proxy_protocol(&self.upstream, &domain, &self.comm);
+        if GrpcHelper::is_grpc(req) {
+            protocol = protocol.for_grpc(url.scheme.is_https());
         }
-        let stream = match url.get_connect_url() {
-            Some(connect) => HealthCheck::connect_timeout(&connect, connect_timeout).await?,
-            None => {
-                return Err(ProtError::Extension("get url error"));
+        let next = match &self.comm.proxy_next_upstream {
+            Some(next) if upstream.is_some() && !next.is_off() && next.can_retry_request(req) => {
+                Some(next)
             }
+            _ => None,
         };
-        let mut res = if url.scheme.is_http() {
-            let client = Client::builder()
-                .timeout_layer(proxy_timeout)
-                .connect_by_stream(stream)
-                .await?;
-            Self::deal_client(req, client).await?
-        } else {
-            let client = Client::builder()
-                .timeout_layer(proxy_timeout)
-                .url(url.clone())?
-                .connect_tls_by_stream(stream)
-                .await?;
-            Self::deal_client(req, client).await?
-        };
-        Helper::rewrite_response(&mut res.0, &self.headers);
-        Ok(res)
+        let max_tries = self.comm.proxy_next_upstream_tries.unwrap_or(0);
+        let start = Instant::now();
+        let mut tried = vec![];
+        // 重试时保留上游最后的返回, 无可重试的地址时返回该结果
+        let mut last_res: Option<Response<Body>> = None;
+        let mut last_err = None;
+        loop {
+            let mut guard = None;
+            let mut sticky = None;
+            if let Some(upstream) = upstream {
+                match upstream.get_server_addr_except(BalanceKey::Req(req), &tried) {
+                    Some(addr) => {
+                        url.domain = Some(addr.ip().to_string());
+                        url.port = Some(addr.port());
+                        guard = Some(PeerStats::begin(addr));
+                        sticky = upstream.sticky.as_ref().map(|s| (s, addr));
+                        tried.push(addr);
+                    }
+                    None if !tried.is_empty() => {
+                        if let Some(mut res) = last_res {
+                            Helper::rewrite_response(&mut res, &self.headers);
+                            return Ok(res);
+                        }
+                        return Err(last_err.unwrap_or(ProtError::Extension("no more upstream to try")));
+                    }
+                    None => {}
+                }
+            }
+            let can_next = next.is_some()
+                && (max_tries == 0 || tried.len() < max_tries)
+                && self
+                    .comm
+                    .proxy_next_upstream_timeout
+                    .as_ref()
+                    .map(|t| start.elapsed() < t.0)
+                    .unwrap_or(true);
+
+            // 重试时请求体已被消费, 需恢复缓冲的请求体
+            RequestBody::restore_buffered(req);
+            let result = self.deal_proxy_addr(req, &url, tls, protocol).await;
+            let addr = tried.last().cloned();
+            match result {
+                Ok(mut res) => {
+                    if let Some(guard) = &guard {
+                        guard.record_latency();
+                    }
+                    if let (Some(upstream), Some(addr)) = (upstream, addr) {
+                        upstream.report_status(addr, res.status().as_u16());
+                    }
+                    if can_next && next.unwrap().is_retry_status(res.status().as_u16()) {
+                        log::warn!("上游{:?}返回状态{}, 尝试下一个地址", addr, res.status());
+                        // 如404等客户端错误只重试, 不作为上游的失败
+                        if res.status().is_server_error() {
+                            HealthCheck::add_fall_down(addr.unwrap());
+                        }
+                        last_res = Some(res);
+                        continue;
+                    }
+                    if let Some((sticky, addr)) = sticky {
+                        sticky.set_cookie(req, &mut res, &addr);
+                    }
+                    // 响应体发送完之前仍计为活跃连接, 用于least_conn及max_conns
+                    if let Some(guard) = guard {
+                        res.extensions_mut().insert(guard);
+                    }
+                    Helper::rewrite_response(&mut res, &self.headers);
+                    return Ok(res);
+                }
+                Err(e) => {
+                    let (retry, is_timeout) = match next {
+                        Some(next) => next.is_retry_error(&e),
+                        None => (false, false),
+                    };
+                    if let (Some(upstream), Some(addr)) = (upstream, addr) {
+                        upstream.report_error(addr);
+                    }
+                    // 连接失败已在HealthCheck::connect中记录
+                    if is_timeout {
+                        if let Some(addr) = addr {
+                            HealthCheck::add_fall_down(addr);
+                        }
+                    }
+                    if !can_next || !retry {
+                        if let Some(mut res) = last_res {
+                            Helper::rewrite_response(&mut res, &self.headers);
+                            return Ok(res);
+                        }
+                        return Err(e);
+                    }
+                    log::warn!("访问上游{:?}失败:{:?}, 尝试下一个地址", addr, e);
+                    last_err = Some(e);
+                }
+            }
+        }
     }
 
     pub async fn deal_request(
         &self,
         req: &mut Request<Body>,
-    ) -> ProtResult<(
-        Response<Body>,
-        Option<Sender<Request<Body>>>,
-        Option<Receiver<ProtResult<Response<Body>>>>,
-    )> {
-        Helper::log_acess(&self.comm.log_format, &self.comm.access_log, &req);
+    ) -> ProtResult<Response<Body>> {
+        let mut result = self.deal_location(req).await;
+        if let Some(compress) = &self.comm.compress {
+            result = result.map(|res| compress.deal_response(req, res));
+        }
+        // 处理完后再记录, 以便记录缓存状态等处理中的信息
+        Helper::log_acess(&self.comm.log_format, &self.comm.access_log, req);
+        result
+    }
+
+    async fn deal_location(
+        &self,
+        req: &mut Request<Body>,
+    ) -> ProtResult<Response<Body>> {
         if let Some(file_server) = &self.file_server {
             let res = file_server.deal_request(req).await?;
-            return Ok((res, None, None));
+            return Ok(res);
         }
         if let Some(static_reponse) = &self.static_response {
             let res = static_reponse.deal_request(req).await?;
-            return Ok((res, None, None));
+            return Ok(res);
         }
         if let Some(reverse) = &self.comm.proxy_url {
+            // gRPC按grpc-timeout限制整个代理的时长, 包括流式返回的响应体, grpc-timeout头原样转发
+            if let Some(timeout) = GrpcHelper::get_timeout(req) {
+                let deadline = tokio::time::Instant::now() + timeout;
+                let mut res = match tokio::time::timeout_at(deadline, self.deal_reverse_proxy(req, reverse)).await {
+                    Ok(res) => res?,
+                    Err(_) => return Err(ProtError::time_timeout("grpc deadline exceeded")),
+                };
+                GrpcHelper::limit_body(&mut res, deadline);
+                return Ok(res);
+            }
+            if let Some(cache) = &self.comm.proxy_cache {
+                return cache.deal_request(self, req, reverse).await;
+            }
             return self.deal_reverse_proxy(req, reverse).await;
         }
         return Err(ProtError::Extension("unknow data"));
@@ -213,23 +314,23 @@ impl LocationConfig {
         self.comm.get_log_names(names);
     }
 
-    pub fn get_upstream_addr(&self) -> Option<SocketAddr> {
+    pub fn get_upstream_addr(&self, key: BalanceKey) -> Option<SocketAddr> {
         let mut name = String::new();
         if let Some(r) = &self.comm.proxy_url {
             name = r.domain.clone().unwrap_or(String::new());
         }
         for stream in &self.upstream {
             if stream.name == name {
-                return stream.get_server_addr();
+                return stream.get_server_addr(key);
             } else if name == "" {
-                return stream.get_server_addr();
+                return stream.get_server_addr(key);
             }
         }
         return None;
     }
 
-    pub fn get_reverse_url(&self) -> ProtResult<(Url, String)> {
-        if let Some(addr) = self.get_upstream_addr() {
+    pub fn get_reverse_url(&self, key: BalanceKey) -> ProtResult<(Url, String)> {
+        if let Some(addr) = self.get_upstream_addr(key) {
             if let Some(r) = &self.comm.proxy_url {
                 let mut url = r.clone();
                 let domain = url.domain.clone().unwrap_or(String::new());
@@ -246,3 +347,105 @@ impl LocationConfig {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use webparse::{BinaryMut, Buf, Request, Url};
+    use wenmeng::Body;
+
+    use crate::{reverse::PeerStats, test_util::fake_upstream};
+
+    use super::LocationConfig;
+
+    /// 每个请求均返回固定数据的上游
+    async fn upstream(data: &'static [u8]) -> String {
+        fake_upstream(|_| data.to_vec()).await.0.to_string()
+    }
+
+    async fn bad_gateway() -> String {
+        upstream(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 3\r\n\r\nbad").await
+    }
+
+    #[tokio::test]
+    async fn do_test_next_upstream_last_response() {
+        let (first, second) = (bad_gateway().await, bad_gateway().await);
+        let location: LocationConfig = toml::from_str(&format!(
+            r#"
+            rule = "/"
+            proxy_next_upstream = "error timeout http_502"
+            [[upstream]]
+            name = "up"
+            server = [{{ addr = "{}" }}, {{ addr = "{}" }}]
+            "#,
+            first, second
+        ))
+        .unwrap();
+        for upstream in &location.upstream {
+            upstream.resolve().await;
+        }
+        let url = Url::parse("http://up/".to_string().into_bytes()).unwrap();
+        let mut req = Request::builder().url(url.clone()).body(Body::empty()).unwrap();
+        // 所有地址均返回502时返回上游最后的结果
+        let mut res = location.deal_reverse_proxy(&mut req, &url).await.unwrap();
+        assert_eq!(res.status(), 502);
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await.unwrap();
+        assert_eq!(data.chunk(), b"bad");
+    }
+
+    #[tokio::test]
+    async fn do_test_peer_active_until_body_end() {
+        // 响应体未发送完
+        let addr = upstream(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n").await;
+        let location: LocationConfig = toml::from_str(&format!(
+            r#"
+            rule = "/"
+            [[upstream]]
+            name = "up"
+            server = [{{ addr = "{}" }}]
+            "#,
+            addr
+        ))
+        .unwrap();
+        for upstream in &location.upstream {
+            upstream.resolve().await;
+        }
+        let addr: SocketAddr = addr.parse().unwrap();
+        let url = Url::parse("http://up/".to_string().into_bytes()).unwrap();
+        let mut req = Request::builder().url(url.clone()).body(Body::empty()).unwrap();
+        let res = location.deal_reverse_proxy(&mut req, &url).await.unwrap();
+        assert_eq!(PeerStats::active(&addr), 1);
+        drop(res);
+        assert_eq!(PeerStats::active(&addr), 0);
+    }
+
+    #[tokio::test]
+    async fn do_test_max_conns_streaming() {
+        let first = upstream(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\n").await;
+        let backup = upstream(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\nb").await;
+        let location: LocationConfig = toml::from_str(&format!(
+            r#"
+            rule = "/"
+            [[upstream]]
+            name = "up"
+            server = [{{ addr = "{}", max_conns = 1 }}, {{ addr = "{}", backup = true }}]
+            "#,
+            first, backup
+        ))
+        .unwrap();
+        for upstream in &location.upstream {
+            upstream.resolve().await;
+        }
+        let url = Url::parse("http://up/".to_string().into_bytes()).unwrap();
+        let mut req = Request::builder().url(url.clone()).body(Body::empty()).unwrap();
+        let _streaming = location.deal_reverse_proxy(&mut req, &url).await.unwrap();
+        // 流式的响应未结束时仍占用连接数, 请求转到备用地址
+        let mut req = Request::builder().url(url.clone()).body(Body::empty()).unwrap();
+        let mut res = location.deal_reverse_proxy(&mut req, &url).await.unwrap();
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await.unwrap();
+        assert_eq!(data.chunk(), b"b");
+    }
+}
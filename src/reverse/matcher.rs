@@ -11,7 +11,7 @@
 // Created Date: 2024/01/24 03:12:37
 
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt::{self, Display},
     str::FromStr, net::IpAddr,
 };
@@ -19,18 +19,104 @@ use std::{
 use serde::{
     Deserialize, Serialize,
 };
-use serde_with::{serde_as, DisplayFromStr};
-use webparse::{Method, Scheme, WebError};
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
+use regex::Regex;
+use webparse::{Method, Scheme, Url, WebError};
 use wenmeng::{RecvRequest, ProtResult, ProtError};
 
-use crate::{Helper, IpSets};
+use crate::{Helper, IpSets, ProxyError};
+
+use super::JwtConfig;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MatchMethod(pub HashSet<Method>);
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MatchScheme(pub HashSet<Scheme>);
 
+/// 头部, Cookie及参数值的匹配方式
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchValue {
+    /// 值完全相同, 以=开头时强制为该方式, 如"=*"
+    Exact(String),
+    /// 以*结尾, 匹配前缀
+    Prefix(String),
+    /// 以~开头, 按正则匹配
+    Regex(String),
+    /// 为*时, 存在即可
+    Present,
+    /// 为!时, 必须不存在
+    Absent,
+}
+
+impl MatchValue {
+    pub fn is_match(&self, value: Option<&str>) -> bool {
+        match (self, value) {
+            (MatchValue::Absent, v) => v.is_none(),
+            (_, None) => false,
+            (MatchValue::Present, _) => true,
+            (MatchValue::Exact(e), Some(v)) => e == v,
+            (MatchValue::Prefix(p), Some(v)) => v.starts_with(p),
+            (MatchValue::Regex(r), Some(v)) => match Helper::try_cache_regex(r) {
+                Some(re) => re.is_match(v),
+                None => false,
+            },
+        }
+    }
+}
+
+impl FromStr for MatchValue {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(v) = s.strip_prefix('=') {
+            return Ok(MatchValue::Exact(v.to_string()));
+        }
+        if let Some(r) = s.strip_prefix('~') {
+            Regex::new(r).map_err(|_| ProxyError::Extension("匹配的正则表达式错误"))?;
+            return Ok(MatchValue::Regex(r.to_string()));
+        }
+        match s {
+            "*" => Ok(MatchValue::Present),
+            "!" => Ok(MatchValue::Absent),
+            _ => match s.strip_suffix('*') {
+                Some(p) => Ok(MatchValue::Prefix(p.to_string())),
+                None => Ok(MatchValue::Exact(s.to_string())),
+            },
+        }
+    }
+}
+
+impl Display for MatchValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatchValue::Exact(v) => f.write_fmt(format_args!("={}", v)),
+            MatchValue::Prefix(p) => f.write_fmt(format_args!("{}*", p)),
+            MatchValue::Regex(r) => f.write_fmt(format_args!("~{}", r)),
+            MatchValue::Present => f.write_str("*"),
+            MatchValue::Absent => f.write_str("!"),
+        }
+    }
+}
+
+/// 路径规则的类型
+#[derive(Debug, PartialEq, Eq)]
+enum PathKind<'a> {
+    /// 以=开头, 路径完全相同
+    Exact(&'a str),
+    /// 前缀匹配, 以^~开头的为true, 优先于正则
+    Prefix(&'a str, bool),
+    /// 以~开头的正则
+    Regex(&'a str),
+    /// 含通配或正则字符, 先按通配再按正则匹配
+    Pattern(&'a str),
+    /// 未配置路径
+    Any,
+}
+
 /// location匹配，将根据该类的匹配信息进行是否匹配
+/// path以=开头为精确匹配, 以^~开头为优先于正则的前缀匹配, 以~开头为正则匹配
+/// 与nginx不同, 匹配的^~前缀总是优先于正则, 而不是仅在最长的匹配前缀为^~时才跳过正则,
+/// 如^~ /static与/static/img同时存在时, /static/img/a.png将由^~ /static处理
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Matcher {
@@ -44,6 +130,33 @@ pub struct Matcher {
     method: Option<MatchMethod>,
     #[serde_as(as = "Option<DisplayFromStr>")]
     scheme: Option<MatchScheme>,
+    /// 按JWT的声明匹配, 值可用*通配, 需在server中配置jwt
+    #[serde(default)]
+    jwt: HashMap<String, String>,
+    /// 按请求头匹配, 值可为精确值, 前缀如"v2*", 正则如"~^Mobile", 存在"*"或不存在"!"
+    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    #[serde(default)]
+    headers: HashMap<String, MatchValue>,
+    /// 按Cookie匹配, 值的格式同headers
+    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    #[serde(default)]
+    cookies: HashMap<String, MatchValue>,
+    /// 按请求参数匹配, 值的格式同headers
+    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    #[serde(default)]
+    query: HashMap<String, MatchValue>,
+    /// 需同时满足的条件, 可为@name引用match_names中的配置
+    #[serde_as(as = "Vec<PickFirst<(_, DisplayFromStr)>>")]
+    #[serde(default)]
+    all: Vec<Matcher>,
+    /// 满足其一即可的条件
+    #[serde_as(as = "Vec<PickFirst<(_, DisplayFromStr)>>")]
+    #[serde(default)]
+    any: Vec<Matcher>,
+    /// 均不能满足的条件
+    #[serde_as(as = "Vec<PickFirst<(_, DisplayFromStr)>>")]
+    #[serde(default)]
+    not: Vec<Matcher>,
 }
 
 impl Matcher {
@@ -65,6 +178,12 @@ impl Matcher {
 
     pub fn get_path(&self) -> String {
         if let Some(p) = &self.path {
+            let p = &p
+                .strip_prefix('=')
+                .or_else(|| p.strip_prefix("^~"))
+                .unwrap_or(p)
+                .trim_start()
+                .to_string();
             if p.contains("*") {
                 let v = p.replace("*", "");
                 if v.len() != 0 {
@@ -79,20 +198,33 @@ impl Matcher {
 
     /// 当本地限制方法时,优先匹配方法,在进行路径的匹配
     pub fn is_match_rule(&self, path: &String, req: &RecvRequest) -> ProtResult<bool>  {
-        if let Some(p) = &self.path {
-            let mut is_match = false;
-            if Helper::is_match(&path, p) {
-                is_match = true;
+        match self.path_kind() {
+            PathKind::Exact(e) => {
+                if path.split('?').next() != Some(e) {
+                    return Ok(false);
+                }
             }
-            if !is_match {
-                if let Some(re) = Helper::try_cache_regex(&p) {
-                    if !re.is_match(path) {
-                        return Ok(false);
-                    }
-                } else {
+            PathKind::Prefix(p, true) => {
+                if !path.starts_with(p) {
                     return Ok(false);
                 }
             }
+            PathKind::Regex(r) => {
+                match Helper::try_cache_regex(r) {
+                    Some(re) if re.is_match(path) => {}
+                    _ => return Ok(false),
+                }
+            }
+            PathKind::Any => {}
+            PathKind::Prefix(_, false) | PathKind::Pattern(_) => {
+                let p = self.path.as_ref().unwrap();
+                if !Helper::is_match(path, p) {
+                    match Helper::try_cache_regex(p) {
+                        Some(re) if re.is_match(path) => {}
+                        _ => return Ok(false),
+                    }
+                }
+            }
         }
 
         if let Some(m) = &self.method {
@@ -128,8 +260,206 @@ impl Matcher {
             }
         }
 
+        if let Some(r) = &self.remote_ip {
+            // 由server中的trusted_proxies确定, 未经过server处理时同client_ip
+            let ip = req
+                .headers()
+                .system_get("{remote_ip}")
+                .or(req.headers().system_get("{client_ip}"))
+                .and_then(|ip| ip.parse::<IpAddr>().ok());
+            match ip {
+                Some(ip) if r.contains(&ip) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        for (name, value) in &self.headers {
+            if !value.is_match(req.headers().get_str_value(name).as_deref()) {
+                return Ok(false);
+            }
+        }
+
+        if !self.cookies.is_empty() {
+            let cookie = req.headers().get_cookie();
+            for (name, value) in &self.cookies {
+                let v = cookie.as_ref().and_then(|c| Self::get_cookie_value(c, name));
+                if !value.is_match(v) {
+                    return Ok(false);
+                }
+            }
+        }
+
+        for (name, value) in &self.query {
+            let v = req
+                .url()
+                .query
+                .as_ref()
+                .and_then(|q| Self::get_query_value(q, name));
+            if !value.is_match(v.as_deref()) {
+                return Ok(false);
+            }
+        }
+
+        for (name, pattern) in &self.jwt {
+            let is_match = match req.headers().system_get(&JwtConfig::claim_key(name)) {
+                Some(value) => value.split(',').any(|v| {
+                    if pattern.contains('*') {
+                        Helper::is_match(v, pattern)
+                    } else {
+                        v == pattern
+                    }
+                }),
+                None => false,
+            };
+            if !is_match {
+                return Ok(false);
+            }
+        }
+
+        for m in &self.all {
+            if !m.is_match_rule(path, req)? {
+                return Ok(false);
+            }
+        }
+        if !self.any.is_empty() {
+            let mut is_match = false;
+            for m in &self.any {
+                if m.is_match_rule(path, req)? {
+                    is_match = true;
+                    break;
+                }
+            }
+            if !is_match {
+                return Ok(false);
+            }
+        }
+        for m in &self.not {
+            if m.is_match_rule(path, req)? {
+                return Ok(false);
+            }
+        }
+
         Ok(true)
     }
+
+    fn path_kind(&self) -> PathKind<'_> {
+        let p = match &self.path {
+            Some(p) => p.as_str(),
+            None => return PathKind::Any,
+        };
+        if let Some(e) = p.strip_prefix('=') {
+            return PathKind::Exact(e.trim_start());
+        }
+        if let Some(v) = p.strip_prefix("^~") {
+            return PathKind::Prefix(v.trim_start(), true);
+        }
+        if let Some(r) = p.strip_prefix('~') {
+            return PathKind::Regex(r.trim_start());
+        }
+        if p.starts_with('@') {
+            return PathKind::Pattern(p);
+        }
+        let v = p.strip_suffix('*').unwrap_or(p);
+        if v.contains(|c| "*^$()[]{}|+?\\".contains(c)) {
+            PathKind::Pattern(p)
+        } else {
+            PathKind::Prefix(v, false)
+        }
+    }
+
+    /// 匹配的优先级, 越小越优先: 精确匹配, ^~前缀, 正则及通配(按配置顺序), 普通前缀, 前缀中长的优先
+    pub fn get_priority(&self) -> (u8, usize) {
+        match self.path_kind() {
+            PathKind::Exact(_) => (0, 0),
+            PathKind::Prefix(p, true) => (1, usize::MAX - p.len()),
+            PathKind::Regex(_) | PathKind::Pattern(_) => (2, 0),
+            PathKind::Prefix(p, false) => (3, usize::MAX - p.len()),
+            PathKind::Any => (3, usize::MAX),
+        }
+    }
+
+    /// 除路径外是否还有其它的条件
+    fn has_condition(&self) -> bool {
+        self.client_ip.is_some()
+            || self.remote_ip.is_some()
+            || self.host.is_some()
+            || self.method.is_some()
+            || self.scheme.is_some()
+            || !self.jwt.is_empty()
+            || !self.headers.is_empty()
+            || !self.cookies.is_empty()
+            || !self.query.is_empty()
+            || !self.all.is_empty()
+            || !self.any.is_empty()
+            || !self.not.is_empty()
+    }
+
+    /// 优先级更高的other能匹配的请求是否包含了该规则能匹配的全部请求
+    pub fn is_shadowed_by(&self, other: &Matcher) -> bool {
+        if other.has_condition() {
+            return false;
+        }
+        match (other.path_kind(), self.path_kind()) {
+            (PathKind::Any, _) | (PathKind::Prefix("", _), _) => true,
+            (PathKind::Exact(o), PathKind::Exact(s)) => o == s,
+            (PathKind::Prefix(o, _), PathKind::Exact(s) | PathKind::Prefix(s, _)) => {
+                s.starts_with(o)
+            }
+            (PathKind::Regex(o), PathKind::Regex(s)) => o == s,
+            (PathKind::Pattern(o), PathKind::Pattern(s)) => o == s,
+            _ => false,
+        }
+    }
+
+    /// 将@name替换为match_names中的配置, 组合条件中的也一并替换
+    pub fn resolve_names(&mut self, names: &HashMap<String, Matcher>) {
+        self.resolve_names_depth(names, 0);
+    }
+
+    fn resolve_names_depth(&mut self, names: &HashMap<String, Matcher>, depth: usize) {
+        if depth > 16 {
+            log::error!("匹配名字的引用层级过深, 可能存在循环引用");
+            return;
+        }
+        if let Some(n) = self.get_match_name() {
+            match names.get(&n) {
+                Some(m) => {
+                    *self = m.clone();
+                    return self.resolve_names_depth(names, depth + 1);
+                }
+                None => {
+                    log::error!("配置匹配名字@{},但未找到相应的配置", n);
+                    return;
+                }
+            }
+        }
+        for m in self
+            .all
+            .iter_mut()
+            .chain(self.any.iter_mut())
+            .chain(self.not.iter_mut())
+        {
+            m.resolve_names_depth(names, depth + 1);
+        }
+    }
+
+    fn get_cookie_value<'a>(cookie: &'a str, name: &str) -> Option<&'a str> {
+        cookie.split(';').find_map(|item| match item.split_once('=') {
+            Some((k, v)) if k.trim() == name => Some(v.trim()),
+            _ => None,
+        })
+    }
+
+    fn get_query_value(query: &str, name: &str) -> Option<String> {
+        query.split('&').find_map(|item| {
+            let (k, v) = item.split_once('=').unwrap_or((item, ""));
+            if k == name {
+                Some(Url::url_decode(v).unwrap_or_else(|_| v.to_string()))
+            } else {
+                None
+            }
+        })
+    }
 }
 
 impl FromStr for MatchMethod {
@@ -187,6 +517,13 @@ impl Default for Matcher {
             host: Default::default(),
             method: Default::default(),
             scheme: Default::default(),
+            jwt: Default::default(),
+            headers: Default::default(),
+            cookies: Default::default(),
+            query: Default::default(),
+            all: Default::default(),
+            any: Default::default(),
+            not: Default::default(),
         }
     }
 }
@@ -202,6 +539,34 @@ impl FromStr for Matcher {
     }
 }
 
+/// 按键排序输出, 保证相同的规则输出一致
+fn write_map<V: Display>(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    map: &HashMap<String, V>,
+) -> fmt::Result {
+    let mut keys: Vec<&String> = map.keys().collect();
+    keys.sort();
+    for k in keys {
+        write!(f, " {}.{}={}", name, k, map[k])?;
+    }
+    Ok(())
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, name: &str, list: &[Matcher]) -> fmt::Result {
+    if list.is_empty() {
+        return Ok(());
+    }
+    write!(f, " {}(", name)?;
+    for (i, m) in list.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", m)?;
+    }
+    f.write_str(")")
+}
+
 impl Display for Matcher {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if let Some(p) = &self.path {
@@ -210,6 +575,186 @@ impl Display for Matcher {
         if let Some(p) = &self.host {
             f.write_str(&*p)?;
         }
-        Ok(())
+        // 除路径外的条件, 用于区分路径相同的规则
+        if let Some(ip) = &self.client_ip {
+            write!(f, " client_ip={}", ip)?;
+        }
+        if let Some(ip) = &self.remote_ip {
+            write!(f, " remote_ip={}", ip)?;
+        }
+        if let Some(m) = &self.method {
+            write!(f, " method={}", m)?;
+        }
+        if let Some(s) = &self.scheme {
+            write!(f, " scheme={}", s)?;
+        }
+        write_map(f, "jwt", &self.jwt)?;
+        write_map(f, "headers", &self.headers)?;
+        write_map(f, "cookies", &self.cookies)?;
+        write_map(f, "query", &self.query)?;
+        write_list(f, "all", &self.all)?;
+        write_list(f, "any", &self.any)?;
+        write_list(f, "not", &self.not)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use std::collections::HashMap;
+
+    use crate::{reverse::CommonConfig, IpSets};
+
+    use super::{MatchValue, Matcher};
+
+    fn build_req(url: &str, headers: Vec<(&str, &str)>) -> Request<Body> {
+        let mut builder = Request::builder().url(url);
+        for (k, v) in headers {
+            builder = builder.header(k.to_string(), v.to_string());
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        set_client_ip(&mut req, "10.0.0.1");
+        req
+    }
+
+    /// 按server中配置的trusted_proxies记录真实地址
+    fn set_client_ip(req: &mut Request<Body>, ip: &str) {
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), ip.to_string());
+        let mut comm = CommonConfig::new();
+        comm.trusted_proxies = Some(IpSets::from_str("10.0.0.0/8").unwrap());
+        comm.set_remote_ip(req);
+    }
+
+    #[test]
+    fn do_test_matcher_conditions() {
+        for v in ["=*", "v2*", "~^Mobile", "*", "!"] {
+            assert_eq!(MatchValue::from_str(v).unwrap().to_string(), v);
+        }
+        assert!(MatchValue::from_str("~(").is_err());
+        assert!(MatchValue::from_str("=*").unwrap().is_match(Some("*")));
+        assert!(!MatchValue::from_str("*").unwrap().is_match(None));
+        assert!(MatchValue::from_str("!").unwrap().is_match(None));
+
+        let matcher: Matcher = toml::from_str(
+            r#"
+            path = "/api"
+            remote_ip = "192.168.0.0/16"
+            headers = { "X-Version" = "v2*", "User-Agent" = "~Mobile", "X-Debug" = "!" }
+            cookies = { canary = "always" }
+            query = { name = "wm proxy", token = "*" }
+            "#,
+        )
+        .unwrap();
+        let path = "/api".to_string();
+        let headers = vec![
+            ("X-Version", "v2.1"),
+            ("User-Agent", "Mozilla/5.0 Mobile Safari"),
+            ("Cookie", "id=1; canary=always"),
+            ("X-Forwarded-For", "192.168.1.2, 10.0.0.1"),
+        ];
+        let url = "http://wmproxy.net/api?name=wm%20proxy&token";
+        let req = build_req(url, headers.clone());
+        assert!(matcher.is_match_rule(&path, &req).unwrap());
+
+        let mut other = headers.clone();
+        other.push(("X-Debug", "1"));
+        assert!(!matcher.is_match_rule(&path, &build_req(url, other)).unwrap());
+        let mut other = headers.clone();
+        other[0] = ("X-Version", "v1");
+        assert!(!matcher.is_match_rule(&path, &build_req(url, other)).unwrap());
+        let mut other = headers.clone();
+        other[2] = ("Cookie", "canary=never");
+        assert!(!matcher.is_match_rule(&path, &build_req(url, other)).unwrap());
+        let mut other = headers.clone();
+        other.pop();
+        assert!(!matcher.is_match_rule(&path, &build_req(url, other)).unwrap());
+        let req = build_req("http://wmproxy.net/api?name=wm", headers.clone());
+        assert!(!matcher.is_match_rule(&path, &req).unwrap());
+
+        // 非信任的代理转发的X-Forwarded-For不可信
+        let mut req = build_req(url, headers.clone());
+        set_client_ip(&mut req, "172.16.0.1");
+        assert!(!matcher.is_match_rule(&path, &req).unwrap());
+        let mut other = headers;
+        other[3] = ("X-Forwarded-For", "192.168.1.2, 172.16.0.1, 10.0.0.2");
+        assert!(!matcher.is_match_rule(&path, &build_req(url, other)).unwrap());
+    }
+
+    #[test]
+    fn do_test_matcher_combinators() {
+        let mut names = HashMap::new();
+        names.insert(
+            "mobile".to_string(),
+            toml::from_str::<Matcher>(r#"headers = { "User-Agent" = "~Mobile" }"#).unwrap(),
+        );
+        let mut matcher: Matcher = toml::from_str(
+            r#"
+            path = "/api"
+            any = ["@mobile", { cookies = { canary = "always" } }]
+            not = [{ client_ip = "10.0.0.0/8" }]
+            "#,
+        )
+        .unwrap();
+        matcher.resolve_names(&names);
+        let path = "/api/list".to_string();
+        let mut req = build_req("http://wmproxy.net/api/list", vec![("User-Agent", "Mobile")]);
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), "192.168.0.1".to_string());
+        assert!(matcher.is_match_rule(&path, &req).unwrap());
+        let mut req = build_req("http://wmproxy.net/api/list", vec![("Cookie", "canary=always")]);
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), "192.168.0.1".to_string());
+        assert!(matcher.is_match_rule(&path, &req).unwrap());
+        // client_ip为10.0.0.1
+        let req = build_req("http://wmproxy.net/api/list", vec![("User-Agent", "Mobile")]);
+        assert!(!matcher.is_match_rule(&path, &req).unwrap());
+        let mut req = build_req("http://wmproxy.net/api/list", vec![]);
+        req.headers_mut()
+            .system_insert("{client_ip}".to_string(), "192.168.0.1".to_string());
+        assert!(!matcher.is_match_rule(&path, &req).unwrap());
+    }
+
+    #[test]
+    fn do_test_matcher_priority() {
+        let exact = Matcher::from_str("= /login").unwrap();
+        let first = Matcher::from_str("^~ /static").unwrap();
+        let regex = Matcher::from_str("~ \\.(png|jpg)$").unwrap();
+        let glob = Matcher::from_str("*.css").unwrap();
+        let long = Matcher::from_str("/api/v2").unwrap();
+        let short = Matcher::from_str("/api*").unwrap();
+        let root = Matcher::from_str("/").unwrap();
+        let mut list = vec![&root, &short, &glob, &long, &regex, &first, &exact];
+        list.sort_by_key(|m| m.get_priority());
+        assert_eq!(list, vec![&exact, &first, &glob, &regex, &long, &short, &root]);
+
+        let req = build_req("http://wmproxy.net/login?id=1", vec![]);
+        assert!(exact.is_match_rule(&"/login?id=1".to_string(), &req).unwrap());
+        assert!(!exact.is_match_rule(&"/login/a".to_string(), &req).unwrap());
+        assert!(regex.is_match_rule(&"/a/b.png".to_string(), &req).unwrap());
+        assert!(!regex.is_match_rule(&"/a/b.gif".to_string(), &req).unwrap());
+        assert_eq!(exact.get_path(), "/login");
+        assert_eq!(first.get_path(), "/static");
+
+        assert!(long.is_shadowed_by(&short));
+        assert!(!short.is_shadowed_by(&long));
+        assert!(exact.is_shadowed_by(&root));
+        assert!(regex.is_shadowed_by(&Matcher::from_str("~ \\.(png|jpg)$").unwrap()));
+        assert!(!regex.is_shadowed_by(&first));
+        let cond: Matcher = toml::from_str("path = \"/\"\nmethod = \"GET\"").unwrap();
+        assert!(!exact.is_shadowed_by(&cond));
+
+        // 覆盖的提示中需包含路径外的条件
+        let api: Matcher = toml::from_str(
+            "path = \"/api\"\nheaders = { \"X-Version\" = \"v2*\", \"Accept\" = \"*\" }",
+        )
+        .unwrap();
+        assert!(api.is_shadowed_by(&Matcher::from_str("/api").unwrap()));
+        assert_eq!(format!("{}", api), "/api headers.Accept=* headers.X-Version=v2*");
+        assert_eq!(format!("{}", cond), "/ method=GET");
     }
 }
@@ -10,26 +10,58 @@
 // -----
 // Created Date: 2023/10/16 04:28:22
 
+mod auth;
+mod balance;
+mod cache;
 mod common;
+mod compress;
+mod cors;
+mod error_page;
+mod grpc;
 mod http;
+mod jwt;
 mod limit_req;
 mod location;
 mod matcher;
+mod next_upstream;
+mod outlier;
+mod pool;
+mod proxy_tls;
+mod req_body;
 mod reverse_helper;
+mod rewrite;
 mod server;
+mod server_name;
 mod stream;
+mod sticky;
 mod try_paths;
 mod upstream;
 mod ws;
 
+pub use auth::{AuthBasic, AuthRequest};
+pub use balance::{BalanceKey, PeerStats};
+pub use cache::{CacheData, CacheZone, ProxyCache};
 pub use common::CommonConfig;
+pub use compress::CompressConfig;
+pub use cors::CorsConfig;
+pub use error_page::{ErrorPage, ErrorPageTarget};
+pub use grpc::GrpcHelper;
 pub use http::HttpConfig;
+pub use jwt::JwtConfig;
 pub use limit_req::{LimitReq, LimitReqMiddleware};
 pub use location::LocationConfig;
 pub use matcher::Matcher;
+pub use next_upstream::NextUpstream;
+pub use outlier::OutlierConfig;
+pub use pool::{PoolConfig, UpstreamPool, UpstreamProtocol};
+pub use proxy_tls::ProxyTlsConfig;
+pub use req_body::RequestBody;
 pub use reverse_helper::ReverseHelper;
+pub use rewrite::{RewriteConfig, RewriteResult};
 pub use server::ServerConfig;
+pub use server_name::{ServerName, ServerNameMatch, ServerNameResolver};
 pub use stream::{StreamConfig, StreamUdp};
+pub use sticky::StickyConfig;
 pub use try_paths::TryPathsConfig;
 pub use upstream::UpstreamConfig;
 
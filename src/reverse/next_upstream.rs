@@ -0,0 +1,153 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/02 14:36:08
+
+use std::{fmt::Display, str::FromStr};
+
+use webparse::{Method, Request};
+use wenmeng::{Body, ProtError};
+
+use crate::{Helper, ProxyError};
+
+use super::RequestBody;
+
+/// 访问上游失败时切换到下一个地址的条件, 格式如 "error timeout http_502 http_503"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NextUpstream {
+    /// 连接失败时重试
+    pub error: bool,
+    /// 连接或者读取超时时重试
+    pub timeout: bool,
+    /// 上游返回这些状态码时重试
+    pub status: Vec<u16>,
+    /// 非幂等的请求如POST也允许重试
+    pub non_idempotent: bool,
+}
+
+impl NextUpstream {
+    pub fn is_off(&self) -> bool {
+        !self.error && !self.timeout && self.status.is_empty()
+    }
+
+    pub fn is_retry_status(&self, status: u16) -> bool {
+        self.status.contains(&status)
+    }
+
+    /// 判断错误是否满足重试的条件, 返回值中第二个表示是否为超时
+    pub fn is_retry_error(&self, err: &ProtError) -> (bool, bool) {
+        let is_timeout = match err {
+            ProtError::IoError(e) => e.kind() == std::io::ErrorKind::TimedOut,
+            _ => err.is_timeout().0,
+        };
+        if is_timeout {
+            (self.timeout, true)
+        } else {
+            (self.error, false)
+        }
+    }
+
+    /// 请求是否能重新发送, 带请求体的请求因已被消费不重试, 除非请求体已缓冲
+    /// h2及gRPC的请求体可不带长度及chunked, 按body是否结束判断
+    pub fn can_retry_request(&self, req: &Request<Body>) -> bool {
+        if !RequestBody::is_buffered(req) && !req.body().is_end() {
+            return false;
+        }
+        if self.non_idempotent {
+            return true;
+        }
+        !matches!(
+            req.method(),
+            Method::Post | Method::Patch | Method::Connect | Method::Extension(_)
+        )
+    }
+}
+
+impl FromStr for NextUpstream {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut next = NextUpstream::default();
+        for val in Helper::split_by_whitespace(s) {
+            match val {
+                "off" => return Ok(NextUpstream::default()),
+                "error" => next.error = true,
+                "timeout" => next.timeout = true,
+                "non_idempotent" => next.non_idempotent = true,
+                _ => {
+                    let code = val
+                        .strip_prefix("http_")
+                        .and_then(|v| v.parse::<u16>().ok())
+                        .ok_or(ProxyError::Extension("unknow next upstream condition"))?;
+                    next.status.push(code);
+                }
+            }
+        }
+        Ok(next)
+    }
+}
+
+impl Display for NextUpstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut vals = vec![];
+        if self.error {
+            vals.push("error".to_string());
+        }
+        if self.timeout {
+            vals.push("timeout".to_string());
+        }
+        for code in &self.status {
+            vals.push(format!("http_{}", code));
+        }
+        if self.non_idempotent {
+            vals.push("non_idempotent".to_string());
+        }
+        if vals.is_empty() {
+            vals.push("off".to_string());
+        }
+        f.write_str(&vals.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use tokio::sync::mpsc::channel;
+    use webparse::{BinaryMut, Request};
+    use wenmeng::Body;
+
+    use super::NextUpstream;
+
+    #[test]
+    fn do_test_next_upstream() {
+        let next = NextUpstream::from_str("error timeout http_502 http_503").unwrap();
+        assert!(next.error && next.timeout && !next.non_idempotent);
+        assert!(next.is_retry_status(502) && !next.is_retry_status(500));
+        assert_eq!(format!("{}", next), "error timeout http_502 http_503");
+        assert!(NextUpstream::from_str("off").unwrap().is_off());
+        assert!(NextUpstream::from_str("http_abc").is_err());
+    }
+
+    #[test]
+    fn do_test_can_retry_request() {
+        let next = NextUpstream::from_str("error timeout").unwrap();
+        let req = Request::builder().method("PUT").url("http://wmproxy.net/").body(Body::empty()).unwrap();
+        assert!(next.can_retry_request(&req));
+        // h2的请求体可不带长度也非chunked
+        let (_sender, receiver) = channel(1);
+        let req = Request::builder()
+            .method("PUT")
+            .url("http://wmproxy.net/")
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap();
+        assert!(!next.can_retry_request(&req));
+    }
+}
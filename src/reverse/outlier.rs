@@ -0,0 +1,257 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/06 15:21:37
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::{ConfigDuration, DisplayFromStrOrNumber, StatusRanges};
+
+fn default_consecutive_errors() -> usize {
+    5
+}
+
+fn default_window() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(10))
+}
+
+fn default_min_requests() -> usize {
+    10
+}
+
+fn default_base_ejection() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(30))
+}
+
+fn default_max_ejection() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(300))
+}
+
+fn default_max_ejection_percent() -> usize {
+    50
+}
+
+fn default_status() -> StatusRanges {
+    StatusRanges(vec![(500, 599)])
+}
+
+/// 异常地址的剔除配置, 根据返回的状态码及错误被动判断
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutlierConfig {
+    /// 连续失败达到该次数后剔除, 0表示不按连续失败剔除
+    #[serde(default = "default_consecutive_errors")]
+    pub consecutive_errors: usize,
+    /// 统计窗口内错误率达到该百分比后剔除, 0表示不按错误率剔除
+    #[serde(default)]
+    pub error_rate: usize,
+    /// 统计错误率的窗口时长
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_window")]
+    pub window: ConfigDuration,
+    /// 窗口内请求数达到该值才计算错误率
+    #[serde(default = "default_min_requests")]
+    pub min_requests: usize,
+    /// 首次剔除的时长, 每次再被剔除时翻倍
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_base_ejection")]
+    pub base_ejection: ConfigDuration,
+    /// 最长的剔除时长
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_max_ejection")]
+    pub max_ejection: ConfigDuration,
+    /// 同时被剔除的地址最多占比, 保证不会剔除全部地址
+    #[serde(default = "default_max_ejection_percent")]
+    pub max_ejection_percent: usize,
+    /// 认为失败的状态码
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default = "default_status")]
+    pub status: StatusRanges,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        Self {
+            consecutive_errors: default_consecutive_errors(),
+            error_rate: 0,
+            window: default_window(),
+            min_requests: default_min_requests(),
+            base_ejection: default_base_ejection(),
+            max_ejection: default_max_ejection(),
+            max_ejection_percent: default_max_ejection_percent(),
+            status: default_status(),
+        }
+    }
+}
+
+/// 单个地址的统计数据
+#[derive(Debug, Default)]
+struct PeerOutlier {
+    /// 当前连续失败的次数
+    consecutive: usize,
+    /// 当前窗口的开始时间
+    window_start: Option<Instant>,
+    /// 窗口内的请求数
+    total: usize,
+    /// 窗口内的失败数
+    errors: usize,
+    /// 剔除的截止时间
+    ejected_until: Option<Instant>,
+    /// 已连续被剔除的次数, 决定下次剔除的时长
+    eject_times: u32,
+}
+
+impl PeerOutlier {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.map(|until| now < until).unwrap_or(false)
+    }
+}
+
+/// 上游组内各地址的异常统计, 复制到子级时共享
+#[derive(Debug, Default)]
+pub(crate) struct OutlierDetector {
+    peers: HashMap<SocketAddr, PeerOutlier>,
+}
+
+impl OutlierDetector {
+    /// 当前处于剔除中的地址
+    pub fn ejected(&self, now: Instant) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, p)| p.is_ejected(now))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// 记录一次访问结果, total_peers为组内的地址总数, 返回是否因此次结果被剔除
+    pub fn report(
+        &mut self,
+        config: &OutlierConfig,
+        addr: SocketAddr,
+        success: bool,
+        total_peers: usize,
+        now: Instant,
+    ) -> bool {
+        let ejected = self.peers.values().filter(|p| p.is_ejected(now)).count();
+        let state = self.peers.entry(addr).or_default();
+        let expired = state
+            .window_start
+            .map(|start| now.duration_since(start) > config.window.0)
+            .unwrap_or(true);
+        if expired {
+            state.window_start = Some(now);
+            state.total = 0;
+            state.errors = 0;
+        }
+        state.total += 1;
+        if success {
+            state.consecutive = 0;
+            return false;
+        }
+        state.consecutive += 1;
+        state.errors += 1;
+        if state.is_ejected(now) {
+            return false;
+        }
+
+        let hit = (config.consecutive_errors > 0 && state.consecutive >= config.consecutive_errors)
+            || (config.error_rate > 0
+                && state.total >= config.min_requests
+                && state.errors * 100 >= config.error_rate * state.total);
+        if !hit {
+            return false;
+        }
+        if (ejected + 1) * 100 > config.max_ejection_percent * total_peers {
+            return false;
+        }
+        // 恢复后长时间未再被剔除, 剔除时长重新计算
+        if let Some(until) = state.ejected_until {
+            if now.duration_since(until) > config.max_ejection.0 {
+                state.eject_times = 0;
+            }
+        }
+        let time = config
+            .base_ejection
+            .0
+            .checked_mul(1 << state.eject_times.min(16))
+            .unwrap_or(config.max_ejection.0)
+            .min(config.max_ejection.0);
+        state.eject_times += 1;
+        state.ejected_until = Some(now + time);
+        state.consecutive = 0;
+        state.window_start = None;
+        log::warn!("上游地址{}异常, 剔除{:?}", addr, time);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{OutlierConfig, OutlierDetector};
+
+    #[test]
+    fn do_test_outlier() {
+        let config: OutlierConfig =
+            toml::from_str("consecutive_errors = 3\nbase_ejection = \"10s\"\nmax_ejection = \"25s\"")
+                .unwrap();
+        let a: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let mut detector = OutlierDetector::default();
+        let now = Instant::now();
+
+        // 连续失败达到次数后剔除, 成功会重置连续次数
+        assert!(!detector.report(&config, a, false, 2, now));
+        assert!(!detector.report(&config, a, true, 2, now));
+        assert!(!detector.report(&config, a, false, 2, now));
+        assert!(!detector.report(&config, a, false, 2, now));
+        assert!(detector.report(&config, a, false, 2, now));
+        assert_eq!(detector.ejected(now), vec![a]);
+        assert!(detector.ejected(now + Duration::from_secs(11)).is_empty());
+
+        // 已剔除一半, 不再剔除其它地址
+        for _ in 0..5 {
+            assert!(!detector.report(&config, b, false, 2, now));
+        }
+
+        // 再次被剔除时时长翻倍, 且不超过最大值
+        let later = now + Duration::from_secs(11);
+        for _ in 0..3 {
+            detector.report(&config, a, false, 2, later);
+        }
+        assert_eq!(detector.ejected(later + Duration::from_secs(19)), vec![a]);
+        let later = later + Duration::from_secs(21);
+        for _ in 0..3 {
+            detector.report(&config, a, false, 2, later);
+        }
+        assert_eq!(detector.ejected(later + Duration::from_secs(24)), vec![a]);
+        assert!(detector.ejected(later + Duration::from_secs(26)).is_empty());
+
+        // 按错误率剔除
+        let config: OutlierConfig =
+            toml::from_str("consecutive_errors = 0\nerror_rate = 50\nmin_requests = 4").unwrap();
+        let mut detector = OutlierDetector::default();
+        assert!(!detector.report(&config, a, true, 4, now));
+        assert!(!detector.report(&config, a, false, 4, now));
+        assert!(!detector.report(&config, a, true, 4, now));
+        assert!(detector.report(&config, a, false, 4, now));
+    }
+}
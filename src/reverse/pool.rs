@@ -0,0 +1,795 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/03 10:05:12
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Display,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use tokio::{
+    net::TcpStream,
+    sync::{
+        mpsc::{channel, error::TryRecvError, Receiver, Sender},
+        oneshot,
+    },
+};
+use webparse::{
+    http2::frame::StreamIdentifier, HeaderName, Method, Request, Response, Url, Version,
+};
+use wenmeng::{Body, Client, Middleware, ProtError, ProtResult, RecvRequest, RecvResponse, TimeoutLayer};
+
+use crate::{ConfigDuration, DisplayFromStrOrNumber, HealthCheck, ProxyError};
+
+use super::{ProxyTlsConfig, RequestBody};
+
+lazy_static! {
+    static ref UPSTREAM_POOL: Mutex<UpstreamPool> = Mutex::new(UpstreamPool::new());
+    /// 未配置proxy_tls时使用内置根证书的配置
+    static ref DEFAULT_TLS: ProxyTlsConfig = ProxyTlsConfig::default();
+}
+
+fn default_max_idle() -> usize {
+    32
+}
+
+fn default_idle_timeout() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(60))
+}
+
+fn default_max_requests() -> usize {
+    1000
+}
+
+fn default_max_streams() -> usize {
+    100
+}
+
+/// 访问上游时使用的协议
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    /// https按ALPN协商, http按原有方式尝试升级h2c
+    #[default]
+    Auto,
+    /// 只使用http/1.1
+    Http1,
+    /// 通过TLS的ALPN协商h2, 协商失败则报错
+    H2,
+    /// 明文直接使用http2, 即prior knowledge
+    H2c,
+}
+
+impl UpstreamProtocol {
+    /// 是否可能复用http2的连接
+    pub fn allow_h2(&self) -> bool {
+        *self != UpstreamProtocol::Http1
+    }
+
+    /// gRPC只能使用http2, 未指定时按是否TLS选择h2或h2c
+    pub fn for_grpc(&self, is_tls: bool) -> Self {
+        match self {
+            UpstreamProtocol::H2 | UpstreamProtocol::H2c => *self,
+            _ if is_tls => UpstreamProtocol::H2,
+            _ => UpstreamProtocol::H2c,
+        }
+    }
+}
+
+impl FromStr for UpstreamProtocol {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "auto" => Ok(UpstreamProtocol::Auto),
+            "http1" | "http/1.1" => Ok(UpstreamProtocol::Http1),
+            "h2" | "http2" => Ok(UpstreamProtocol::H2),
+            "h2c" => Ok(UpstreamProtocol::H2c),
+            _ => Err(ProxyError::Extension("unknow upstream protocol")),
+        }
+    }
+}
+
+impl Display for UpstreamProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpstreamProtocol::Auto => f.write_str("auto"),
+            UpstreamProtocol::Http1 => f.write_str("http1"),
+            UpstreamProtocol::H2 => f.write_str("h2"),
+            UpstreamProtocol::H2c => f.write_str("h2c"),
+        }
+    }
+}
+
+/// 上游连接池的配置
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// 每个上游地址最大的空闲连接数, 0表示不复用连接
+    #[serde(default = "default_max_idle")]
+    pub max_idle: usize,
+    /// 空闲连接的存活时长
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: ConfigDuration,
+    /// 单个连接最多处理的请求数, 0表示不限制
+    #[serde(default = "default_max_requests")]
+    pub max_requests: usize,
+    /// http2的单个连接上同时进行的请求数, 超出则新建连接
+    #[serde(default = "default_max_streams")]
+    pub max_streams: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: default_max_idle(),
+            idle_timeout: default_idle_timeout(),
+            max_requests: default_max_requests(),
+            max_streams: default_max_streams(),
+        }
+    }
+}
+
+/// 已建立的上游连接
+struct PooledConn {
+    sender: Sender<Request<Body>>,
+    receiver: Receiver<ProtResult<Response<Body>>>,
+    /// 已处理的请求数
+    requests: usize,
+    /// 放回连接池的时间
+    idle_since: Instant,
+}
+
+impl PooledConn {
+    fn is_usable(&mut self, config: &PoolConfig) -> bool {
+        if self.sender.is_closed() || self.idle_since.elapsed() > config.idle_timeout.0 {
+            return false;
+        }
+        // 空闲时收到的消息只可能是错误或者关闭
+        matches!(self.receiver.try_recv(), Err(TryRecvError::Empty))
+    }
+}
+
+/// 放在Response的extensions中, 保持连接存活直到Response被释放,
+/// 此时响应体已处理完毕, 带有key的连接放回连接池
+struct ConnGuard {
+    key: Option<String>,
+    conn: Option<PooledConn>,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        if let (Some(key), Some(conn)) = (self.key.take(), self.conn.take()) {
+            UpstreamPool::put(&key, conn);
+        }
+    }
+}
+
+type H2Reply = oneshot::Sender<ProtResult<Response<Body>>>;
+type H2Request = (Request<Body>, H2Reply);
+type H2Pending = Arc<Mutex<HashMap<u32, H2Reply>>>;
+
+/// 随请求传入客户端的回复通道
+struct H2Token(H2Reply);
+
+/// 客户端的最后一个中间件, 客户端在其之后立即为请求分配stream id且不会失败,
+/// 在此按分配的顺序登记回复通道, 之前失败的请求不会占用stream id
+struct H2Register {
+    next_id: StreamIdentifier,
+    pending: H2Pending,
+}
+
+#[async_trait]
+impl Middleware for H2Register {
+    async fn process_request(&mut self, req: &mut RecvRequest) -> ProtResult<Option<RecvResponse>> {
+        let token = req
+            .extensions_mut()
+            .remove::<H2Token>()
+            .ok_or(ProtError::Extension("miss h2 token"))?;
+        let id = self.next_id.next_id();
+        self.pending.lock().unwrap().insert(id.0, token.0);
+        Ok(None)
+    }
+
+    async fn process_response(&mut self, _req: &mut RecvRequest, _res: &mut RecvResponse) -> ProtResult<()> {
+        Ok(())
+    }
+}
+
+/// 多路复用的http2连接, 由单独的任务按stream id分发返回
+#[derive(Clone)]
+struct H2Conn {
+    sender: Sender<H2Request>,
+    /// 进行中的请求数, Response释放时减少
+    streams: Arc<AtomicUsize>,
+    /// 已处理的请求数
+    requests: usize,
+    /// 最后一次分配请求的时间
+    active_at: Instant,
+}
+
+impl H2Conn {
+    fn is_usable(&self, config: &PoolConfig) -> bool {
+        if self.sender.is_closed()
+            || (config.max_requests != 0 && self.requests >= config.max_requests)
+        {
+            return false;
+        }
+        self.streams.load(Ordering::Relaxed) > 0 || self.active_at.elapsed() <= config.idle_timeout.0
+    }
+
+    /// 启动分发任务, 首个请求随连接一起发出
+    async fn start(mut client: Client, mut req: Request<Body>) -> ProtResult<(Self, Response<Body>)> {
+        let pending: H2Pending = Arc::new(Mutex::new(HashMap::new()));
+        client.middle(H2Register {
+            next_id: StreamIdentifier::client_first(),
+            pending: pending.clone(),
+        });
+        let (first, wait) = oneshot::channel();
+        req.extensions_mut().insert(H2Token(first));
+        let (mut receiver, req_sender) = client.send2(req).await?;
+        let (sender, mut requests) = channel::<H2Request>(10);
+        tokio::spawn(async move {
+            let mut accepting = true;
+            loop {
+                tokio::select! {
+                    r = requests.recv(), if accepting => {
+                        match r {
+                            Some((mut req, reply)) => {
+                                req.extensions_mut().insert(H2Token(reply));
+                                if let Err(e) = req_sender.send(req).await {
+                                    let mut req = e.0;
+                                    if let Some(token) = req.extensions_mut().remove::<H2Token>() {
+                                        let _ = token.0.send(Err(ProtError::Extension("already close by other")));
+                                    }
+                                    break;
+                                }
+                            }
+                            None => {
+                                accepting = false;
+                                if pending.lock().unwrap().is_empty() {
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    r = receiver.recv() => {
+                        match r {
+                            Some(Ok(res)) => {
+                                // 无法对应到请求的响应说明连接状态已错乱, 关闭连接防止错发
+                                let id = match res.extensions().get::<StreamIdentifier>() {
+                                    Some(id) => id.0,
+                                    None => {
+                                        log::warn!("上游http2的响应缺少stream id");
+                                        break;
+                                    }
+                                };
+                                let reply = pending.lock().unwrap().remove(&id);
+                                match reply {
+                                    Some(reply) => {
+                                        let _ = reply.send(Ok(res));
+                                    }
+                                    None => {
+                                        log::warn!("上游http2的响应stream id {}未对应到请求", id);
+                                        break;
+                                    }
+                                }
+                                if !accepting && pending.lock().unwrap().is_empty() {
+                                    break;
+                                }
+                            }
+                            Some(Err(e)) => {
+                                log::warn!("上游http2连接出错:{:?}", e);
+                                break;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+            }
+            // 连接关闭时未返回的请求直接失败
+            for (_, reply) in pending.lock().unwrap().drain() {
+                let _ = reply.send(Err(ProtError::Extension("already close by other")));
+            }
+        });
+        let conn = H2Conn {
+            sender,
+            streams: Arc::new(AtomicUsize::new(1)),
+            requests: 1,
+            active_at: Instant::now(),
+        };
+        let res = Self::wait(&conn, wait).await?;
+        Ok((conn, res))
+    }
+
+    /// 通过该连接发送请求, 连接已关闭时返回原请求以便重试
+    async fn send(&self, req: Request<Body>) -> Result<ProtResult<Response<Body>>, Request<Body>> {
+        let (reply, wait) = oneshot::channel();
+        if let Err(e) = self.sender.send((req, reply)).await {
+            self.streams.fetch_sub(1, Ordering::Relaxed);
+            return Err(e.0 .0);
+        }
+        Ok(Self::wait(self, wait).await)
+    }
+
+    async fn wait(
+        &self,
+        wait: oneshot::Receiver<ProtResult<Response<Body>>>,
+    ) -> ProtResult<Response<Body>> {
+        let guard = H2Stream {
+            _sender: self.sender.clone(),
+            streams: self.streams.clone(),
+        };
+        match wait.await {
+            Ok(Ok(mut res)) => {
+                res.extensions_mut().insert(guard);
+                Ok(res)
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(ProtError::Extension("already close by other")),
+        }
+    }
+}
+
+/// 放在Response的extensions中, 保持http2连接存活直到Response被释放
+struct H2Stream {
+    _sender: Sender<H2Request>,
+    streams: Arc<AtomicUsize>,
+}
+
+impl Drop for H2Stream {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 进程内共享的上游连接池, 按协议及地址区分
+pub struct UpstreamPool {
+    config: PoolConfig,
+    idles: HashMap<String, Vec<PooledConn>>,
+    /// 可多路复用的http2连接, 不需要取出独占
+    h2s: HashMap<String, Vec<H2Conn>>,
+}
+
+impl UpstreamPool {
+    fn new() -> Self {
+        Self {
+            config: PoolConfig::default(),
+            idles: HashMap::new(),
+            h2s: HashMap::new(),
+        }
+    }
+
+    /// 重新加载配置时更新, 已有的空闲连接按新配置淘汰
+    pub fn set_config(config: PoolConfig) {
+        if let Ok(mut pool) = UPSTREAM_POOL.lock() {
+            pool.config = config;
+        }
+    }
+
+    /// TLS连接的key包含完整的TLS配置, 不同配置的连接不能互相复用
+    fn key(url: &Url, tls: Option<&ProxyTlsConfig>, protocol: UpstreamProtocol) -> Option<String> {
+        let mut key = format!("{}://{}", url.scheme, url.get_connect_url()?);
+        if !url.scheme.is_http() && !url.scheme.is_ws() {
+            let mut hasher = DefaultHasher::new();
+            tls.unwrap_or(&DEFAULT_TLS).hash(&mut hasher);
+            key += &format!("#{:x}", hasher.finish());
+        }
+        if protocol != UpstreamProtocol::Auto {
+            key += &format!("@{}", protocol);
+        }
+        Some(key)
+    }
+
+    /// 选取进行中请求最少且未满的http2连接
+    fn take_h2(key: &str) -> Option<H2Conn> {
+        let mut pool = UPSTREAM_POOL.lock().ok()?;
+        let config = pool.config.clone();
+        let list = pool.h2s.get_mut(key)?;
+        list.retain(|c| c.is_usable(&config));
+        let conn = list
+            .iter_mut()
+            .filter(|c| {
+                config.max_streams == 0 || c.streams.load(Ordering::Relaxed) < config.max_streams
+            })
+            .min_by_key(|c| c.streams.load(Ordering::Relaxed))?;
+        conn.streams.fetch_add(1, Ordering::Relaxed);
+        conn.requests += 1;
+        conn.active_at = Instant::now();
+        Some(conn.clone())
+    }
+
+    fn put_h2(key: String, conn: H2Conn) {
+        if let Ok(mut pool) = UPSTREAM_POOL.lock() {
+            if pool.config.max_idle == 0 {
+                return;
+            }
+            pool.h2s.entry(key).or_default().push(conn);
+        }
+    }
+
+    /// 转成http2的请求, 去掉逐跳的头信息
+    fn fix_h2_request(url: &Url, req: &mut Request<Body>) {
+        req.set_version(Version::Http2);
+        req.set_scheme(url.scheme.clone());
+        let headers = req.headers_mut();
+        headers.remove(&HeaderName::CONNECTION);
+        headers.remove(&HeaderName::TRANSFER_ENCODING);
+        headers.remove(&HeaderName::UPGRADE);
+        headers.remove(&"Keep-Alive");
+        headers.remove(&"Proxy-Connection");
+        // TE只允许保留trailers
+        if headers.is_contains(&HeaderName::TE, b"trailers") {
+            headers.insert(HeaderName::TE, "trailers");
+        } else {
+            headers.remove(&HeaderName::TE);
+        }
+    }
+
+    fn take(key: &str) -> Option<PooledConn> {
+        let mut pool = UPSTREAM_POOL.lock().ok()?;
+        let config = pool.config.clone();
+        let list = pool.idles.get_mut(key)?;
+        while let Some(mut conn) = list.pop() {
+            if conn.is_usable(&config) {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn put(key: &str, mut conn: PooledConn) {
+        if let Ok(mut pool) = UPSTREAM_POOL.lock() {
+            let config = pool.config.clone();
+            if config.max_requests != 0 && conn.requests >= config.max_requests {
+                return;
+            }
+            let list = pool.idles.entry(key.to_string()).or_default();
+            list.retain_mut(|c| c.is_usable(&config));
+            if list.len() >= config.max_idle {
+                return;
+            }
+            conn.idle_since = Instant::now();
+            list.push(conn);
+        }
+    }
+
+    /// Response释放后才将连接放回连接池, 响应体未读完即被释放的连接在复用时出错, 由send换用其它连接
+    fn attach(key: String, res: &mut Response<Body>, conn: PooledConn) {
+        let reusable = !res
+            .headers()
+            .is_contains(&HeaderName::CONNECTION, b"close")
+            && res.status() != 101;
+        if reusable && res.body().is_end() {
+            Self::put(&key, conn);
+            return;
+        }
+        res.extensions_mut().insert(ConnGuard {
+            key: reusable.then_some(key),
+            conn: Some(conn),
+        });
+    }
+
+    /// 与上游建立新的连接, passive为true时将记录被动健康检查且跳过已下线的地址
+    /// 配置了tls时按该配置进行握手, 否则使用内置的根证书
+    pub async fn connect(
+        url: &Url,
+        domain: Option<&str>,
+        timeout: Option<TimeoutLayer>,
+        passive: bool,
+        tls: Option<&ProxyTlsConfig>,
+        protocol: UpstreamProtocol,
+    ) -> ProtResult<Client> {
+        Ok(Self::connect_inner(url, domain, timeout, passive, tls, protocol).await?.0)
+    }
+
+    /// 建立连接, 返回值中第二个表示是否为http2连接
+    async fn connect_inner(
+        url: &Url,
+        domain: Option<&str>,
+        timeout: Option<TimeoutLayer>,
+        passive: bool,
+        tls: Option<&ProxyTlsConfig>,
+        protocol: UpstreamProtocol,
+    ) -> ProtResult<(Client, bool)> {
+        let connect = url
+            .get_connect_url()
+            .ok_or(ProtError::Extension("get url error"))?;
+        let connect_timeout = timeout.as_ref().and_then(|t| t.connect_timeout);
+        let stream = if passive {
+            HealthCheck::connect_timeout(&connect, connect_timeout).await?
+        } else {
+            match connect_timeout {
+                Some(t) => match tokio::time::timeout(t, TcpStream::connect(&connect)).await {
+                    Ok(s) => s?,
+                    Err(_) => return Err(ProtError::connect_timeout("client")),
+                },
+                None => TcpStream::connect(&connect).await?,
+            }
+        };
+        if url.scheme.is_http() || url.scheme.is_ws() {
+            let builder = Client::builder().timeout_layer(timeout).url(url.clone())?;
+            let is_h2 = url.scheme.is_http()
+                && matches!(protocol, UpstreamProtocol::H2 | UpstreamProtocol::H2c);
+            let builder = match protocol {
+                _ if is_h2 => builder.http2_only(true),
+                UpstreamProtocol::Auto => builder,
+                _ => builder.http2(false),
+            };
+            return Ok((builder.connect_by_stream(stream).await?, is_h2));
+        }
+        let tls = tls.unwrap_or(&DEFAULT_TLS);
+        let alpn = !url.scheme.is_wss() && protocol.allow_h2();
+        let (client, is_h2) = tls
+            .connect_client(url, timeout, stream, domain.unwrap_or(""), alpn)
+            .await?;
+        if !is_h2 && protocol == UpstreamProtocol::H2 {
+            return Err(ProtError::Extension("upstream not support h2"));
+        }
+        Ok((client, is_h2))
+    }
+
+    /// 发送请求, 优先复用连接池中的连接, 返回的Response释放后连接自动放回连接池
+    /// http2的连接可同时发送多个请求
+    pub async fn send(
+        url: &Url,
+        req: &mut Request<Body>,
+        timeout: Option<TimeoutLayer>,
+        passive: bool,
+        tls: Option<&ProxyTlsConfig>,
+        protocol: UpstreamProtocol,
+    ) -> ProtResult<Response<Body>> {
+        let key = Self::key(url, tls, protocol).ok_or(ProtError::Extension("get url error"))?;
+        let version = req.version();
+        if protocol.allow_h2() {
+            while let Some(conn) = Self::take_h2(&key) {
+                let mut h2_req = req.replace_clone(Body::empty());
+                Self::fix_h2_request(url, &mut h2_req);
+                match conn.send(h2_req).await {
+                    Ok(res) => {
+                        log::trace!("复用上游http2连接{}发送请求", key);
+                        let mut res = res?;
+                        *res.version_mut() = version;
+                        return Ok(res);
+                    }
+                    Err(mut origin) => {
+                        std::mem::swap(req.body_mut(), origin.body_mut());
+                    }
+                }
+            }
+        }
+        // 复用的连接可能已被上游关闭或有未读完的响应体, 未收到响应时可重发的请求换用其它连接
+        let resend = RequestBody::is_buffered(req)
+            || (req.body().is_end()
+                && matches!(req.method(), Method::Get | Method::Head | Method::Options));
+        while let Some(mut conn) = Self::take(&key) {
+            match conn.sender.send(req.replace_clone(Body::empty())).await {
+                Ok(()) => {
+                    log::trace!("复用上游连接{}发送请求", key);
+                    let err = match conn.receiver.recv().await {
+                        Some(Ok(mut res)) => {
+                            conn.requests += 1;
+                            Self::attach(key, &mut res, conn);
+                            return Ok(res);
+                        }
+                        Some(Err(e)) => e,
+                        None => ProtError::Extension("already close by other"),
+                    };
+                    if !resend {
+                        return Err(err);
+                    }
+                    log::trace!("复用上游连接{}失败:{:?}, 重新发送请求", key, err);
+                    RequestBody::restore_buffered(req);
+                }
+                // 连接已关闭, 请求未发出, 取回请求体后尝试下一个连接
+                Err(e) => {
+                    let mut origin = e.0;
+                    std::mem::swap(req.body_mut(), origin.body_mut());
+                }
+            }
+        }
+
+        let (client, is_h2) =
+            Self::connect_inner(url, None, timeout, passive, tls, protocol).await?;
+        if is_h2 {
+            let mut h2_req = req.replace_clone(Body::empty());
+            Self::fix_h2_request(url, &mut h2_req);
+            let (conn, mut res) = H2Conn::start(client, h2_req).await?;
+            Self::put_h2(key, conn);
+            *res.version_mut() = version;
+            return Ok(res);
+        }
+        let (mut receiver, sender) = client.send2(req.replace_clone(Body::empty())).await?;
+        match receiver.recv().await {
+            Some(Ok(mut res)) => {
+                let conn = PooledConn {
+                    sender,
+                    receiver,
+                    requests: 1,
+                    idle_since: Instant::now(),
+                };
+                Self::attach(key, &mut res, conn);
+                Ok(res)
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(ProtError::Extension("already close by other")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use async_trait::async_trait;
+    use tokio::{net::TcpListener, sync::oneshot};
+    use webparse::{http2::frame::StreamIdentifier, BinaryMut, Request, Response, Url};
+    use wenmeng::{Body, HttpTrait, Middleware, ProtResult, RecvRequest, RecvResponse, Server};
+
+    use crate::{reverse::ProxyTlsConfig, test_util::fake_upstream};
+
+    use super::{H2Register, H2Token, UpstreamPool, UpstreamProtocol};
+
+    struct EchoPath;
+
+    #[async_trait]
+    impl HttpTrait for EchoPath {
+        async fn operate(&mut self, req: &mut RecvRequest) -> ProtResult<RecvResponse> {
+            Ok(Response::builder()
+                .body(req.path().clone())
+                .unwrap()
+                .into_type())
+        }
+    }
+
+    #[tokio::test]
+    async fn do_test_reuse() {
+        let (addr, accepts) =
+            fake_upstream(|_| b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec()).await;
+
+        let url = Url::parse(format!("http://{}/", addr).into_bytes()).unwrap();
+        for _ in 0..3 {
+            let mut req = Request::builder()
+                .url(url.clone())
+                .body(Body::empty())
+                .unwrap();
+            let mut res = UpstreamPool::send(&url, &mut req, None, false, None, UpstreamProtocol::Auto)
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+            res.body_mut().wait_all().await;
+        }
+        assert_eq!(accepts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn do_test_key() {
+        let url = Url::parse("https://wmproxy.net/".to_string().into_bytes()).unwrap();
+        let key = |tls: &ProxyTlsConfig| UpstreamPool::key(&url, Some(tls), UpstreamProtocol::Auto);
+        let verify = ProxyTlsConfig::default();
+        let mut no_verify = ProxyTlsConfig::default();
+        no_verify.verify = false;
+        let mut client_cert = ProxyTlsConfig::default();
+        client_cert.cert = Some("client.pem".to_string());
+        assert_eq!(key(&verify), UpstreamPool::key(&url, None, UpstreamProtocol::Auto));
+        assert_ne!(key(&verify), key(&no_verify));
+        assert_ne!(key(&verify), key(&client_cert));
+    }
+
+    #[tokio::test]
+    async fn do_test_drop_unread() {
+        // 响应体只发送了一部分
+        let (addr, accepts) =
+            fake_upstream(|_| b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nok".to_vec()).await;
+
+        // 未读完响应体的连接不能用于后续请求, 出错后换用新的连接
+        let url = Url::parse(format!("http://{}/", addr).into_bytes()).unwrap();
+        for _ in 0..2 {
+            let mut req = Request::builder()
+                .url(url.clone())
+                .body(Body::empty())
+                .unwrap();
+            let res = UpstreamPool::send(&url, &mut req, None, false, None, UpstreamProtocol::Auto)
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+            drop(res);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert_eq!(accepts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn do_test_h2c() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let count = accepts.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                count.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut server = Server::new(stream, Some(addr));
+                    server.set_callback_http(Box::new(EchoPath));
+                    let _ = server.incoming().await;
+                });
+            }
+        });
+
+        // 同时发出的多个请求复用同一个http2连接, 且按stream id对应返回
+        let url = Url::parse(format!("http://{}/first", addr).into_bytes()).unwrap();
+        let mut req = Request::builder().url(url.clone()).body(Body::empty()).unwrap();
+        let mut res = UpstreamPool::send(&url, &mut req, None, false, None, UpstreamProtocol::H2c)
+            .await
+            .unwrap();
+        assert_eq!(res.version(), webparse::Version::Http11);
+        let mut tasks = vec![];
+        for i in 0..5 {
+            tasks.push(tokio::spawn(async move {
+                let url = Url::parse(format!("http://{}/{}", addr, i).into_bytes()).unwrap();
+                let mut req = Request::builder().url(url.clone()).body(Body::empty()).unwrap();
+                let mut res =
+                    UpstreamPool::send(&url, &mut req, None, false, None, UpstreamProtocol::H2c)
+                        .await
+                        .unwrap();
+                let mut data = BinaryMut::new();
+                res.body_mut().read_all(&mut data).await.unwrap();
+                assert_eq!(data.as_slice(), format!("/{}", i).as_bytes());
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let mut data = BinaryMut::new();
+        res.body_mut().read_all(&mut data).await.unwrap();
+        assert_eq!(data.as_slice(), b"/first");
+        assert_eq!(accepts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn do_test_h2_register() {
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let mut register = H2Register {
+            next_id: StreamIdentifier::client_first(),
+            pending: pending.clone(),
+        };
+        // 未带回复通道的请求不能分配stream id
+        let mut req = Request::builder().url("http://wmproxy.net/").body(Body::empty()).unwrap();
+        assert!(register.process_request(&mut req).await.is_err());
+        let mut waits = vec![];
+        for _ in 0..2 {
+            let (reply, wait) = oneshot::channel();
+            waits.push(wait);
+            req.extensions_mut().insert(H2Token(reply));
+            register.process_request(&mut req).await.unwrap();
+        }
+        let mut ids: Vec<u32> = pending.lock().unwrap().keys().cloned().collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 3]);
+    }
+}
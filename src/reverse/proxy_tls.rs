@@ -0,0 +1,296 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/07 11:02:45
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, BufReader},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use webparse::Url;
+use wenmeng::{Client, ClientOption, MaybeHttpsStream, ProtResult, TimeoutLayer};
+
+use crate::ProxyError;
+
+fn default_verify() -> bool {
+    true
+}
+
+/// TLS的协议版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsVersion {
+    V1_2,
+    V1_3,
+}
+
+impl FromStr for TlsVersion {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match &*s.to_lowercase() {
+            "1.2" | "tls1.2" | "tlsv1.2" => Ok(TlsVersion::V1_2),
+            "1.3" | "tls1.3" | "tlsv1.3" => Ok(TlsVersion::V1_3),
+            _ => Err(ProxyError::Extension("unknow tls version")),
+        }
+    }
+}
+
+impl Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsVersion::V1_2 => f.write_str("1.2"),
+            TlsVersion::V1_3 => f.write_str("1.3"),
+        }
+    }
+}
+
+/// 连接上游时的TLS配置
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyTlsConfig {
+    /// 信任的CA证书文件, 为空则使用内置的根证书
+    pub ca: Option<String>,
+    /// 是否校验上游的证书
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+    /// 双向认证时提供的客户端证书
+    pub cert: Option<String>,
+    /// 双向认证时提供的客户端私钥
+    pub key: Option<String>,
+    /// 握手时的SNI, 为空则使用访问的域名
+    pub sni: Option<String>,
+    /// 最低的TLS版本, 可为1.2或1.3
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub min_version: Option<TlsVersion>,
+    /// 按是否协商http2缓存的配置, 复制时共享
+    #[serde(skip)]
+    cache: Arc<Mutex<HashMap<bool, Arc<ClientConfig>>>>,
+}
+
+impl Default for ProxyTlsConfig {
+    fn default() -> Self {
+        Self {
+            ca: None,
+            verify: default_verify(),
+            cert: None,
+            key: None,
+            sni: None,
+            min_version: None,
+            cache: Default::default(),
+        }
+    }
+}
+
+impl PartialEq for ProxyTlsConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.ca == other.ca
+            && self.verify == other.verify
+            && self.cert == other.cert
+            && self.key == other.key
+            && self.sni == other.sni
+            && self.min_version == other.min_version
+    }
+}
+
+impl Eq for ProxyTlsConfig {}
+
+impl Hash for ProxyTlsConfig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ca.hash(state);
+        self.verify.hash(state);
+        self.cert.hash(state);
+        self.key.hash(state);
+        self.sni.hash(state);
+        self.min_version.map(|v| v.to_string()).hash(state);
+    }
+}
+
+/// 不校验上游的证书
+#[derive(Debug)]
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::ring::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ProxyTlsConfig {
+    fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        rustls_pemfile::certs(&mut reader).collect()
+    }
+
+    fn load_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        rustls_pemfile::private_key(&mut reader)?
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "no private key found"))
+    }
+
+    fn build(&self, http2: bool) -> io::Result<ClientConfig> {
+        let builder = match self.min_version {
+            Some(TlsVersion::V1_3) => {
+                ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+            }
+            _ => ClientConfig::builder_with_protocol_versions(&[
+                &rustls::version::TLS12,
+                &rustls::version::TLS13,
+            ]),
+        };
+        let builder = if self.verify {
+            let mut roots = RootCertStore::empty();
+            match &self.ca {
+                Some(ca) => {
+                    for cert in Self::load_certs(ca)? {
+                        roots
+                            .add(cert)
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            builder.with_root_certificates(roots)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier))
+        };
+        let mut config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(Self::load_certs(cert)?, Self::load_key(key)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            _ => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        if http2 {
+            config.alpn_protocols.push(ClientOption::H2_PROTOCOL.to_vec());
+        }
+        Ok(config)
+    }
+
+    /// 获取客户端的配置, 首次使用时加载证书
+    pub fn client_config(&self, http2: bool) -> io::Result<Arc<ClientConfig>> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(config) = cache.get(&http2) {
+            return Ok(config.clone());
+        }
+        let config = Arc::new(self.build(http2).map_err(|e| {
+            log::warn!("加载上游的TLS配置出错:{:?}", e);
+            e
+        })?);
+        cache.insert(http2, config.clone());
+        Ok(config)
+    }
+
+    /// 与上游进行TLS握手, domain为访问的域名, 配置了sni时以sni为准
+    pub async fn connect(
+        &self,
+        stream: TcpStream,
+        domain: &str,
+        http2: bool,
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let name = self.sni.clone().unwrap_or(domain.to_string());
+        let name = ServerName::try_from(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid dnsname"))?;
+        let connector = TlsConnector::from(self.client_config(http2)?);
+        connector.connect(name, stream).await
+    }
+
+    /// 与上游建立TLS连接并生成客户端, 按协商的协议选择http1或http2
+    /// 返回值中第二个表示是否协商为http2
+    pub async fn connect_client(
+        &self,
+        url: &Url,
+        timeout: Option<TimeoutLayer>,
+        stream: TcpStream,
+        domain: &str,
+        http2: bool,
+    ) -> ProtResult<(Client, bool)> {
+        let domain = match domain {
+            "" => url.domain.clone().unwrap_or_default(),
+            _ => domain.to_string(),
+        };
+        let outbound = self.connect(stream, &domain, http2).await?;
+        let builder = Client::builder().timeout_layer(timeout).url(url.clone())?;
+        let is_h2 = outbound.get_ref().1.alpn_protocol() == Some(&ClientOption::H2_PROTOCOL);
+        let builder = if is_h2 {
+            builder.http2_only(true)
+        } else {
+            builder.http2_only(false).http2(http2)
+        };
+        Ok((Client::new(builder.value(), MaybeHttpsStream::Https(outbound)), is_h2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProxyTlsConfig, TlsVersion};
+
+    #[test]
+    fn do_test_proxy_tls() {
+        let tls: ProxyTlsConfig =
+            toml::from_str("verify = false\nsni = \"backend.local\"\nmin_version = \"1.3\"").unwrap();
+        assert_eq!(tls.min_version, Some(TlsVersion::V1_3));
+        let config = tls.client_config(true).unwrap();
+        assert_eq!(config.alpn_protocols.len(), 2);
+        assert!(std::sync::Arc::ptr_eq(&config, &tls.client_config(true).unwrap()));
+        assert_eq!(tls.client_config(false).unwrap().alpn_protocols.len(), 1);
+
+        let tls: ProxyTlsConfig = toml::from_str("ca = \"not_exist.pem\"").unwrap();
+        assert!(tls.client_config(true).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/16 10:42:37
+
+use std::sync::Arc;
+
+use tokio::sync::Notify;
+use webparse::{Binary, BinaryMut, Buf, HeaderName, Request, Response};
+use wenmeng::{Body, ProtResult};
+
+use crate::{BodyPump, Helper};
+
+use super::CommonConfig;
+
+/// 已缓冲的完整请求体, 用于切换上游时重新发送
+struct BufferedBody(Binary);
+
+/// 流式发送的请求体超出限制时的通知
+struct OverLimit(Arc<Notify>);
+
+/// 请求体的长度限制及缓冲
+pub struct RequestBody;
+
+impl RequestBody {
+    /// 开启proxy_request_buffering但未配置client_max_body_size时的限制
+    pub const DEFAULT_BUFFER_MAX: usize = 1_048_576;
+
+    pub fn too_large() -> Response<Body> {
+        Response::builder()
+            .status(413)
+            .body("request entity too large")
+            .unwrap()
+            .into_type()
+    }
+
+    /// 按client_max_body_size及proxy_request_buffering处理请求体, 超出时返回413
+    pub async fn deal_request(
+        comm: &CommonConfig,
+        req: &mut Request<Body>,
+    ) -> ProtResult<Option<Response<Body>>> {
+        // try_paths等重新匹配location时已处理过
+        if req.headers().system_get("{request_body}").is_some() {
+            return Ok(None);
+        }
+        let buffering = comm.proxy_request_buffering.unwrap_or(false);
+        let max = match &comm.client_max_body_size {
+            Some(size) if size.0 > 0 => size.0 as usize,
+            // 缓冲的请求体保存在内存中, 未限制时同nginx默认最多1m
+            _ if buffering => Self::DEFAULT_BUFFER_MAX,
+            _ => 0,
+        };
+        let len = req.headers().get_body_len();
+        if max > 0 && len > 0 && len as usize > max {
+            log::warn!("请求体长度{}超出限制{}", len, max);
+            return Ok(Some(Self::too_large()));
+        }
+        // h2及gRPC的请求体可不带长度及chunked, 按body是否结束判断
+        if req.body().is_end() {
+            return Ok(None);
+        }
+        if buffering {
+            req.headers_mut()
+                .system_insert("{request_body}".to_string(), "buffered".to_string());
+            return Self::buffer_body(req, max).await;
+        }
+        req.headers_mut()
+            .system_insert("{request_body}".to_string(), "stream".to_string());
+        // 长度可能不可信(如h2), 均按实际读取的数据校验
+        if max > 0 {
+            Self::limit_stream(req, max);
+        }
+        Ok(None)
+    }
+
+    /// 读取完整的请求体, 并改为以Content-Length发送
+    async fn buffer_body(
+        req: &mut Request<Body>,
+        max: usize,
+    ) -> ProtResult<Option<Response<Body>>> {
+        let mut data = BinaryMut::new();
+        let mut buf = vec![0u8; 16384];
+        loop {
+            let size = Helper::read_body(req.body_mut(), &mut buf).await?;
+            if size == 0 {
+                break;
+            }
+            if max > 0 && data.remaining() + size > max {
+                log::warn!("请求体超出限制{}", max);
+                return Ok(Some(Self::too_large()));
+            }
+            data.put_slice(&buf[..size]);
+        }
+        let data = data.freeze();
+        req.headers_mut().remove(&HeaderName::TRANSFER_ENCODING);
+        req.headers_mut()
+            .insert(HeaderName::CONTENT_LENGTH, data.remaining().to_string());
+        *req.body_mut() = Body::only(data.clone());
+        req.extensions_mut().insert(BufferedBody(data));
+        Ok(None)
+    }
+
+    /// 流式转发请求体, 超出限制时通知且不再结束请求体, 防止上游收到被截断的请求
+    fn limit_stream(req: &mut Request<Body>, max: usize) {
+        let notify = Arc::new(Notify::new());
+        req.extensions_mut().insert(OverLimit(notify.clone()));
+        let mut total = 0;
+        Helper::pump_body(req.body_mut(), BinaryMut::new(), None, move |data, _| {
+            total += data.len();
+            if total > max {
+                log::warn!("请求体超出限制{}", max);
+                notify.notify_one();
+                return BodyPump::Hold;
+            }
+            BodyPump::Send(Binary::from(data.to_vec()))
+        });
+    }
+
+    /// 流式发送的请求体超出限制的通知
+    pub fn get_over_limit(req: &Request<Body>) -> Option<Arc<Notify>> {
+        req.extensions().get::<OverLimit>().map(|o| o.0.clone())
+    }
+
+    /// 请求体是否已完整缓冲
+    pub fn is_buffered(req: &Request<Body>) -> bool {
+        req.extensions().get::<BufferedBody>().is_some()
+    }
+
+    /// 重新发送前恢复已缓冲的请求体
+    pub fn restore_buffered(req: &mut Request<Body>) {
+        if let Some(data) = req.extensions().get::<BufferedBody>().map(|b| b.0.clone()) {
+            *req.body_mut() = Body::only(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::channel;
+    use webparse::{Binary, BinaryMut, Buf, Request};
+    use wenmeng::Body;
+
+    use crate::{reverse::CommonConfig, ConfigSize};
+
+    use super::RequestBody;
+
+    fn build_req(chunks: Vec<&'static str>, length: Option<usize>) -> Request<Body> {
+        build_req_by(chunks, length, true)
+    }
+
+    fn build_req_by(chunks: Vec<&'static str>, length: Option<usize>, chunked: bool) -> Request<Body> {
+        let (sender, receiver) = channel(10);
+        tokio::spawn(async move {
+            let len = chunks.len();
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                let _ = sender.send((i + 1 == len, Binary::from(chunk))).await;
+            }
+        });
+        let mut builder = Request::builder().method("POST").url("http://wmproxy.net/upload");
+        builder = match length {
+            Some(len) => builder.header("Content-Length", len.to_string()),
+            None if chunked => builder.header("Transfer-Encoding", "chunked"),
+            None => builder,
+        };
+        builder
+            .body(Body::new(receiver, BinaryMut::new(), false))
+            .unwrap()
+    }
+
+    async fn read_body(req: &mut Request<Body>) -> String {
+        let mut data = BinaryMut::new();
+        req.body_mut().read_all(&mut data).await;
+        String::from_utf8_lossy(data.chunk()).to_string()
+    }
+
+    #[tokio::test]
+    async fn do_test_request_body() {
+        let mut comm = CommonConfig::new();
+        comm.client_max_body_size = Some(ConfigSize(10));
+
+        let mut req = build_req(vec!["0123456789ab"], Some(12));
+        let res = RequestBody::deal_request(&comm, &mut req).await.unwrap();
+        assert_eq!(res.unwrap().status(), 413);
+
+        let mut req = build_req(vec!["01234", "56789"], None);
+        assert!(RequestBody::deal_request(&comm, &mut req).await.unwrap().is_none());
+        assert!(!RequestBody::is_buffered(&req));
+        assert_eq!(read_body(&mut req).await, "0123456789");
+
+        // 流式发送时超出限制将通知
+        let mut req = build_req(vec!["01234", "56789", "ab"], None);
+        assert!(RequestBody::deal_request(&comm, &mut req).await.unwrap().is_none());
+        let notify = RequestBody::get_over_limit(&req).unwrap();
+        notify.notified().await;
+
+        // h2的请求体可不带长度也非chunked
+        let mut req = build_req_by(vec!["01234", "56789", "ab"], None, false);
+        assert!(RequestBody::deal_request(&comm, &mut req).await.unwrap().is_none());
+        let notify = RequestBody::get_over_limit(&req).unwrap();
+        notify.notified().await;
+        let mut req = Request::builder().url("http://wmproxy.net/").body(Body::empty()).unwrap();
+        assert!(RequestBody::deal_request(&comm, &mut req).await.unwrap().is_none());
+        assert!(RequestBody::get_over_limit(&req).is_none());
+
+        comm.proxy_request_buffering = Some(true);
+        let mut req = build_req(vec!["01234", "56789", "ab"], None);
+        let res = RequestBody::deal_request(&comm, &mut req).await.unwrap();
+        assert_eq!(res.unwrap().status(), 413);
+
+        let mut req = build_req(vec!["01234", "5678"], None);
+        assert!(RequestBody::deal_request(&comm, &mut req).await.unwrap().is_none());
+        assert!(RequestBody::is_buffered(&req));
+        assert_eq!(req.headers().get_body_len(), 9);
+        assert!(req.headers().get_str_value(&"Transfer-Encoding").is_none());
+        assert_eq!(read_body(&mut req).await, "012345678");
+        RequestBody::restore_buffered(&mut req);
+        assert_eq!(read_body(&mut req).await, "012345678");
+
+        // 缓冲时未限制长度也最多缓冲1m
+        comm.client_max_body_size = None;
+        let mut req = build_req(vec!["01234"], Some(RequestBody::DEFAULT_BUFFER_MAX + 1));
+        let res = RequestBody::deal_request(&comm, &mut req).await.unwrap();
+        assert_eq!(res.unwrap().status(), 413);
+        comm.client_max_body_size = Some(ConfigSize(0));
+        let mut req = build_req(vec!["01234"], Some(RequestBody::DEFAULT_BUFFER_MAX + 1));
+        let res = RequestBody::deal_request(&comm, &mut req).await.unwrap();
+        assert_eq!(res.unwrap().status(), 413);
+    }
+}
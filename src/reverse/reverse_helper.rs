@@ -14,38 +14,100 @@ use std::{net::SocketAddr, sync::Arc};
 
 use wenmeng::{RecvRequest};
 
-use super::{UpstreamConfig, ServerConfig, LocationConfig};
+use super::{BalanceKey, CommonConfig, LocationConfig, ProxyTlsConfig, ServerConfig, ServerName, ServerNameMatch, UpstreamConfig, UpstreamProtocol};
 
 
 pub struct ReverseHelper;
 
 impl ReverseHelper {
 
-    pub fn get_upstream_addr(upstream: &Vec<UpstreamConfig>, name: &str) -> Option<SocketAddr> {
+    pub fn get_upstream<'a>(upstream: &'a Vec<UpstreamConfig>, name: &str) -> Option<&'a UpstreamConfig> {
         for stream in upstream {
-            if &stream.name == name {
-                return stream.get_server_addr()
-            } else if name == "" {
-                return stream.get_server_addr()
+            if stream.name == name || name.is_empty() {
+                return Some(stream)
             }
         }
         return None;
     }
+
+    pub fn get_upstream_addr(upstream: &Vec<UpstreamConfig>, name: &str, key: BalanceKey) -> Option<SocketAddr> {
+        Self::get_upstream(upstream, name)?.get_server_addr(key)
+    }
     
-    pub fn get_location_by_req<'a>(servers: &'a Vec<Arc<ServerConfig>>, req: &RecvRequest) -> Option<&'a LocationConfig> {
-        let server_len = servers.len();
-        let host = req.get_host().unwrap_or(String::new());
-        // 不管有没有匹配, 都执行最后一个
-        for (index, s) in servers.iter().enumerate() {
-            if s.up_name == host || host.is_empty() || index == server_len - 1 {
-                let path = req.path().clone();
-                for idx in 0..s.location.len() {
-                    if s.location[idx].is_match_rule(&path, req) {
-                        return Some(&s.location[idx]);
-                    }
+    /// 访问上游的TLS配置, 上游组中的配置优先
+    pub fn get_proxy_tls<'a>(
+        upstream: &'a [UpstreamConfig],
+        name: &str,
+        comm: &'a CommonConfig,
+    ) -> Option<&'a ProxyTlsConfig> {
+        upstream
+            .iter()
+            .find(|u| u.name == name || name.is_empty())
+            .and_then(|u| u.tls.as_ref())
+            .or(comm.proxy_tls.as_ref())
+    }
+
+    /// 访问上游的协议, 上游组中的配置优先
+    pub fn get_proxy_protocol(
+        upstream: &[UpstreamConfig],
+        name: &str,
+        comm: &CommonConfig,
+    ) -> UpstreamProtocol {
+        upstream
+            .iter()
+            .find(|u| u.name == name || name.is_empty())
+            .and_then(|u| u.protocol)
+            .or(comm.proxy_protocol)
+            .unwrap_or_default()
+    }
+
+    /// 解析上游配置中的域名, 在绑定前调用以免首次请求时阻塞
+    pub async fn resolve_upstreams(upstream: &[UpstreamConfig]) {
+        for stream in upstream {
+            stream.resolve().await;
+        }
+    }
+
+    /// 按名字严格匹配server, 精确优先, 其次为长的通配, 正则按配置顺序
+    pub fn find_server_by_name<'a>(
+        servers: &'a [Arc<ServerConfig>],
+        host: &str,
+    ) -> Option<(&'a Arc<ServerConfig>, ServerNameMatch)> {
+        let host = ServerName::normalize_host(host);
+        let mut best: Option<(&'a Arc<ServerConfig>, ServerNameMatch)> = None;
+        for s in servers {
+            if let Some(m) = s.match_host(&host) {
+                match &best {
+                    Some(b) if b.1.priority <= m.priority => {}
+                    _ => best = Some((s, m)),
                 }
             }
         }
-        return None;
+        best
+    }
+
+    /// 按Host选择server, 未匹配时选择default_server, 否则无Host时为第一个, 有Host时为最后一个
+    pub fn get_server_by_host<'a>(
+        servers: &'a [Arc<ServerConfig>],
+        host: &str,
+    ) -> Option<(&'a Arc<ServerConfig>, Option<ServerNameMatch>)> {
+        if !host.is_empty() {
+            if let Some((s, m)) = Self::find_server_by_name(servers, host) {
+                return Some((s, Some(m)));
+            }
+        }
+        let s = match servers.iter().find(|s| s.default_server) {
+            Some(s) => s,
+            None if host.is_empty() => servers.first()?,
+            None => servers.last()?,
+        };
+        Some((s, None))
+    }
+
+    pub fn get_location_by_req<'a>(servers: &'a Vec<Arc<ServerConfig>>, req: &RecvRequest) -> Option<&'a LocationConfig> {
+        let host = req.get_host().unwrap_or_default();
+        let (s, _) = Self::get_server_by_host(servers, &host)?;
+        let path = req.path().clone();
+        s.location.iter().find(|l| l.is_match_rule(&path, req))
     }
-}
\ No newline at end of file
+}
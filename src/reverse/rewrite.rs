@@ -0,0 +1,252 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/11 10:05:37
+
+use std::{fmt::Display, str::FromStr};
+
+use regex::Regex;
+use webparse::{HeaderName, Request, Response};
+use wenmeng::Body;
+
+use crate::{Helper, ProxyError};
+
+/// rewrite的处理标志
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RewriteFlag {
+    /// 停止后续的rewrite, 以新的路径重新匹配location
+    Last,
+    /// 停止后续的rewrite, 在当前location中继续处理
+    Break,
+    /// 302临时重定向
+    Redirect,
+    /// 301永久重定向
+    Permanent,
+}
+
+impl FromStr for RewriteFlag {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(RewriteFlag::Last),
+            "break" => Ok(RewriteFlag::Break),
+            "redirect" => Ok(RewriteFlag::Redirect),
+            "permanent" => Ok(RewriteFlag::Permanent),
+            _ => Err(ProxyError::Extension("unknow rewrite flag")),
+        }
+    }
+}
+
+impl Display for RewriteFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RewriteFlag::Last => f.write_str("last"),
+            RewriteFlag::Break => f.write_str("break"),
+            RewriteFlag::Redirect => f.write_str("redirect"),
+            RewriteFlag::Permanent => f.write_str("permanent"),
+        }
+    }
+}
+
+/// rewrite的执行结果
+#[derive(Debug)]
+pub enum RewriteResult {
+    /// 无规则匹配
+    None,
+    /// 路径已改写, 需重新匹配location
+    Last,
+    /// 路径可能已改写, 在当前位置继续处理
+    Break,
+    /// 直接返回重定向
+    Response(Box<Response<Body>>),
+}
+
+/// 路径的改写, 格式如 "^/old/(.*)$ /new/$1 last"
+/// 替换值可使用正则的分组及format_req的变量, 以?结尾时不再附加原请求的参数
+#[derive(Debug, Clone)]
+pub struct RewriteConfig {
+    pub regex: Regex,
+    pub replace: String,
+    pub flag: Option<RewriteFlag>,
+}
+
+impl RewriteConfig {
+    /// 重定向的地址
+    fn is_redirect_url(replace: &str) -> bool {
+        replace.starts_with("http://") || replace.starts_with("https://")
+    }
+
+    /// 按规则改写请求, 未匹配时返回None, 否则返回新的地址(含参数)
+    fn rewrite(&self, req: &Request<Body>) -> Option<String> {
+        let path = &req.url().path;
+        let caps = self.regex.captures(path)?;
+        let replace = Helper::format_req(req, &self.replace);
+        let mut target = String::new();
+        caps.expand(&replace, &mut target);
+        if let Some(t) = target.strip_suffix('?') {
+            return Some(t.to_string());
+        }
+        if let Some(query) = &req.url().query {
+            if !query.is_empty() {
+                target.push(if target.contains('?') { '&' } else { '?' });
+                target.push_str(query);
+            }
+        }
+        Some(target)
+    }
+
+    /// 依次执行rewrite规则
+    pub fn deal_request(rewrites: &[RewriteConfig], req: &mut Request<Body>) -> RewriteResult {
+        let mut changed = false;
+        for rewrite in rewrites {
+            let target = match rewrite.rewrite(req) {
+                Some(target) => target,
+                None => continue,
+            };
+            let status = match rewrite.flag {
+                Some(RewriteFlag::Permanent) => Some(301),
+                Some(RewriteFlag::Redirect) => Some(302),
+                _ if Self::is_redirect_url(&target) => Some(302),
+                _ => None,
+            };
+            if let Some(status) = status {
+                let res = Response::builder()
+                    .status(status)
+                    .header(HeaderName::LOCATION, target)
+                    .body(Body::empty())
+                    .unwrap();
+                return RewriteResult::Response(Box::new(res));
+            }
+            log::trace!("rewrite {} => {}", req.path(), target);
+            Helper::set_req_path(req, target);
+            match rewrite.flag {
+                Some(RewriteFlag::Last) => return RewriteResult::Last,
+                Some(RewriteFlag::Break) => return RewriteResult::Break,
+                _ => changed = true,
+            }
+        }
+        // 无标志的规则执行完后, 路径有变化时需重新匹配
+        if changed {
+            RewriteResult::Last
+        } else {
+            RewriteResult::None
+        }
+    }
+}
+
+impl FromStr for RewriteConfig {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let vals = Helper::split_by_whitespace(s);
+        if vals.len() < 2 || vals.len() > 3 {
+            return Err(ProxyError::Extension("rewrite格式错误, 应为: 正则 替换值 [标志]"));
+        }
+        let regex =
+            Regex::new(vals[0]).map_err(|_| ProxyError::Extension("rewrite的正则表达式错误"))?;
+        let flag = match vals.get(2) {
+            Some(flag) => Some(RewriteFlag::from_str(flag)?),
+            None => None,
+        };
+        Ok(RewriteConfig {
+            regex,
+            replace: vals[1].to_string(),
+            flag,
+        })
+    }
+}
+
+impl Display for RewriteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("'{}' '{}'", self.regex.as_str(), self.replace))?;
+        if let Some(flag) = &self.flag {
+            f.write_fmt(format_args!(" {}", flag))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use super::{RewriteConfig, RewriteFlag, RewriteResult};
+
+    fn build_req(url: &str) -> Request<Body> {
+        let mut req = Request::builder().url(url).body(Body::empty()).unwrap();
+        let path = req.url().path.clone();
+        let target = match &req.url().query {
+            Some(q) => format!("{}?{}", path, q),
+            None => path,
+        };
+        req.set_path(target);
+        req
+    }
+
+    #[test]
+    fn do_test_rewrite() {
+        let rewrite = RewriteConfig::from_str("^/old/(.*)$ /new/$1 last").unwrap();
+        assert_eq!(rewrite.flag, Some(RewriteFlag::Last));
+        assert_eq!(
+            RewriteConfig::from_str(&rewrite.to_string()).unwrap().to_string(),
+            rewrite.to_string()
+        );
+        assert!(RewriteConfig::from_str("^/old /new unknow").is_err());
+
+        let mut req = build_req("http://wmproxy.net/old/a/b?id=1");
+        assert!(matches!(
+            RewriteConfig::deal_request(&[rewrite.clone()], &mut req),
+            RewriteResult::Last
+        ));
+        assert_eq!(req.path(), "/new/a/b?id=1");
+        assert_eq!(req.url().path, "/new/a/b");
+
+        let mut req = build_req("http://wmproxy.net/other");
+        assert!(matches!(
+            RewriteConfig::deal_request(&[rewrite], &mut req),
+            RewriteResult::None
+        ));
+
+        let rewrites = vec![
+            RewriteConfig::from_str("^/a/(\\w+)$ /b/$1?from={host}").unwrap(),
+            RewriteConfig::from_str("^/b/(\\w+)$ /c/$1 break").unwrap(),
+        ];
+        // 无标志的规则改写后继续执行后续的规则
+        let mut req = build_req("http://wmproxy.net/a/x?id=1");
+        assert!(matches!(
+            RewriteConfig::deal_request(&rewrites, &mut req),
+            RewriteResult::Break
+        ));
+        assert_eq!(req.path(), "/c/x?from=wmproxy.net&id=1");
+        let mut req = build_req("http://wmproxy.net/a/x?id=1");
+        assert!(matches!(
+            RewriteConfig::deal_request(&rewrites[..1], &mut req),
+            RewriteResult::Last
+        ));
+        assert_eq!(req.path(), "/b/x?from=wmproxy.net&id=1");
+
+        let rewrite = RewriteConfig::from_str("^/go/(.*)$ https://wmproxy.net/$1? permanent").unwrap();
+        let mut req = build_req("http://127.0.0.1/go/doc?id=1");
+        match RewriteConfig::deal_request(&[rewrite], &mut req) {
+            RewriteResult::Response(res) => {
+                assert_eq!(res.status(), 301);
+                assert_eq!(
+                    res.headers().get_str_value(&"Location").unwrap(),
+                    "https://wmproxy.net/doc"
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+}
@@ -13,13 +13,13 @@
 use std::{collections::HashMap, net::{SocketAddr, ToSocketAddrs}};
 
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr};
+use serde_with::{serde_as, DisplayFromStr, OneOrMany};
 use wenmeng::ProtResult;
 
 
 use crate::{ConfigHeader, WrapVecAddr};
 
-use super::{LocationConfig, UpstreamConfig, common::CommonConfig, ReverseHelper};
+use super::{BalanceKey, LocationConfig, ProxyTlsConfig, RewriteConfig, ServerName, ServerNameMatch, UpstreamConfig, common::CommonConfig, ReverseHelper};
 
 fn default_bind_mode() -> String {
     "tcp".to_string()
@@ -40,6 +40,13 @@ pub struct ServerConfig {
     
     #[serde(default = "default_up_name")]
     pub up_name: String,
+    /// 虚拟主机的名字, 可为多个, 支持*.wmproxy.net, www.wmproxy.*及~开头的正则, 为空时按up_name匹配
+    #[serde_as(as = "OneOrMany<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub server_name: Vec<ServerName>,
+    /// 在绑定的地址上未匹配到server_name时使用该server, 同时为未匹配SNI时的证书
+    #[serde(default)]
+    pub default_server: bool,
     pub root: Option<String>,
     pub cert: Option<String>,
     pub key: Option<String>,
@@ -50,6 +57,10 @@ pub struct ServerConfig {
     #[serde_as(as = "Vec<DisplayFromStr>")]
     #[serde(default = "Vec::new")]
     pub headers: Vec<ConfigHeader>,
+    /// 按顺序执行的路径改写规则
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    #[serde(default = "Vec::new")]
+    pub rewrite: Vec<RewriteConfig>,
     #[serde(default = "Vec::new")]
     pub location: Vec<LocationConfig>,
     #[serde(default = "Vec::new")]
@@ -66,12 +77,15 @@ impl ServerConfig {
             bind_addr,
             bind_ssl: WrapVecAddr::empty(),
             up_name: default_up_name(),
+            server_name: vec![],
+            default_server: false,
             root: None,
             cert: None,
             key: None,
             bind_mode: default_bind_mode(),
             headers: vec![],
             location: vec![],
+            rewrite: vec![],
             upstream: vec![],
             comm: CommonConfig::new(),
         }
@@ -82,29 +96,59 @@ impl ServerConfig {
             bind_addr: WrapVecAddr::empty(),
             bind_ssl,
             up_name: default_up_name(),
+            server_name: vec![],
+            default_server: false,
             root: None,
             cert: None,
             key: None,
             bind_mode: default_bind_mode(),
             headers: vec![],
             location: vec![],
+            rewrite: vec![],
             upstream: vec![],
             comm: CommonConfig::new(),
         }
     }
+    /// 按Host匹配该server, host需已经过ServerName::normalize_host处理
+    pub fn match_host(&self, host: &str) -> Option<ServerNameMatch> {
+        if self.server_name.is_empty() {
+            if !self.up_name.is_empty() && ServerName::normalize_host(&self.up_name) == host {
+                return Some(ServerNameMatch {
+                    priority: (0, 0),
+                    captures: vec![],
+                });
+            }
+            return None;
+        }
+        ServerName::best_match(&self.server_name, host)
+    }
+
+    /// SNI选择证书时匹配的名字
+    pub fn get_cert_names(&self) -> Vec<ServerName> {
+        if !self.server_name.is_empty() {
+            return self.server_name.clone();
+        }
+        let name = self.comm.domain.clone().unwrap_or(self.up_name.clone());
+        match name.parse::<ServerName>() {
+            Ok(name) => vec![name],
+            Err(_) => vec![],
+        }
+    }
+
+    /// 匹配的名字, 为首个server_name或up_name
+    pub fn get_server_name(&self) -> String {
+        match self.server_name.first() {
+            Some(name) => name.to_string(),
+            None => self.up_name.clone(),
+        }
+    }
+
     /// 将配置参数提前共享给子级
     pub fn copy_to_child(&mut self) {
         for l in &mut self.location {
             l.comm.copy_from_parent(&self.comm);
             l.comm.pre_deal();
-            if let Some(n) = l.rule.get_match_name() {
-                if l.comm.match_names.contains_key(&n) {
-                    l.rule = l.comm.match_names[&n].clone();
-                } else {
-                    log::error!("配置匹配名字@{},但未找到相应的配置", n);
-                    println!("配置匹配名字@{},但未找到相应的配置", n);
-                }
-            }
+            l.rule.resolve_names(&l.comm.match_names);
             l.up_name = Some(self.up_name.clone());
             l.upstream.append(&mut self.upstream.clone());
             l.headers.append(&mut self.headers.clone());
@@ -121,6 +165,25 @@ impl ServerConfig {
                 }
             }
         }
+        // 按优先级排序, 同优先级的保持配置的顺序
+        self.location.sort_by_key(|l| l.rule.get_priority());
+        self.check_locations();
+    }
+
+    /// 检查被优先级更高的location完全覆盖, 永远不会匹配的location
+    fn check_locations(&self) {
+        for (idx, l) in self.location.iter().enumerate() {
+            // 未找到的匹配名字已在替换时提示
+            if l.rule.get_match_name().is_some() {
+                continue;
+            }
+            if let Some(other) = self.location[..idx]
+                .iter()
+                .find(|o| l.rule.is_shadowed_by(&o.rule))
+            {
+                log::warn!("location {} 被 {} 覆盖, 将永远不会匹配", l.rule, other.rule);
+            }
+        }
     }
 
     
@@ -142,7 +205,16 @@ impl ServerConfig {
         }
     }
 
-    pub fn get_addr_domain(&self) -> ProtResult<(Option<SocketAddr>, Option<String>)> {
+    /// 访问上游的TLS配置
+    pub fn get_proxy_tls(&self) -> Option<&ProxyTlsConfig> {
+        let name = match self.comm.proxy_url.as_ref().and_then(|r| r.domain.as_ref()) {
+            Some(domain) => domain,
+            None => &self.up_name,
+        };
+        ReverseHelper::get_proxy_tls(&self.upstream, name, &self.comm)
+    }
+
+    pub fn get_addr_domain(&self, key: BalanceKey) -> ProtResult<(Option<SocketAddr>, Option<String>)> {
         let mut domain = self.comm.domain.clone();
         let mut addr = None;
         if self.comm.proxy_url.is_some() {
@@ -150,7 +222,7 @@ impl ServerConfig {
                 domain = self.comm.proxy_url.as_ref().unwrap().domain.clone();
             }
             if let Some(domain) = &self.comm.proxy_url.as_ref().unwrap().domain {
-                addr = ReverseHelper::get_upstream_addr(&self.upstream, &domain);
+                addr = ReverseHelper::get_upstream_addr(&self.upstream, &domain, key);
                 if addr.is_some() && self.comm.proxy_url.as_ref().unwrap().port.is_some() {
                     addr.as_mut().unwrap().set_port(self.comm.proxy_url.as_ref().unwrap().port.unwrap());
                 }
@@ -163,7 +235,7 @@ impl ServerConfig {
         }
 
         if addr.is_none() {
-            addr = ReverseHelper::get_upstream_addr(&self.upstream, &self.up_name)
+            addr = ReverseHelper::get_upstream_addr(&self.upstream, &self.up_name, key)
         }
         Ok((addr, domain))
     }
@@ -0,0 +1,306 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/17 09:21:45
+
+use std::{fmt::Display, str::FromStr, sync::Arc};
+
+use regex::Regex;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+use crate::ProxyError;
+
+/// 虚拟主机的名字, 如 wmproxy.net, *.wmproxy.net, .wmproxy.net, www.wmproxy.*,
+/// 以~开头的为正则, 如 ~^(?<sub>\w+)\.wmproxy\.net$, 分组可在format_req中以{server_name(sub)}使用
+#[derive(Debug, Clone)]
+pub enum ServerName {
+    Exact(String),
+    /// *.wmproxy.net匹配子域名, .wmproxy.net同时匹配wmproxy.net, 值为去掉*的后缀及是否匹配主域名
+    Suffix(String, bool),
+    /// www.wmproxy.*, 值为去掉*的前缀
+    Prefix(String),
+    Regex(Regex),
+}
+
+/// 匹配的结果, 包含优先级及正则的分组
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerNameMatch {
+    /// 越小越优先: 精确, 长的前置通配, 长的后置通配, 正则
+    pub priority: (u8, usize),
+    pub captures: Vec<(String, String)>,
+}
+
+impl ServerName {
+    /// 去掉Host中的端口及末尾的., 并转成小写
+    pub fn normalize_host(host: &str) -> String {
+        let host = host.trim();
+        let host = if host.starts_with('[') {
+            match host.find(']') {
+                Some(idx) => &host[..=idx],
+                None => host,
+            }
+        } else {
+            match host.rsplit_once(':') {
+                Some((h, port)) if port.chars().all(|c| c.is_ascii_digit()) => h,
+                _ => host,
+            }
+        };
+        host.trim_end_matches('.').to_ascii_lowercase()
+    }
+
+    /// host需已经过normalize_host处理
+    pub fn match_host(&self, host: &str) -> Option<ServerNameMatch> {
+        let (priority, captures) = match self {
+            ServerName::Exact(name) if name == host => ((0, 0), vec![]),
+            ServerName::Suffix(suffix, with_main) => {
+                let is_match = host.len() > suffix.len() && host.ends_with(suffix.as_str())
+                    || *with_main && host == &suffix[1..];
+                if !is_match {
+                    return None;
+                }
+                ((1, usize::MAX - suffix.len()), vec![])
+            }
+            ServerName::Prefix(prefix) if host.len() > prefix.len() && host.starts_with(prefix.as_str()) => {
+                ((2, usize::MAX - prefix.len()), vec![])
+            }
+            ServerName::Regex(re) => {
+                let caps = re.captures(host)?;
+                let mut captures = vec![];
+                for (idx, name) in re.capture_names().enumerate().skip(1) {
+                    if let Some(m) = caps.get(idx) {
+                        let key = name.map(|n| n.to_string()).unwrap_or(idx.to_string());
+                        captures.push((key, m.as_str().to_string()));
+                    }
+                }
+                ((3, 0), captures)
+            }
+            _ => return None,
+        };
+        Some(ServerNameMatch { priority, captures })
+    }
+
+    /// 在多个名字中选出最优的匹配, 同优先级的取先配置的
+    pub fn best_match(names: &[ServerName], host: &str) -> Option<ServerNameMatch> {
+        let mut best: Option<ServerNameMatch> = None;
+        for name in names {
+            if let Some(m) = name.match_host(host) {
+                match &best {
+                    Some(b) if b.priority <= m.priority => {}
+                    _ => best = Some(m),
+                }
+            }
+        }
+        best
+    }
+
+    /// 正则分组在请求中保存的key
+    pub fn capture_key(name: &str) -> String {
+        format!("{{server_name.{}}}", name)
+    }
+}
+
+impl FromStr for ServerName {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Some(r) = s.strip_prefix('~') {
+            let re = Regex::new(r).map_err(|_| ProxyError::Extension("server_name的正则表达式错误"))?;
+            return Ok(ServerName::Regex(re));
+        }
+        let s = s.to_ascii_lowercase();
+        if s.is_empty() {
+            return Err(ProxyError::Extension("server_name不能为空"));
+        }
+        if let Some(suffix) = s.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.contains('*') {
+                return Err(ProxyError::Extension("server_name的通配只能为*.example.com格式"));
+            }
+            return Ok(ServerName::Suffix(suffix.to_string(), false));
+        }
+        if s.starts_with('.') {
+            if s.contains('*') {
+                return Err(ProxyError::Extension("server_name的通配格式错误"));
+            }
+            return Ok(ServerName::Suffix(s, true));
+        }
+        if let Some(prefix) = s.strip_suffix('*') {
+            if !prefix.ends_with('.') || prefix.contains('*') {
+                return Err(ProxyError::Extension("server_name的通配只能为www.example.*格式"));
+            }
+            return Ok(ServerName::Prefix(prefix.to_string()));
+        }
+        if s.contains('*') {
+            return Err(ProxyError::Extension("server_name的通配只能在开头或结尾"));
+        }
+        Ok(ServerName::Exact(s))
+    }
+}
+
+impl Display for ServerName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerName::Exact(name) => f.write_str(name),
+            ServerName::Suffix(suffix, true) => f.write_str(suffix),
+            ServerName::Suffix(suffix, false) => f.write_fmt(format_args!("*{}", suffix)),
+            ServerName::Prefix(prefix) => f.write_fmt(format_args!("{}*", prefix)),
+            ServerName::Regex(re) => f.write_fmt(format_args!("~{}", re.as_str())),
+        }
+    }
+}
+
+/// 按SNI选择证书, 匹配规则同server_name, 未匹配或未带SNI时使用默认的证书,
+/// 每个SSL端口使用单独的实例
+#[derive(Debug, Default)]
+pub struct ServerNameResolver {
+    certs: Vec<(Vec<ServerName>, Arc<CertifiedKey>)>,
+    default: Option<Arc<CertifiedKey>>,
+    /// 默认证书是否来自default_server
+    is_default: bool,
+}
+
+impl ServerNameResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 添加证书, 以首个default_server的证书为默认, 没有时以首个添加的为默认, 同选择server的规则
+    pub fn add(&mut self, names: Vec<ServerName>, key: Arc<CertifiedKey>, is_default: bool) {
+        if (is_default && !self.is_default) || self.default.is_none() {
+            self.default = Some(key.clone());
+            self.is_default = is_default;
+        }
+        self.certs.push((names, key));
+    }
+
+    pub fn find(&self, sni: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        if let Some(sni) = sni {
+            let host = ServerName::normalize_host(sni);
+            let mut best: Option<(ServerNameMatch, &Arc<CertifiedKey>)> = None;
+            for (names, key) in &self.certs {
+                if let Some(m) = ServerName::best_match(names, &host) {
+                    match &best {
+                        Some(b) if b.0.priority <= m.priority => {}
+                        _ => best = Some((m, key)),
+                    }
+                }
+            }
+            if let Some((_, key)) = best {
+                return Some(key.clone());
+            }
+        }
+        self.default.clone()
+    }
+}
+
+impl ResolvesServerCert for ServerNameResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.find(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use webparse::Request;
+    use wenmeng::Body;
+
+    use crate::{
+        reverse::{ReverseHelper, ServerConfig},
+        Helper,
+    };
+
+    use super::ServerName;
+
+    fn best(names: &[&str], host: &str) -> Option<(u8, usize)> {
+        let names: Vec<ServerName> = names.iter().map(|n| ServerName::from_str(n).unwrap()).collect();
+        ServerName::best_match(&names, &ServerName::normalize_host(host)).map(|m| m.priority)
+    }
+
+    #[test]
+    fn do_test_server_name() {
+        assert_eq!(ServerName::normalize_host("WMproxy.net:8080"), "wmproxy.net");
+        assert_eq!(ServerName::normalize_host("wmproxy.net."), "wmproxy.net");
+        assert_eq!(ServerName::normalize_host("[::1]:443"), "[::1]");
+        for n in ["wmproxy.net", "*.wmproxy.net", ".wmproxy.net", "www.wmproxy.*", "~^(\\w+)\\.net$"] {
+            assert_eq!(ServerName::from_str(n).unwrap().to_string(), n);
+        }
+        for n in ["a.*.net", "*wmproxy.net", "www*", "~(", ""] {
+            assert!(ServerName::from_str(n).is_err());
+        }
+
+        assert_eq!(best(&["wmproxy.net"], "WMPROXY.net:80"), Some((0, 0)));
+        assert!(best(&["*.wmproxy.net"], "wmproxy.net").is_none());
+        assert!(best(&["*.wmproxy.net"], "a.b.wmproxy.net").is_some());
+        assert!(best(&[".wmproxy.net"], "wmproxy.net").is_some());
+        assert!(best(&["www.wmproxy.*"], "www.wmproxy.com").is_some());
+        assert!(best(&["www.wmproxy.*"], "api.wmproxy.com").is_none());
+        // 精确优先于通配, 通配中长的优先, 正则最后
+        let names = ["~.*", "*.net", "*.wmproxy.net", "wmproxy.net"];
+        assert_eq!(best(&names, "wmproxy.net").unwrap().0, 0);
+        assert_eq!(
+            best(&names, "a.wmproxy.net").unwrap(),
+            best(&["*.wmproxy.net"], "a.wmproxy.net").unwrap()
+        );
+        assert_eq!(best(&names, "wmproxy.com").unwrap().0, 3);
+
+        let name = ServerName::from_str("~^(?<sub>\\w+)\\.(\\w+)\\.net$").unwrap();
+        let m = name.match_host("api.wmproxy.net").unwrap();
+        assert_eq!(
+            m.captures,
+            vec![
+                ("sub".to_string(), "api".to_string()),
+                ("2".to_string(), "wmproxy".to_string())
+            ]
+        );
+    }
+
+    fn build_server(conf: &str) -> Arc<ServerConfig> {
+        let conf = format!("bind_addr = \"127.0.0.1:80\"\nbind_ssl = \"\"\n{}", conf);
+        Arc::new(toml::from_str(&conf).unwrap())
+    }
+
+    #[test]
+    fn do_test_server_by_host() {
+        let servers = vec![
+            build_server("up_name = \"old.wmproxy.net\""),
+            build_server("server_name = [\"wmproxy.net\", \"*.wmproxy.net\"]"),
+            build_server("server_name = \"~^(?<app>\\\\w+)\\\\.wmproxy\\\\.com$\"\ndefault_server = true"),
+            build_server("server_name = \"api.wmproxy.net\""),
+        ];
+        let get = |host: &str| {
+            let (s, _) = ReverseHelper::get_server_by_host(&servers, host).unwrap();
+            servers.iter().position(|v| Arc::ptr_eq(v, s)).unwrap()
+        };
+        assert_eq!(get("old.wmproxy.net:8080"), 0);
+        assert_eq!(get("WMPROXY.NET"), 1);
+        assert_eq!(get("www.wmproxy.net"), 1);
+        assert_eq!(get("api.wmproxy.net"), 3);
+        assert_eq!(get("unknown.com"), 2);
+        assert_eq!(get(""), 2);
+        assert!(ReverseHelper::find_server_by_name(&servers, "unknown.com").is_none());
+
+        let (s, m) = ReverseHelper::get_server_by_host(&servers, "blog.wmproxy.com").unwrap();
+        let mut req = Request::builder().url("http://blog.wmproxy.com/").body(Body::empty()).unwrap();
+        req.headers_mut()
+            .system_insert("{server_name}".to_string(), s.get_server_name());
+        for (name, value) in m.unwrap().captures {
+            req.headers_mut().system_insert(ServerName::capture_key(&name), value);
+        }
+        assert_eq!(
+            Helper::format_req(&req, "{server_name(app)} {server_name}"),
+            "blog ~^(?<app>\\w+)\\.wmproxy\\.com$"
+        );
+    }
+}
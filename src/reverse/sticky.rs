@@ -0,0 +1,132 @@
+// Copyright 2022 - 2024 Wenmeng See the COPYRIGHT
+// file at the top-level directory of this distribution.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+//
+// Author: tickbh
+// -----
+// Created Date: 2024/02/02 09:21:46
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use webparse::{HeaderName, Request, Response};
+use wenmeng::Body;
+
+use crate::{ConfigDuration, DisplayFromStrOrNumber};
+
+use super::balance::hash_bytes;
+
+fn default_name() -> String {
+    "wmproxy_route".to_string()
+}
+
+fn default_path() -> String {
+    "/".to_string()
+}
+
+fn default_http_only() -> bool {
+    true
+}
+
+/// 基于Cookie的会话保持, 首次选择的地址将写入Cookie, 后续请求优先选择该地址
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StickyConfig {
+    /// Cookie的名称
+    #[serde(default = "default_name")]
+    pub name: String,
+    /// Cookie的路径
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Cookie的有效时长, 为空则为会话Cookie
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub expires: Option<ConfigDuration>,
+    #[serde(default = "default_http_only")]
+    pub http_only: bool,
+    #[serde(default)]
+    pub secure: bool,
+}
+
+impl StickyConfig {
+    /// 地址对应的Cookie值, 不直接暴露后端地址
+    pub fn route_value(addr: &SocketAddr) -> String {
+        format!("{:016x}", hash_bytes(addr.to_string().as_bytes()))
+    }
+
+    /// 获取请求中Cookie记录的值
+    pub fn get_route(&self, req: &Request<Body>) -> Option<String> {
+        let cookie = req.headers().get_cookie()?;
+        for item in cookie.split(';') {
+            if let Some((k, v)) = item.split_once('=') {
+                if k.trim() == self.name {
+                    return Some(v.trim().to_string());
+                }
+            }
+        }
+        None
+    }
+
+    /// 若请求中的Cookie与选中的地址不符, 则在返回中设置新的Cookie
+    pub fn set_cookie(&self, req: &Request<Body>, res: &mut Response<Body>, addr: &SocketAddr) {
+        let value = Self::route_value(addr);
+        if self.get_route(req).as_ref() == Some(&value) {
+            return;
+        }
+        let mut cookie = format!("{}={}; Path={}", self.name, value, self.path);
+        if let Some(expires) = &self.expires {
+            cookie += &format!("; Max-Age={}", expires.0.as_secs());
+        }
+        if self.http_only {
+            cookie += "; HttpOnly";
+        }
+        if self.secure {
+            cookie += "; Secure";
+        }
+        res.headers_mut().push(HeaderName::SET_COOKIE, cookie);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use webparse::{Request, Response};
+    use wenmeng::Body;
+
+    use super::StickyConfig;
+
+    #[test]
+    fn do_test_sticky() {
+        let sticky: StickyConfig = toml::from_str("expires = \"1h\"\nsecure = true").unwrap();
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let value = StickyConfig::route_value(&addr);
+        let req: Request<Body> = Request::builder()
+            .url("http://127.0.0.1/")
+            .header("Cookie", format!("a=b; wmproxy_route={}", value))
+            .body("")
+            .unwrap()
+            .into_type();
+        assert_eq!(sticky.get_route(&req), Some(value.clone()));
+
+        let mut res: Response<Body> = Response::builder().body("").unwrap().into_type();
+        sticky.set_cookie(&req, &mut res, &addr);
+        assert!(res.headers().get_option_value(&"set-cookie").is_none());
+
+        let other: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        sticky.set_cookie(&req, &mut res, &other);
+        let cookie = res.headers().get_str_value(&"set-cookie").unwrap();
+        assert_eq!(
+            cookie,
+            format!(
+                "wmproxy_route={}; Path=/; Max-Age=3600; HttpOnly; Secure",
+                StickyConfig::route_value(&other)
+            )
+        );
+    }
+}
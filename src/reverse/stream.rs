@@ -32,12 +32,17 @@ use tokio::{
     time::sleep,
 };
 use tokio_util::sync::PollSender;
-use webparse::{BinaryMut, Buf, BufMut};
-use wenmeng::plugins::{StreamToWs, WsToStream};
+use async_trait::async_trait;
+use webparse::{ws::OwnedMessage, BinaryMut, Buf, BufMut, Url};
+use wenmeng::{
+    plugins::{StreamToWs, WsToStream},
+    ws::{WsHandshake, WsOption, WsTrait},
+    ProtError, ProtResult,
+};
 
 use crate::{HealthCheck, Helper, ProxyError, ProxyResult};
 
-use super::{ServerConfig, UpstreamConfig};
+use super::{BalanceKey, PeerStats, ProxyTlsConfig, ReverseHelper, ServerConfig, UpstreamConfig};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StreamConfig {
@@ -68,6 +73,10 @@ impl StreamConfig {
         let mut listeners = vec![];
         let mut udp_listeners = vec![];
         let mut bind_port = HashSet::new();
+        ReverseHelper::resolve_upstreams(&self.upstream).await;
+        for value in &self.server {
+            ReverseHelper::resolve_upstreams(&value.upstream).await;
+        }
         for value in &self.server.clone() {
             for v in &value.bind_addr.0 {
                 if bind_port.contains(&v.port()) {
@@ -94,46 +103,113 @@ impl StreamConfig {
         data: Arc<Mutex<StreamConfig>>,
         local_addr: SocketAddr,
         mut inbound: T,
-        _addr: SocketAddr,
+        addr: SocketAddr,
     ) -> ProxyResult<()>
     where
         T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
     {
-        let value = data.lock().await;
-        for (_, s) in value.server.iter().enumerate() {
-            if s.bind_addr.contains(local_addr.port()) {
-                let (addr, domain) = s.get_addr_domain()?;
-                if addr.is_none() {
-                    return Err(ProxyError::Extension("unknow addr"));
+        // 连接期间不持有配置锁, 避免阻塞其它连接
+        let server = {
+            let value = data.lock().await;
+            value
+                .server
+                .iter()
+                .find(|s| s.bind_addr.contains(local_addr.port()))
+                .cloned()
+        };
+        if let Some(s) = server {
+            let (addr, domain) = s.get_addr_domain(BalanceKey::Ip(addr.ip()))?;
+            if addr.is_none() {
+                return Err(ProxyError::Extension("unknow addr"));
+            }
+            let addr = addr.unwrap();
+            let guard = PeerStats::begin(addr);
+            if s.bind_mode == "ws2tcp" {
+                let mut ws_to_stream = WsToStream::new(inbound, addr)?;
+                if domain.is_some() {
+                    ws_to_stream.set_domain(domain.unwrap());
                 }
-                let addr = addr.unwrap();
-                if s.bind_mode == "ws2tcp" {
-                    let mut ws_to_stream = WsToStream::new(inbound, addr)?;
-                    if domain.is_some() {
-                        ws_to_stream.set_domain(domain.unwrap());
-                    }
-                    let _ = ws_to_stream.copy_bidirectional().await;
-                } else if s.bind_mode == "tcp2ws" {
-                    let mut stream_to_ws = StreamToWs::new(inbound, format!("ws://{}", addr))?;
-                    if domain.is_some() {
-                        stream_to_ws.set_domain(domain.unwrap());
-                    }
-                    let _ = stream_to_ws.copy_bidirectional().await;
-                } else if s.bind_mode == "tcp2wss" {
-                    let mut stream_to_ws = StreamToWs::new(inbound, format!("wss://{}", addr))?;
-                    if domain.is_some() {
-                        stream_to_ws.set_domain(domain.unwrap());
-                    }
-                    let _ = stream_to_ws.copy_bidirectional().await;
-                } else {
-                    let mut connect = HealthCheck::connect(&addr).await?;
-                    copy_bidirectional(&mut inbound, &mut connect).await?;
+                let _ = ws_to_stream.copy_bidirectional().await;
+            } else if s.bind_mode == "tcp2ws" {
+                let mut stream_to_ws = StreamToWs::new(inbound, format!("ws://{}", addr))?;
+                if domain.is_some() {
+                    stream_to_ws.set_domain(domain.unwrap());
                 }
-                break;
+                let _ = stream_to_ws.copy_bidirectional().await;
+            } else if s.bind_mode == "tcp2wss" && s.get_proxy_tls().is_some() {
+                let tls = s.get_proxy_tls().unwrap();
+                Self::copy_to_wss(inbound, addr, domain, tls).await?;
+            } else if s.bind_mode == "tcp2wss" {
+                let mut stream_to_ws = StreamToWs::new(inbound, format!("wss://{}", addr))?;
+                if domain.is_some() {
+                    stream_to_ws.set_domain(domain.unwrap());
+                }
+                let _ = stream_to_ws.copy_bidirectional().await;
+            } else {
+                let mut connect = HealthCheck::connect(&addr).await?;
+                guard.record_latency();
+                copy_bidirectional(&mut inbound, &mut connect).await?;
             }
         }
         Ok(())
     }
+
+    /// tcp转wss, 按配置的CA, 客户端证书及SNI进行握手
+    async fn copy_to_wss<T>(
+        inbound: T,
+        addr: SocketAddr,
+        domain: Option<String>,
+        tls: &ProxyTlsConfig,
+    ) -> ProxyResult<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + std::marker::Send + 'static,
+    {
+        let url = Url::parse(format!("wss://{}", addr).into_bytes())?;
+        let stream = HealthCheck::connect(&addr).await?;
+        let (mut client, _) = tls
+            .connect_client(&url, None, stream, domain.as_deref().unwrap_or(""), false)
+            .await?;
+        let (ws_sender, ws_receiver) = channel::<OwnedMessage>(10);
+        let (stream_sender, stream_receiver) = channel::<Vec<u8>>(10);
+        client.set_callback_ws(Box::new(WssOperate {
+            stream_sender,
+            receiver: Some(ws_receiver),
+        }));
+        tokio::spawn(async move {
+            let _ = client.wait_ws_operate().await;
+        });
+        StreamToWs::bind(inbound, ws_sender, stream_receiver).await?;
+        Ok(())
+    }
+}
+
+/// 以配置的TLS连接wss, 将收到的ws消息转发到tcp
+struct WssOperate {
+    stream_sender: Sender<Vec<u8>>,
+    receiver: Option<Receiver<OwnedMessage>>,
+}
+
+#[async_trait]
+impl WsTrait for WssOperate {
+    async fn on_open(&mut self, _shake: WsHandshake) -> ProtResult<Option<WsOption>> {
+        let mut option = WsOption::new();
+        if let Some(receiver) = self.receiver.take() {
+            option.set_receiver(receiver);
+        }
+        Ok(Some(option))
+    }
+
+    async fn on_message(&mut self, msg: OwnedMessage) -> ProtResult<()> {
+        let data = match msg {
+            OwnedMessage::Text(v) => v.into_bytes(),
+            OwnedMessage::Binary(v) => v,
+            _ => return Ok(()),
+        };
+        self.stream_sender
+            .send(data)
+            .await
+            .map_err(|_| ProtError::Extension("close"))
+    }
 }
 
 struct InnerUdp {
@@ -265,7 +341,7 @@ impl StreamUdp {
         let mut remote_addr = None;
         for up in &self.server.upstream {
             if up.name == self.server.up_name {
-                remote_addr = up.get_server_addr();
+                remote_addr = up.get_server_addr(BalanceKey::Ip(addr.ip()));
             }
         }
         if remote_addr.is_none() {
@@ -273,6 +349,7 @@ impl StreamUdp {
         }
 
         let remote_addr = remote_addr.unwrap();
+        let guard = PeerStats::begin(remote_addr);
         let (sender, receiver) = channel(10);
         let mut timeout = Duration::new(60, 0);
         if self.server.comm.client_timeout.is_some() {
@@ -288,6 +365,7 @@ impl StreamUdp {
         );
         let mut sender_clone = self.sender.clone();
         tokio::spawn(async move {
+            let _guard = guard;
             if let Err(e) = Self::deal_udp_bind(
                 &mut sender_clone,
                 receiver,
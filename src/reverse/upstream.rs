@@ -10,14 +10,31 @@
 // -----
 // Created Date: 2023/10/20 10:19:47
 
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
 
+use hickory_resolver::TokioAsyncResolver;
+use lazy_static::lazy_static;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
 use serde_with::DurationSeconds;
+use serde_with::{serde_as, DisplayFromStr};
 
-use crate::HealthCheck;
+use crate::{ActiveCheckConfig, ConfigDuration, DisplayFromStrOrNumber, HealthCheck, HealthPolicy};
+
+use super::balance::{smooth_round_robin, BalanceKey, BalanceStrategy, HashRing, PeerStats};
+use super::{outlier::OutlierDetector, OutlierConfig, ProxyTlsConfig, StickyConfig, UpstreamProtocol};
+
+lazy_static! {
+    /// 按系统配置(resolv.conf及hosts)解析域名, 可取得记录的TTL
+    static ref DNS_RESOLVER: Option<TokioAsyncResolver> = TokioAsyncResolver::tokio_from_system_conf()
+        .map_err(|e| log::warn!("读取系统DNS配置失败, 使用系统解析: {:?}", e))
+        .ok();
+}
 
 fn default_weight() -> u16 {
     100
@@ -35,10 +52,26 @@ fn default_rise_times() -> usize {
     2
 }
 
+fn default_addr() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 0))
+}
+
+fn default_resolve_interval() -> ConfigDuration {
+    ConfigDuration::new(Duration::from_secs(60))
+}
+
+fn default_hash_key() -> String {
+    "{client_ip}".to_string()
+}
+
 #[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SingleStreamConfig {
-    /// 访问地址
+    /// 配置的访问地址, 可为IP:端口或者域名:端口
+    #[serde(rename = "addr")]
+    pub host: String,
+    /// 解析后的地址, 域名可能解析出多个地址
+    #[serde(skip, default = "default_addr")]
     pub addr: SocketAddr,
     /// 权重
     #[serde(default = "default_weight")]
@@ -53,18 +86,86 @@ pub struct SingleStreamConfig {
     /// 当前连续成功的次数
     #[serde(default = "default_rise_times")]
     rise_times: usize,
+    /// 备用地址, 仅在主地址均不可用时使用
+    #[serde(default)]
+    pub backup: bool,
+    /// 手动下线, 不参与选择
+    #[serde(default)]
+    pub down: bool,
+    /// 同时最大的连接数, 0表示不限制
+    #[serde(default)]
+    pub max_conns: usize,
+    /// 恢复后权重从小逐渐增长到配置值的时长
+    #[serde_as(as = "Option<DisplayFromStrOrNumber>")]
+    #[serde(default)]
+    pub slow_start: Option<ConfigDuration>,
 
     #[serde(skip)]
     pub status: Option<String>,
 }
 
+/// 域名解析后的地址列表
+#[derive(Debug, Default)]
+struct ResolvedPeers {
+    list: Arc<Vec<SingleStreamConfig>>,
+    /// 最后一次解析的时间, 为空表示未解析过
+    resolved: Option<Instant>,
+    /// 是否正在后台解析中
+    resolving: bool,
+    /// 本次解析结果的有效期, 按TTL或resolve_interval
+    valid: Duration,
+}
+
+#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UpstreamConfig {
     pub name: String,
     #[serde(default = "String::new")]
     pub bind: String,
+    /// 负载均衡的策略
+    #[serde_as(as = "DisplayFromStr")]
+    #[serde(default)]
+    pub strategy: BalanceStrategy,
+    /// hash策略下计算key的格式, 如{client_ip}, {path}, {header(x-user-id)}
+    #[serde(default = "default_hash_key")]
+    pub hash_key: String,
+    /// 基于Cookie的会话保持
+    #[serde(default)]
+    pub sticky: Option<StickyConfig>,
     #[serde(default = "Vec::new")]
     pub server: Vec<SingleStreamConfig>,
+    /// 域名重新解析的间隔
+    #[serde_as(as = "DisplayFromStrOrNumber")]
+    #[serde(default = "default_resolve_interval")]
+    pub resolve_interval: ConfigDuration,
+    /// 按DNS记录的TTL重新解析, 间隔不超过resolve_interval
+    #[serde(default)]
+    pub resolve_ttl: bool,
+    /// 访问该组上游的TLS配置, 优先于proxy_tls
+    #[serde(default)]
+    pub tls: Option<ProxyTlsConfig>,
+    /// 访问该组上游的协议, 优先于proxy_protocol
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub protocol: Option<UpstreamProtocol>,
+    /// 主动健康检查, 为空则不做主动检查
+    #[serde(default)]
+    pub health_check: Option<ActiveCheckConfig>,
+    /// 根据返回的状态码及错误剔除异常的地址, 为空则不剔除
+    #[serde(default)]
+    pub outlier: Option<OutlierConfig>,
+    /// 各地址的异常统计, 复制到子级时共享
+    #[serde(skip)]
+    outlier_state: Arc<Mutex<OutlierDetector>>,
+    /// 解析后的地址列表, 复制到子级时共享
+    #[serde(skip)]
+    peers: Arc<RwLock<ResolvedPeers>>,
+    /// 轮询时每个地址的当前权重, 复制到子级时共享
+    #[serde(skip)]
+    rr_current: Arc<Mutex<Vec<i64>>>,
+    /// 一致性哈希环, 首次使用时构建
+    #[serde(skip)]
+    hash_ring: Arc<Mutex<HashRing>>,
 }
 
 impl UpstreamConfig {
@@ -72,64 +173,459 @@ impl UpstreamConfig {
         Self {
             name,
             bind: String::new(),
+            strategy: BalanceStrategy::default(),
+            hash_key: default_hash_key(),
+            sticky: None,
             server: vec![SingleStreamConfig::new_simple(to)],
+            resolve_interval: default_resolve_interval(),
+            resolve_ttl: false,
+            tls: None,
+            protocol: None,
+            health_check: None,
+            outlier: None,
+            outlier_state: Arc::new(Mutex::new(OutlierDetector::default())),
+            peers: Arc::new(RwLock::new(ResolvedPeers::default())),
+            rr_current: Arc::new(Mutex::new(vec![])),
+            hash_ring: Arc::new(Mutex::new(HashRing::default())),
         }
     }
-    pub fn get_server_addr(&self) -> Option<SocketAddr> {
-        if self.server.is_empty() {
-            return None;
+
+    fn has_domain(&self) -> bool {
+        self.server
+            .iter()
+            .any(|s| s.host.parse::<SocketAddr>().is_err())
+    }
+
+    /// 按TTL解析域名, 解析失败时使用系统的解析
+    async fn lookup(&self, host: &str) -> std::io::Result<(Vec<SocketAddr>, Option<Duration>)> {
+        if let (true, Some(resolver)) = (self.resolve_ttl, DNS_RESOLVER.as_ref()) {
+            if let Some((name, Ok(port))) = host.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+                if let Ok(lookup) = resolver.lookup_ip(name).await {
+                    let ttl = lookup.valid_until().saturating_duration_since(Instant::now());
+                    let addrs = lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect();
+                    return Ok((addrs, Some(ttl)));
+                }
+            }
         }
-        let (sum, sum_all) = self.calc_sum_weight();
-        let mut rng = rand::thread_rng();
-        if sum != 0 {
-            let mut random_weight = rng.gen_range(0..sum);
-            for server in &self.server {
-                if !HealthCheck::check_fall_down(
-                    &server.addr,
-                    &server.fail_timeout,
-                    &server.fall_times,
-                    &server.rise_times,
-                ) {
-                    if random_weight <= server.weight {
-                        return Some(server.addr.clone());
+        Ok((tokio::net::lookup_host(host).await?.collect(), None))
+    }
+
+    /// 解析配置中的地址, 域名解析失败时保留该域名上一次成功的结果
+    pub async fn resolve(&self) {
+        let old = self.peers.read().unwrap().list.clone();
+        let mut list = vec![];
+        let mut min_ttl: Option<Duration> = None;
+        for server in &self.server {
+            if let Ok(addr) = server.host.parse::<SocketAddr>() {
+                list.push(server.clone_with_addr(addr));
+                continue;
+            }
+            match self.lookup(&server.host).await {
+                Ok((addrs, ttl)) => {
+                    if let Some(ttl) = ttl {
+                        min_ttl = Some(min_ttl.map_or(ttl, |v| v.min(ttl)));
                     }
-                    random_weight -= server.weight;
+                    let mut already = HashSet::new();
+                    for addr in addrs {
+                        if already.insert(addr) {
+                            list.push(server.clone_with_addr(addr));
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::warn!("解析上游地址{}失败, 保留上次的结果: {:?}", server.host, e);
+                    list.extend(old.iter().filter(|s| s.host == server.host).cloned());
                 }
             }
-        } else {
-            let mut random_weight = rng.gen_range(0..sum_all);
-            for server in &self.server {
-                if random_weight <= server.weight {
-                    return Some(server.addr.clone());
+        }
+        let mut peers = self.peers.write().unwrap();
+        peers.list = Arc::new(list);
+        peers.resolved = Some(Instant::now());
+        peers.resolving = false;
+        // TTL为0时也至少间隔1秒, hosts中的域名TTL较长, 不超过resolve_interval
+        peers.valid = match min_ttl {
+            Some(ttl) => ttl.clamp(Duration::from_secs(1), self.resolve_interval.0.max(Duration::from_secs(1))),
+            None => self.resolve_interval.0,
+        };
+    }
+
+    /// 获取当前解析后的地址列表, 过期后在后台重新解析, 解析完成前沿用旧的结果
+    /// 未提前解析时先使用配置中的IP地址, 不在当前线程中阻塞解析域名
+    pub fn get_peers(&self) -> Arc<Vec<SingleStreamConfig>> {
+        {
+            let peers = self.peers.read().unwrap();
+            if let Some(resolved) = peers.resolved {
+                if peers.resolving || resolved.elapsed() <= peers.valid || !self.has_domain() {
+                    return peers.list.clone();
                 }
-                random_weight -= server.weight;
             }
         }
-        return None;
+        let mut peers = self.peers.write().unwrap();
+        if peers.resolved.is_none() {
+            peers.list = Arc::new(
+                self.server
+                    .iter()
+                    .filter_map(|s| s.host.parse().ok().map(|addr| s.clone_with_addr(addr)))
+                    .collect(),
+            );
+            if !self.has_domain() {
+                peers.resolved = Some(Instant::now());
+                return peers.list.clone();
+            }
+        }
+        if !peers.resolving && tokio::runtime::Handle::try_current().is_ok() {
+            peers.resolving = true;
+            let upstream = self.clone();
+            tokio::spawn(async move {
+                upstream.resolve().await;
+            });
+        }
+        peers.list.clone()
     }
 
-    pub fn calc_sum_weight(&self) -> (u16, u16) {
-        let mut sum = 0;
-        let mut sum_all = 0;
-        for server in &self.server {
-            if !HealthCheck::is_fall_down(&server.addr) {
-                sum += server.weight;
+    /// 获取可用的地址下标及权重, 主地址均不可用时使用备用地址, 全部不可用则返回所有未下线的地址
+    /// 达到最大连接数及except中的地址不参与选择
+    fn get_candidates(
+        peers: &[SingleStreamConfig],
+        ejected: &[SocketAddr],
+        except: &[SocketAddr],
+    ) -> Vec<(usize, u16)> {
+        let select = |backup: bool, check: bool| -> Vec<(usize, u16)> {
+            peers
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| {
+                    !s.down
+                        && s.backup == backup
+                        && !s.is_full()
+                        && !except.contains(&s.addr)
+                        && (!check || (!s.is_fall_down() && !ejected.contains(&s.addr)))
+                })
+                .map(|(idx, s)| (idx, if check { s.effective_weight() } else { s.weight }))
+                .collect()
+        };
+        let mut candidates = select(false, true);
+        if candidates.is_empty() {
+            candidates = select(true, true);
+        }
+        if candidates.is_empty() {
+            candidates = select(false, false);
+        }
+        if candidates.is_empty() {
+            candidates = select(true, false);
+        }
+        candidates
+    }
+
+    /// 记录上游返回的状态码, 用于异常地址的剔除
+    pub fn report_status(&self, addr: SocketAddr, status: u16) {
+        if let Some(outlier) = &self.outlier {
+            self.report(outlier, addr, !outlier.status.contains(status));
+        }
+    }
+
+    /// 记录访问上游发生的错误, 如连接失败或者超时
+    pub fn report_error(&self, addr: SocketAddr) {
+        if let Some(outlier) = &self.outlier {
+            self.report(outlier, addr, false);
+        }
+    }
+
+    fn report(&self, outlier: &OutlierConfig, addr: SocketAddr, success: bool) {
+        let total = self.get_peers().len();
+        self.outlier_state
+            .lock()
+            .unwrap()
+            .report(outlier, addr, success, total, Instant::now());
+    }
+
+    pub fn get_server_addr(&self, key: BalanceKey) -> Option<SocketAddr> {
+        self.get_server_addr_except(key, &[])
+    }
+
+    /// 选择地址, 排除已尝试过的地址, 用于失败后的重试
+    pub fn get_server_addr_except(&self, key: BalanceKey, except: &[SocketAddr]) -> Option<SocketAddr> {
+        let peers = self.get_peers();
+        let ejected = match &self.outlier {
+            Some(_) => self.outlier_state.lock().unwrap().ejected(Instant::now()),
+            None => vec![],
+        };
+        let candidates = Self::get_candidates(&peers, &ejected, except);
+        if candidates.is_empty() {
+            return None;
+        }
+        if let Some(idx) = self.select_sticky(&peers, &key, &candidates) {
+            return Some(peers[idx].addr);
+        }
+        let idx = match self.strategy {
+            BalanceStrategy::Random => Self::select_random(&candidates),
+            BalanceStrategy::Hash => match key.build_key(&self.hash_key) {
+                Some(key) => self.select_hash(&peers, &key, &candidates),
+                None => Self::select_random(&candidates),
+            },
+            BalanceStrategy::RoundRobin => self.select_round_robin(&peers, &candidates),
+            BalanceStrategy::LeastConn => Self::select_least_conn(&peers, &candidates),
+            BalanceStrategy::P2cEwma => Self::select_p2c(&peers, &candidates),
+        };
+        Some(peers[idx].addr)
+    }
+
+    /// 会话保持的地址仍可用时优先选择, 否则走正常的选择逻辑
+    fn select_sticky(
+        &self,
+        peers: &[SingleStreamConfig],
+        key: &BalanceKey,
+        candidates: &[(usize, u16)],
+    ) -> Option<usize> {
+        let (sticky, req) = match (&self.sticky, key) {
+            (Some(sticky), BalanceKey::Req(req)) => (sticky, req),
+            _ => return None,
+        };
+        let route = sticky.get_route(req)?;
+        candidates
+            .iter()
+            .map(|(idx, _)| *idx)
+            .find(|idx| StickyConfig::route_value(&peers[*idx].addr) == route)
+    }
+
+    fn select_random(candidates: &[(usize, u16)]) -> usize {
+        let sum: u32 = candidates.iter().map(|(_, w)| *w as u32).sum();
+        let mut rng = rand::thread_rng();
+        if sum == 0 {
+            return candidates[rng.gen_range(0..candidates.len())].0;
+        }
+        let mut random_weight = rng.gen_range(0..sum);
+        for (idx, weight) in candidates {
+            if random_weight < *weight as u32 {
+                return *idx;
+            }
+            random_weight -= *weight as u32;
+        }
+        candidates[candidates.len() - 1].0
+    }
+
+    fn select_round_robin(&self, peers: &[SingleStreamConfig], candidates: &[(usize, u16)]) -> usize {
+        let mut current = self.rr_current.lock().unwrap();
+        if current.len() != peers.len() {
+            *current = vec![0; peers.len()];
+        }
+        smooth_round_robin(&mut current, candidates)
+    }
+
+    fn select_least_conn(peers: &[SingleStreamConfig], candidates: &[(usize, u16)]) -> usize {
+        let mut best: Vec<usize> = vec![];
+        let mut best_score = (0u64, 1u64);
+        for (idx, weight) in candidates {
+            let active = PeerStats::active(&peers[*idx].addr) as u64;
+            let weight = (*weight).max(1) as u64;
+            // active / weight 越小越优先, 交叉相乘避免浮点
+            if best.is_empty() || active * best_score.1 < best_score.0 * weight {
+                best = vec![*idx];
+                best_score = (active, weight);
+            } else if active * best_score.1 == best_score.0 * weight {
+                best.push(*idx);
             }
-            sum_all += server.weight;
         }
-        return (sum, sum_all);
+        if best.len() == 1 {
+            return best[0];
+        }
+        best[rand::thread_rng().gen_range(0..best.len())]
+    }
+
+    fn select_hash(&self, peers: &[SingleStreamConfig], key: &str, candidates: &[(usize, u16)]) -> usize {
+        let mut ring = self.hash_ring.lock().unwrap();
+        let addrs: Vec<SocketAddr> = peers.iter().map(|s| s.addr).collect();
+        if !ring.is_same(&addrs) {
+            let servers: Vec<(SocketAddr, u16)> = peers.iter().map(|s| (s.addr, s.weight)).collect();
+            *ring = HashRing::new(&servers);
+        }
+        ring.get(key, |idx| candidates.iter().any(|(i, _)| *i == idx))
+            .unwrap_or(candidates[0].0)
+    }
+
+    fn select_p2c(peers: &[SingleStreamConfig], candidates: &[(usize, u16)]) -> usize {
+        if candidates.len() == 1 {
+            return candidates[0].0;
+        }
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..candidates.len());
+        let mut second = rng.gen_range(0..candidates.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+        let score = |(idx, weight): (usize, u16)| {
+            let addr = &peers[idx].addr;
+            // 未有延时记录的按1ms计算, 使新地址能被尝试
+            let ewma = PeerStats::ewma(addr).max(1f64);
+            ewma * (PeerStats::active(addr) + 1) as f64 / (weight.max(1) as f64)
+        };
+        if score(candidates[first]) <= score(candidates[second]) {
+            candidates[first].0
+        } else {
+            candidates[second].0
+        }
     }
 }
 
 impl SingleStreamConfig {
     pub fn new_simple(addr: SocketAddr) -> Self {
         Self {
+            host: addr.to_string(),
             addr,
             weight: 100,
             fail_timeout: Duration::from_secs(60),
             fall_times: 3,
             rise_times: 2,
+            backup: false,
+            down: false,
+            max_conns: 0,
+            slow_start: None,
             status: None,
         }
     }
+
+    /// 是否已达到最大连接数
+    fn is_full(&self) -> bool {
+        self.max_conns != 0 && PeerStats::active(&self.addr) >= self.max_conns
+    }
+
+    /// 该地址的健康判定参数, 同一地址在不同的上游组中可配置不同的参数
+    pub fn policy(&self) -> HealthPolicy {
+        HealthPolicy::new(self.fail_timeout, self.fall_times, self.rise_times)
+    }
+
+    fn is_fall_down(&self) -> bool {
+        HealthCheck::check_fall_down(&self.addr, &self.policy())
+    }
+
+    /// 当前生效的权重, 处于慢启动期间时按恢复的时长比例计算
+    pub fn effective_weight(&self) -> u16 {
+        let slow_start = match &self.slow_start {
+            Some(slow_start) if !slow_start.0.is_zero() => slow_start.0,
+            _ => return self.weight,
+        };
+        match HealthCheck::recover_since(&self.addr, &self.policy()) {
+            Some(since) if since.elapsed() < slow_start => {
+                let rate = since.elapsed().as_secs_f64() / slow_start.as_secs_f64();
+                ((self.weight as f64 * rate) as u16).max(1)
+            }
+            _ => self.weight,
+        }
+    }
+
+    fn clone_with_addr(&self, addr: SocketAddr) -> Self {
+        let mut value = self.clone();
+        value.addr = addr;
+        HealthCheck::register(addr, value.policy());
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::{reverse::PeerStats, HealthCheck};
+
+    use super::{BalanceKey, UpstreamConfig};
+
+    #[tokio::test]
+    async fn do_test_resolve() {
+        let upstream: UpstreamConfig = toml::from_str(
+            r#"
+            name = "test"
+            resolve_interval = "10s"
+            [[server]]
+            addr = "127.0.0.1:8080"
+            weight = 3
+            [[server]]
+            addr = "localhost:8081"
+            weight = 5
+            "#,
+        )
+        .unwrap();
+        // 未提前解析时先使用IP地址, 域名在后台解析
+        let peers = upstream.get_peers();
+        assert_eq!(peers.len(), 1);
+        upstream.resolve().await;
+        let peers = upstream.get_peers();
+        assert!(peers.len() >= 2);
+        assert_eq!(peers[0].addr, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(peers[0].weight, 3);
+        for p in &peers[1..] {
+            assert_eq!(p.host, "localhost:8081");
+            assert_eq!(p.addr.port(), 8081);
+            assert_eq!(p.weight, 5);
+        }
+    }
+
+    #[tokio::test]
+    async fn do_test_resolve_ttl() {
+        let upstream: UpstreamConfig = toml::from_str(
+            r#"
+            name = "test"
+            resolve_interval = "10s"
+            resolve_ttl = true
+            [[server]]
+            addr = "localhost:8081"
+            "#,
+        )
+        .unwrap();
+        // hosts中的域名TTL较长, 重新解析的间隔不超过resolve_interval
+        upstream.resolve().await;
+        assert!(!upstream.get_peers().is_empty());
+        let peers = upstream.peers.read().unwrap();
+        assert!(peers.valid >= Duration::from_secs(1));
+        assert!(peers.valid <= Duration::from_secs(10));
+    }
+
+    #[test]
+    fn do_test_peer_options() {
+        let upstream: UpstreamConfig = toml::from_str(
+            r#"
+            name = "test"
+            [[server]]
+            addr = "127.0.0.1:9301"
+            max_conns = 1
+            [[server]]
+            addr = "127.0.0.1:9302"
+            down = true
+            [[server]]
+            addr = "127.0.0.1:9303"
+            backup = true
+            [[server]]
+            addr = "127.0.0.1:9304"
+            slow_start = "10min"
+            fall_times = 1
+            rise_times = 1
+            "#,
+        )
+        .unwrap();
+        let first: SocketAddr = "127.0.0.1:9301".parse().unwrap();
+        let backup: SocketAddr = "127.0.0.1:9303".parse().unwrap();
+        let slow: SocketAddr = "127.0.0.1:9304".parse().unwrap();
+
+        // 解析时注册各地址的健康判定参数
+        upstream.get_peers();
+        // 慢启动的地址恢复后权重从小开始增长
+        HealthCheck::add_fall_down(slow);
+        HealthCheck::add_fall_down(slow);
+        HealthCheck::add_fall_down(slow);
+        assert_eq!(upstream.get_server_addr_except(BalanceKey::None, &[]), Some(first));
+        HealthCheck::add_rise_up(slow);
+        HealthCheck::add_rise_up(slow);
+        assert!(upstream.get_peers()[3].effective_weight() < 10);
+
+        // 达到最大连接数后不再选择, 下线的地址始终不选择
+        let _guard = PeerStats::begin(first);
+        for _ in 0..20 {
+            assert_eq!(upstream.get_server_addr(BalanceKey::None), Some(slow));
+        }
+        // 主地址均不可用或已尝试过时才使用备用地址
+        assert_eq!(upstream.get_server_addr_except(BalanceKey::None, &[slow]), Some(backup));
+        HealthCheck::add_fall_down(slow);
+        HealthCheck::add_fall_down(slow);
+        HealthCheck::add_fall_down(slow);
+        assert_eq!(upstream.get_server_addr(BalanceKey::None), Some(backup));
+    }
 }
@@ -16,26 +16,44 @@ use async_trait::async_trait;
 
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 
-use webparse::ws::{CloseData, OwnedMessage};
+use webparse::{
+    ws::{CloseData, OwnedMessage},
+    HeaderMap, Request,
+};
 use wenmeng::{
     ws::{WsHandshake, WsOption, WsTrait},
-    Client, ProtError, ProtResult,
+    Body, ProtError, ProtResult, RecvRequest, RecvResponse,
 };
 
-use super::{ReverseHelper, ServerConfig};
+use super::{BalanceKey, HttpConfig, ReverseHelper, ServerConfig, UpstreamPool, UpstreamProtocol};
 
 pub struct ServerWsOperate {
     inner: InnerWsOper,
     sender: Option<Sender<OwnedMessage>>,
+    /// 认证通过后的请求头, 含认证时写入及移除的头
+    headers: Option<HeaderMap>,
 }
 
 #[async_trait]
 impl WsTrait for ServerWsOperate {
+    /// 握手前进行与HTTP请求相同的认证, 未通过时返回拒绝的响应
+    async fn on_request(&mut self, req: &RecvRequest) -> ProtResult<RecvResponse> {
+        let mut checked = Request::new_by_parts(req.parts().clone()).into_type::<Body>();
+        if let Some(res) = HttpConfig::deal_ws_auth(&mut checked, &self.inner.servers).await {
+            return Ok(res);
+        }
+        self.headers = Some(checked.headers().clone());
+        WsHandshake::build_request(req)
+    }
+
     /// 握手完成后之后的回调,服务端返回了Response之后就认为握手成功
-    async fn on_open(&mut self, shake: WsHandshake) -> ProtResult<Option<WsOption>> {
+    async fn on_open(&mut self, mut shake: WsHandshake) -> ProtResult<Option<WsOption>> {
         if shake.request.is_none() {
             return Err(ProtError::Extension("miss request"));
         }
+        if let Some(headers) = self.headers.take() {
+            *shake.request.as_mut().unwrap().headers_mut() = headers;
+        }
         let mut option = WsOption::new();
         if let Some(location) =
             ReverseHelper::get_location_by_req(&self.inner.servers, shake.request.as_ref().unwrap())
@@ -43,12 +61,17 @@ impl WsTrait for ServerWsOperate {
             if !location.is_ws {
                 return Err(ProtError::Extension("Not Support Ws"));
             }
-            if let Ok((url, domain)) = location.get_reverse_url() {
+            if let Ok((url, domain)) = location.get_reverse_url(BalanceKey::Req(shake.request.as_ref().unwrap())) {
                 println!("connect url = {}, domain = {:?}", url, domain);
-                let mut client = Client::builder()
-                    .url(url)?
-                    .connect_with_domain(&domain)
-                    .await?;
+                let mut client = UpstreamPool::connect(
+                    &url,
+                    Some(&domain),
+                    location.comm.build_proxy_timeout(),
+                    true,
+                    location.get_proxy_tls(),
+                    UpstreamProtocol::Http1,
+                )
+                .await?;
 
                 let (serv_sender, serv_receiver) = channel::<OwnedMessage>(10);
                 let (cli_sender, cli_receiver) = channel::<OwnedMessage>(10);
@@ -122,6 +145,7 @@ impl ServerWsOperate {
         Self {
             inner: InnerWsOper::new(http),
             sender: None,
+            headers: None,
         }
     }
 }
@@ -171,3 +195,49 @@ impl WsTrait for ClientWsOperate {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use webparse::{HeaderName, Request};
+    use wenmeng::{ws::WsTrait, Body};
+
+    use crate::reverse::ServerConfig;
+
+    use super::ServerWsOperate;
+
+    #[tokio::test]
+    async fn do_test_ws_auth() {
+        let file = std::env::temp_dir().join("wmproxy_ws_htpasswd_test");
+        std::fs::write(&file, "admin:{PLAIN}wmproxy\n").unwrap();
+        let conf = format!(
+            "bind_addr = \"127.0.0.1:80\"\nbind_ssl = \"\"\n[[location]]\nrule = \"/ws\"\nis_ws = true\nauth_basic = {{ realm = \"wmproxy\", user_file = \"{}\" }}\n",
+            file.to_string_lossy().replace('\\', "\\\\")
+        );
+        let server: ServerConfig = toml::from_str(&conf).unwrap();
+        let mut operate = ServerWsOperate::new(vec![Arc::new(server)]);
+        let build_req = |auth: Option<&str>| {
+            let mut builder = Request::builder()
+                .url("http://wmproxy.net/ws")
+                .header(HeaderName::CONNECTION, "Upgrade")
+                .header(HeaderName::UPGRADE, "websocket")
+                .header(HeaderName::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+                .header(HeaderName::SEC_WEBSOCKET_VERSION, "13");
+            if let Some(auth) = auth {
+                builder = builder.header(HeaderName::AUTHORIZATION, auth.to_string());
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let res = operate.on_request(&build_req(None)).await.unwrap();
+        assert_eq!(res.status(), 401);
+        // admin:wmproxy
+        let res = operate
+            .on_request(&build_req(Some("Basic YWRtaW46d21wcm94eQ==")))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 101);
+        let _ = std::fs::remove_file(&file);
+    }
+}
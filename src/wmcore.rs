@@ -14,6 +14,7 @@ use std::{
     io::{self},
     net::SocketAddr,
     sync::Arc,
+    time::Duration,
 };
 
 use futures::{future::select_all, FutureExt, StreamExt};
@@ -54,8 +55,7 @@ pub struct WMCore {
     pub map_accept: Option<TlsAcceptor>,
 
     pub http_servers: Vec<Arc<ServerConfig>>,
-    pub http_accept: Option<TlsAcceptor>,
-    pub http_tlss: Vec<bool>,
+    pub http_accepts: Vec<Option<TlsAcceptor>>,
     pub http_listeners: Vec<TcpListener>,
 
     pub stream_config: Option<Arc<Mutex<StreamConfig>>>,
@@ -82,8 +82,7 @@ impl WMCore {
             map_accept: None,
 
             http_servers: vec![],
-            http_accept: None,
-            http_tlss: vec![],
+            http_accepts: vec![],
             http_listeners: vec![],
 
             stream_config: None,
@@ -200,12 +199,19 @@ impl WMCore {
     pub async fn do_start_health_check(&mut self) -> ProxyResult<()> {
         let healths = self.option.get_health_check();
         let (sender, receiver) = channel::<Vec<OneHealth>>(1);
-        let _active = ActiveHealth::new(healths, receiver);
-        // active.do_start()?;
+        let active = ActiveHealth::new(healths, receiver);
+        active.do_start()?;
         self.health_sender = Some(sender);
         Ok(())
     }
 
+    /// 域名重新解析后上游地址可能变化, 同步更新主动健康检查的列表
+    fn refresh_health_check(&mut self) {
+        if let Some(sender) = &self.health_sender {
+            let _ = sender.try_send(self.option.get_health_check());
+        }
+    }
+
     pub async fn ready_serve(&mut self) -> ProxyResult<()> {
         if let Some(option) = &mut self.option.proxy {
             (
@@ -239,7 +245,7 @@ impl WMCore {
         )));
 
         if let Some(http) = &mut self.option.http {
-            (self.http_accept, self.http_tlss, self.http_listeners) = http.bind().await?;
+            (self.http_accepts, self.http_listeners) = http.bind().await?;
         }
 
         if let Some(stream) = &mut self.option.stream {
@@ -257,8 +263,13 @@ impl WMCore {
             let _ = sender.send(()).await;
         }
         self.do_start_health_check().await?;
+        let mut health_refresh = tokio::time::interval(Duration::from_secs(10));
         loop {
             tokio::select! {
+                _ = health_refresh.tick() => {
+                    self.refresh_health_check();
+                    continue;
+                }
                 Some((inbound, addr)) = Self::tcp_listen_work(&self.center_listener) => {
                     log::trace!("中心代理收到客户端连接: {}->{}", addr, self.center_listener.as_ref().unwrap().local_addr()?);
                     if let Some(a) = self.proxy_accept.clone() {
@@ -299,7 +310,7 @@ impl WMCore {
                 (result, index) = Self::multi_tcp_listen_work(&mut self.http_listeners) => {
                     if let Ok((conn, addr)) = result {
                         let local_port = self.http_listeners[index].local_addr()?.port();
-                        log::trace!("反向代理:{}收到客户端连接: {}->{}", if self.http_tlss[index] { "https" } else { "http" }, addr,self.http_listeners[index].local_addr()?);
+                        log::trace!("反向代理:{}收到客户端连接: {}->{}", if self.http_accepts[index].is_some() { "https" } else { "http" }, addr,self.http_listeners[index].local_addr()?);
                         let mut local_servers = vec![];
                         for s in &self.http_servers {
                             if !(*s).bind_addr.contains(local_port) && !(*s).bind_ssl.contains(local_port) {
@@ -307,18 +318,10 @@ impl WMCore {
                             }
                             local_servers.push(s.clone());
                         }
-                        if self.http_tlss[index] {
-                            let tls_accept = self.http_accept.clone().unwrap();
+                        // SNI只用于选择证书, server按每个请求的Host选择
+                        if let Some(tls_accept) = self.http_accepts[index].clone() {
                             tokio::spawn(async move {
                                 if let Ok(stream) = tls_accept.accept(conn).await {
-                                    let data = stream.get_ref();
-                                    let up_name = data.1.server_name().clone().map(|s| s.to_string());
-                                    for s in &local_servers {
-                                        if up_name.is_some() && &s.up_name == up_name.as_ref().unwrap() {
-                                            let _ = HttpConfig::process(vec![s.clone()], stream, addr).await;
-                                            return;
-                                        }
-                                    }
                                     let _ = HttpConfig::process(local_servers, stream, addr).await;
                                 }
                             });